@@ -5,7 +5,7 @@ use super::Node;
 
 use crate::error::Error;
 use crate::parser::Rule;
-use crate::symtable::{CType, SymTable};
+use crate::symtable::{CType, Entry, SymTable};
 
 pub fn from_pair_to_node(pair: Pair<Rule>, symtable: &SymTable) -> Result<Node, Error> {
     match pair.as_rule() {
@@ -36,7 +36,7 @@ pub fn from_pair_to_node(pair: Pair<Rule>, symtable: &SymTable) -> Result<Node,
                 symtable,
             )?;
             Ok(Node::Write {
-                ctype: expr.ctype(),
+                ctype: CType::Void,
                 expr: Box::new(expr),
             })
         }
@@ -61,9 +61,6 @@ pub fn from_pair_to_node(pair: Pair<Rule>, symtable: &SymTable) -> Result<Node,
                     .into_inner(),
                 symtable,
             )?;
-            if !matches!(expr.ctype(), CType::Ptr(_)) {
-                return Err(Error::Type);
-            }
             Ok(Node::Free {
                 expr: Box::new(expr),
             })
@@ -72,21 +69,26 @@ pub fn from_pair_to_node(pair: Pair<Rule>, symtable: &SymTable) -> Result<Node,
     }
 }
 
+// A call by name, or through a function pointer variable of that name.
 fn from_func_call(pair: Pair<Rule>, symtable: &SymTable) -> Result<Node, Error> {
     let mut sp = pair.into_inner();
     let ident = sp.next().ok_or(Error::PairsNext)?.as_str().to_owned();
     let symbol = symtable.get_symbol(&ident)?;
     let mut arguments = Vec::new();
-    for (p, ct) in sp.zip(symbol.arguments()?) {
-        arguments.push(climbers::from_expr(p.into_inner(), symtable)?.set_ctype(&ct)?);
+    for p in sp {
+        arguments.push(climbers::from_expr(p.into_inner(), symtable)?);
     }
-    if arguments.len() != symbol.arguments()?.len() {
-        return Err(Error::Type);
+    match symbol {
+        Entry::Function { ctype, .. } => Ok(Node::Call {
+            ctype,
+            scope: symtable.get_scope(&ident)?,
+            ident,
+            arguments,
+        }),
+        Entry::Symbol { .. } => Ok(Node::IndirectCall {
+            ctype: CType::Void,
+            expr: Box::new(climbers::from_ident(ident, symtable)?),
+            arguments,
+        }),
     }
-    Ok(Node::Call {
-        ctype: symbol.ctype(),
-        scope: symtable.get_scope(&ident)?,
-        ident,
-        arguments,
-    })
 }
@@ -1,23 +1,25 @@
+use pest::error::ErrorVariant;
 use pest::iterators::{Pair, Pairs};
 use pest::pratt_parser::Assoc::Left;
 use pest::pratt_parser::{Op, PrattParser};
 
 use super::call;
+use super::typecheck;
 use super::{BinOp, CondOp, Node};
 
 use crate::error::Error;
 use crate::parser::Rule;
-use crate::symtable::CType;
 use crate::symtable::SymTable;
+use crate::symtable::{CType, Entry};
 
 // Expr prec_climber
 lazy_static::lazy_static! {
     static ref EXPR_CLIMBER: PrattParser<Rule> = PrattParser::new()
         .op(Op::infix(Rule::plus, Left) | Op::infix(Rule::minus, Left))
         .op(Op::infix(Rule::times, Left) | Op::infix(Rule::divide, Left))
-        .op(Op::prefix(Rule::neg) | Op::prefix(Rule::base_type)
+        .op(Op::prefix(Rule::neg) | Op::prefix(Rule::base_type) | Op::prefix(Rule::fptr_type)
             | Op::prefix(Rule::dereference) | Op::prefix(Rule::reference))
-        .op(Op::postfix(Rule::array_expr));
+        .op(Op::postfix(Rule::array_expr) | Op::postfix(Rule::call_expr));
 
     static ref COND_CLIMBER: PrattParser<Rule> = PrattParser::new()
         .op(Op::infix(Rule::equal, Left) | Op::infix(Rule::not_equal, Left))
@@ -27,60 +29,59 @@ lazy_static::lazy_static! {
     static ref LVAL_CLIMBER: PrattParser<Rule> = PrattParser::new()
         .op(Op::infix(Rule::plus, Left) | Op::infix(Rule::minus, Left))
         .op(Op::infix(Rule::times, Left) | Op::infix(Rule::divide, Left))
-        .op(Op::prefix(Rule::neg) | Op::prefix(Rule::base_type)
+        .op(Op::prefix(Rule::neg) | Op::prefix(Rule::base_type) | Op::prefix(Rule::fptr_type)
             | Op::prefix(Rule::address))
         .op(Op::postfix(Rule::array_expr));
 }
 
-// Build AST for an expr.
+// Build AST for an expr. Operations are left untyped until typecheck::check.
 pub fn from_expr(pairs: Pairs<Rule>, symtable: &SymTable) -> Result<Node, Error> {
+    let ternary = pairs.clone().find(|p| p.as_rule() == Rule::ternary);
+    let arith = from_arith(pairs.filter(|p| p.as_rule() != Rule::ternary), symtable)?;
+    match ternary {
+        Some(p) => from_ternary(arith, p, symtable),
+        None => Ok(arith),
+    }
+}
+
+fn from_arith<'i>(
+    pairs: impl Iterator<Item = Pair<'i, Rule>>,
+    symtable: &'i SymTable,
+) -> Result<Node, Error> {
     EXPR_CLIMBER
         .map_primary(|p| match p.as_rule() {
-            Rule::expr => from_expr(p.into_inner(), symtable),
+            Rule::expr | Rule::arith_expr => from_expr(p.into_inner(), symtable),
             Rule::call => {
                 call::from_pair_to_node(p.into_inner().peek().ok_or(Error::PairsNext)?, symtable)
             }
-            Rule::ident => {
-                let ident = p.as_str().to_owned();
-                Ok(Node::Var {
-                    ctype: symtable.get_symbol(&ident)?.ctype(),
-                    scope: symtable.get_scope(&ident)?,
-                    ident,
-                })
-            }
-            Rule::int_lit => Ok(Node::IntLit {
-                ctype: CType::Int,
-                val: p.as_str().parse::<i32>().unwrap(),
-            }),
-            Rule::float_lit => Ok(Node::FloatLit {
-                ctype: CType::Float,
-                val: p.as_str().parse::<f32>().unwrap(),
+            Rule::ident => from_ident(p.as_str().to_owned(), symtable),
+            Rule::int_lit => from_int_lit(p),
+            Rule::sizeof_expr => from_sizeof(p, symtable),
+            Rule::null => Ok(Node::IntLit {
+                ctype: CType::Ptr(Box::new(CType::Void)),
+                val: 0,
             }),
+            Rule::float_lit => from_float_lit(p),
             _ => unreachable!("from_expr: expected atom, found something else"),
         })
         .map_prefix(|op, rhs| {
             let rhs = rhs?;
             match op.as_rule() {
                 Rule::neg => Ok(Node::UnaryOp {
-                    ctype: rhs.ctype(),
-                    expr: Box::new(rhs),
-                }),
-                Rule::base_type => Ok(Node::Cast {
-                    ctype: match CType::from_base_type(op.into_inner()) {
-                        CType::Int => CType::Int,
-                        CType::Float => CType::Float,
-                        _ => return Err(Error::Type),
-                    },
+                    ctype: CType::Void,
                     expr: Box::new(rhs),
                 }),
+                Rule::base_type | Rule::fptr_type => Ok(rhs.cast(&CType::from_type(op))),
                 Rule::dereference => Ok(Node::Dereference {
-                    ctype: rhs.ctype().dereference()?,
+                    ctype: CType::Void,
                     expr: Box::new(rhs),
                 }),
                 Rule::reference => match rhs {
                     Node::Dereference { expr, .. } => Ok(*expr),
+                    // A function already stands for its address.
+                    Node::FunctionAddress { .. } => Ok(rhs),
                     _ => Ok(Node::Reference {
-                        ctype: CType::Ptr(Box::new(rhs.ctype())),
+                        ctype: CType::Void,
                         expr: Box::new(rhs),
                     }),
                 },
@@ -90,18 +91,23 @@ pub fn from_expr(pairs: Pairs<Rule>, symtable: &SymTable) -> Result<Node, Error>
             }
         })
         .map_postfix(|lhs, op| match op.as_rule() {
-            Rule::array_expr => {
-                let arr_expr = resolve_array_expr(
+            Rule::array_expr => Ok(Node::Dereference {
+                ctype: CType::Void,
+                expr: Box::new(resolve_array_expr(
                     lhs?,
                     op.into_inner().peek().ok_or(Error::PairsNext)?,
                     symtable,
-                )?;
-                Ok(Node::Dereference {
-                    ctype: arr_expr.ctype().dereference()?,
-                    expr: Box::new(arr_expr),
-                })
-            }
-            _ => unreachable!("from_expr: expected ident, found other"),
+                )?),
+            }),
+            Rule::call_expr => Ok(Node::IndirectCall {
+                ctype: CType::Void,
+                expr: Box::new(lhs?),
+                arguments: op
+                    .into_inner()
+                    .map(|p| from_expr(p.into_inner(), symtable))
+                    .collect::<Result<_, _>>()?,
+            }),
+            _ => unreachable!("from_expr: expected array_expr or call_expr, found other"),
         })
         .map_infix(|lhs, op, rhs| {
             let op = match op.as_rule() {
@@ -111,31 +117,34 @@ pub fn from_expr(pairs: Pairs<Rule>, symtable: &SymTable) -> Result<Node, Error>
                 Rule::divide => BinOp::Divide,
                 _ => unreachable!("from_expr: expected bin_op, found other"),
             };
-
-            let mut lhs = lhs?;
-            let mut rhs = rhs?;
-
-            let lctype = lhs.ctype();
-            let rctype = rhs.ctype();
-            if lctype != rctype {
-                if lctype == CType::Int && rctype == CType::Float {
-                    lhs = lhs.cast(&CType::Float);
-                } else if rctype == CType::Int && lctype == CType::Float {
-                    rhs = rhs.cast(&CType::Float);
-                } else {
-                    return Err(Error::Type);
-                }
-            }
             Ok(Node::BinaryOp {
-                ctype: lhs.ctype(),
+                ctype: CType::Void,
                 op,
-                lhs: Box::new(lhs),
-                rhs: Box::new(rhs),
+                lhs: Box::new(lhs?),
+                rhs: Box::new(rhs?),
             })
         })
         .parse(pairs)
 }
 
+// A variable, or the address of a function named on its own.
+pub fn from_ident(ident: String, symtable: &SymTable) -> Result<Node, Error> {
+    let symbol = symtable.get_symbol(&ident)?;
+    let scope = symtable.get_scope(&ident)?;
+    match symbol {
+        Entry::Function { .. } => Ok(Node::FunctionAddress {
+            ctype: symbol.function_type()?,
+            ident,
+            scope,
+        }),
+        Entry::Symbol { ctype, .. } => Ok(Node::Var {
+            ctype,
+            ident,
+            scope,
+        }),
+    }
+}
+
 // Build AST for a cond.
 pub fn from_cond(pairs: Pairs<Rule>, symtable: &SymTable) -> Result<Node, Error> {
     COND_CLIMBER
@@ -144,31 +153,52 @@ pub fn from_cond(pairs: Pairs<Rule>, symtable: &SymTable) -> Result<Node, Error>
             _ => unreachable!("from_cond: expected expr, found other"),
         })
         .map_infix(|lhs, op, rhs| {
-            let op = match op.as_rule() {
-                Rule::equal => CondOp::Equal,
-                Rule::not_equal => CondOp::NotEqual,
-                Rule::less => CondOp::Less,
-                Rule::less_equal => CondOp::LessEqual,
-                Rule::greater => CondOp::Greater,
-                Rule::greater_equal => CondOp::GreaterEqual,
-                _ => unreachable!("from_cond: expected cmp_op, found other"),
-            };
-            let mut lhs = lhs?;
-            let mut rhs = rhs?;
-            if lhs.ctype() != rhs.ctype() {
-                rhs = rhs.set_ctype(&CType::Float)?;
-                lhs = lhs.set_ctype(&CType::Float)?;
-            }
             Ok(Node::ConditionalOp {
-                ctype: lhs.ctype(),
-                op,
-                lhs: Box::new(lhs),
-                rhs: Box::new(rhs),
+                ctype: CType::Void,
+                op: from_cmp_op(op.as_rule()),
+                lhs: Box::new(lhs?),
+                rhs: Box::new(rhs?),
             })
         })
         .parse(pairs)
 }
 
+// Build AST for `lhs cmp_op rhs ? then : else`, where lhs was already parsed.
+fn from_ternary(lhs: Node, pair: Pair<Rule>, symtable: &SymTable) -> Result<Node, Error> {
+    let mut pairs = pair.into_inner();
+    let op = from_cmp_op(pairs.next().ok_or(Error::PairsNext)?.as_rule());
+    let rhs = from_expr(pairs.next().ok_or(Error::PairsNext)?.into_inner(), symtable)?;
+    Ok(Node::Ternary {
+        ctype: CType::Void,
+        cond: Box::new(Node::ConditionalOp {
+            ctype: CType::Void,
+            op,
+            lhs: Box::new(lhs),
+            rhs: Box::new(rhs),
+        }),
+        lhs: Box::new(from_expr(
+            pairs.next().ok_or(Error::PairsNext)?.into_inner(),
+            symtable,
+        )?),
+        rhs: Box::new(from_expr(
+            pairs.next().ok_or(Error::PairsNext)?.into_inner(),
+            symtable,
+        )?),
+    })
+}
+
+fn from_cmp_op(rule: Rule) -> CondOp {
+    match rule {
+        Rule::equal => CondOp::Equal,
+        Rule::not_equal => CondOp::NotEqual,
+        Rule::less => CondOp::Less,
+        Rule::less_equal => CondOp::LessEqual,
+        Rule::greater => CondOp::Greater,
+        Rule::greater_equal => CondOp::GreaterEqual,
+        _ => unreachable!("from_cmp_op: expected cmp_op, found other"),
+    }
+}
+
 pub fn from_lval(pairs: Pairs<Rule>, symtable: &SymTable) -> Result<Node, Error> {
     LVAL_CLIMBER
         .map_primary(|p| match p.as_rule() {
@@ -181,46 +211,34 @@ pub fn from_lval(pairs: Pairs<Rule>, symtable: &SymTable) -> Result<Node, Error>
                     ident,
                 })
             }
-            Rule::int_lit => Ok(Node::IntLit {
-                ctype: CType::Int,
-                val: p.as_str().parse::<i32>().unwrap(),
-            }),
+            Rule::int_lit => from_int_lit(p),
+            Rule::sizeof_expr => from_sizeof(p, symtable),
             _ => unreachable!("from_lval: expected unit, found something else"),
         })
         .map_prefix(|op, rhs| {
             let rhs = rhs?;
             match op.as_rule() {
                 Rule::neg => Ok(Node::UnaryOp {
-                    ctype: rhs.ctype(),
-                    expr: Box::new(rhs),
-                }),
-                Rule::base_type => Ok(Node::Cast {
-                    ctype: match CType::from_base_type(op.into_inner()) {
-                        CType::Int => CType::Int,
-                        CType::Float => CType::Float,
-                        _ => return Err(Error::Type),
-                    },
+                    ctype: CType::Void,
                     expr: Box::new(rhs),
                 }),
+                Rule::base_type | Rule::fptr_type => Ok(rhs.cast(&CType::from_type(op))),
                 Rule::address => Ok(Node::Address {
-                    ctype: rhs.ctype(),
+                    ctype: CType::Void,
                     expr: Box::new(rhs),
                 }),
                 _ => unreachable!("from_lval: expected neg, cast or address, found other"),
             }
         })
         .map_postfix(|lhs, op| match op.as_rule() {
-            Rule::array_expr => {
-                let arr_expr = resolve_array_expr(
+            Rule::array_expr => Ok(Node::Address {
+                ctype: CType::Void,
+                expr: Box::new(resolve_array_expr(
                     lhs?,
                     op.into_inner().peek().ok_or(Error::PairsNext)?,
                     symtable,
-                )?;
-                Ok(Node::Address {
-                    ctype: arr_expr.ctype(),
-                    expr: Box::new(arr_expr),
-                })
-            }
+                )?),
+            }),
             _ => unreachable!("from_lval: expected array_expr, found other"),
         })
         .map_infix(|lhs, op, rhs| {
@@ -231,48 +249,88 @@ pub fn from_lval(pairs: Pairs<Rule>, symtable: &SymTable) -> Result<Node, Error>
                 Rule::divide => BinOp::Divide,
                 _ => unreachable!("from_lval: expected bin_op, found other"),
             };
-
-            let mut lhs = lhs?;
-            let mut rhs = rhs?;
-
-            let lctype = lhs.ctype();
-            let rctype = rhs.ctype();
-            if lctype != rctype {
-                if lctype == CType::Int && matches!(rctype, CType::Ptr(_)) {
-                    lhs = lhs.set_ctype(&rctype)?;
-                } else if rctype == CType::Int && matches!(lctype, CType::Ptr(_)) {
-                    rhs = rhs.set_ctype(&lctype)?;
-                } else {
-                    return Err(Error::Type);
-                }
-            }
             Ok(Node::BinaryOp {
-                ctype: lhs.ctype(),
+                ctype: CType::Void,
                 op,
-                lhs: Box::new(lhs),
-                rhs: Box::new(rhs),
+                lhs: Box::new(lhs?),
+                rhs: Box::new(rhs?),
             })
         })
         .parse(pairs)
 }
 
+// Decimal literals must fit an int. Other bases may use all 32 bits, which
+// are taken as the two's complement value, unless negated, when they must
+// fit an int as decimal ones do.
+fn from_int_lit(pair: Pair<Rule>) -> Result<Node, Error> {
+    let text = pair.as_str();
+    let (negative, digits) = match text.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, text),
+    };
+    let (radix, digits) = match digits.get(..2) {
+        Some("0x" | "0X") => (16, &digits[2..]),
+        Some("0b" | "0B") => (2, &digits[2..]),
+        _ if digits.len() > 1 => match digits.strip_prefix('0') {
+            Some(digits) => (8, digits),
+            None => (10, digits),
+        },
+        _ => (10, digits),
+    };
+    let limit = match radix {
+        _ if negative => i64::from(i32::MAX) + 1,
+        10 => i64::from(i32::MAX),
+        _ => i64::from(u32::MAX),
+    };
+    match i64::from_str_radix(digits, radix) {
+        Ok(val) if val <= limit => Ok(Node::IntLit {
+            ctype: CType::Int,
+            val: if negative { -val } else { val } as i32,
+        }),
+        _ => Err(out_of_range(&pair, &CType::Int)),
+    }
+}
+
+fn from_float_lit(pair: Pair<Rule>) -> Result<Node, Error> {
+    match pair.as_str().parse::<f32>() {
+        Ok(val) if val.is_finite() => Ok(Node::FloatLit {
+            ctype: CType::Float,
+            val,
+        }),
+        _ => Err(out_of_range(&pair, &CType::Float)),
+    }
+}
+
+fn out_of_range(pair: &Pair<Rule>, ctype: &CType) -> Error {
+    Error::from(pest::error::Error::new_from_span(
+        ErrorVariant::CustomError {
+            message: format!("literal out of range for {ctype}"),
+        },
+        pair.as_span(),
+    ))
+}
+
+// Build an int literal holding the size of a type or of an expression's type.
+// The expression itself is never evaluated.
+fn from_sizeof(pair: Pair<Rule>, symtable: &SymTable) -> Result<Node, Error> {
+    let mut pairs = pair.into_inner();
+    pairs.next().ok_or(Error::PairsNext)?; // sizeof_kw
+    let ctype = match pairs.peek().ok_or(Error::PairsNext)? {
+        p if matches!(p.as_rule(), Rule::base_type | Rule::fptr_type) => CType::from_type(p),
+        _ => typecheck::check(from_expr(pairs, symtable)?, symtable)?.ctype(),
+    };
+    Ok(Node::IntLit {
+        ctype: CType::Int,
+        val: ctype.size()?,
+    })
+}
+
+// a[i] is the address a + i, scaled when it is type checked.
 fn resolve_array_expr(lhs: Node, expr: Pair<Rule>, symtable: &SymTable) -> Result<Node, Error> {
-    let expr = from_expr(expr.into_inner(), symtable)?;
     Ok(Node::BinaryOp {
-        ctype: lhs.ctype(),
+        ctype: CType::Void,
         op: BinOp::Plus,
-        rhs: Box::new(Node::BinaryOp {
-            ctype: expr.ctype(),
-            op: BinOp::Times,
-            lhs: Box::new(expr),
-            rhs: Box::new(
-                Node::IntLit {
-                    ctype: CType::Int,
-                    val: 4,
-                }
-                .set_ctype(&lhs.ctype())?,
-            ),
-        }),
         lhs: Box::new(lhs),
+        rhs: Box::new(from_expr(expr.into_inner(), symtable)?),
     })
 }
@@ -1,9 +1,10 @@
 use pest::iterators::Pair;
 
 use super::Node;
-use super::{call, climbers};
+use super::{call, climbers, typecheck};
 
 use crate::error::Error;
+use crate::listing::Span;
 use crate::parser::Rule;
 use crate::symtable::CType;
 use crate::symtable::SymTable;
@@ -11,39 +12,31 @@ use crate::symtable::SymbolType;
 
 // Build AST for functions.
 pub fn from_function(pair: Pair<Rule>, symtable: &mut SymTable) -> Result<Node, Error> {
+    let start = pair.as_span().start();
     let mut subpairs = pair.into_inner();
     let ret_type = CType::from_base_type(subpairs.next().ok_or(Error::PairsNext)?.into_inner());
     let name = subpairs.next().ok_or(Error::PairsNext)?.as_str().to_owned();
-    let params = extract_params(subpairs.next().ok_or(Error::PairsNext)?)?;
-
-    if !symtable.contains_symbol(&name) {
-        symtable.add_function(
-            ret_type.clone(),
-            name.clone(),
-            params.iter().cloned().map(|(ctype, _)| ctype).collect(),
-        )?;
-    }
-    if ret_type != symtable.get_symbol(&name)?.ctype() {
-        return Err(Error::Type);
-    }
+    let params = subpairs.next().ok_or(Error::PairsNext)?;
+    // The signature stands for the function's prologue and epilogue.
+    let span = Span {
+        start,
+        end: params.as_span().end(),
+    };
+    let params = extract_params(params).map_err(|e| e.at(span))?;
 
-    symtable.push_scope(symtable.get_symbol(&name)?.address(), &name, ret_type)?;
-    for (ctype, ident) in params.into_iter() {
-        symtable.add_symbol(ctype, ident, SymbolType::Argument)?;
-    }
+    // Errors in the signature are reported at it.
+    declare(symtable, &ret_type, &name, &params).map_err(|e| e.at(span))?;
 
     let mut statements = Node::Empty;
     for p in subpairs {
         match p.as_rule() {
             Rule::var_decl => {
-                let mut subpairs = p.into_inner();
-                let ctype =
-                    CType::from_base_type(subpairs.next().ok_or(Error::PairsNext)?.into_inner());
-                symtable.add_symbol(
-                    ctype,
-                    subpairs.next().ok_or(Error::PairsNext)?.as_str().to_owned(),
-                    SymbolType::Local,
-                )?;
+                let decl = Span::from(p.as_span());
+                let (ctype, name) =
+                    CType::from_declaration(&mut p.into_inner()).map_err(|e| e.at(decl))?;
+                symtable
+                    .add_symbol(ctype, name, SymbolType::Local)
+                    .map_err(|e| e.at(decl))?;
             }
             Rule::statements => statements = from_statements(p, symtable)?,
             r => {
@@ -55,13 +48,47 @@ pub fn from_function(pair: Pair<Rule>, symtable: &mut SymTable) -> Result<Node,
     }
     symtable.pop_scope()?;
 
-    Ok(Node::Function {
-        scope: symtable.get_scope(&name)?,
-        ident: name,
-        statements: Box::new(statements),
+    Ok(Node::Located {
+        span,
+        node: Box::new(Node::Function {
+            scope: symtable.get_scope(&name)?,
+            ident: name,
+            statements: Box::new(statements),
+        }),
     })
 }
 
+// Add the function to the symbol table unless it is already defined, and
+// enter its scope with the parameters.
+fn declare(
+    symtable: &mut SymTable,
+    ret_type: &CType,
+    name: &str,
+    params: &[(CType, String)],
+) -> Result<(), Error> {
+    symtable.add_function(
+        ret_type.clone(),
+        name.to_owned(),
+        params.iter().map(|(ctype, _)| ctype.clone()).collect(),
+    )?;
+    let name = name.to_owned();
+    if symtable.get_symbol(&name)?.is_defined() {
+        return Err(Error::SymTable(format!(
+            "from_function: redefinition of {name}"
+        )));
+    }
+
+    symtable.push_scope(
+        symtable.get_symbol(&name)?.address(),
+        &name,
+        ret_type.clone(),
+    )?;
+    for (ctype, ident) in params.iter().cloned() {
+        symtable.add_symbol(ctype, ident, SymbolType::Argument)?;
+    }
+    Ok(())
+}
+
 fn extract_params(pair: Pair<Rule>) -> Result<Vec<(CType, String)>, Error> {
     if pair.as_rule() != Rule::params {
         return Err(Error::Other(String::from(
@@ -71,12 +98,7 @@ fn extract_params(pair: Pair<Rule>) -> Result<Vec<(CType, String)>, Error> {
 
     let mut ret = Vec::new();
     for p in pair.into_inner() {
-        let mut subpairs = p.into_inner();
-        let ctype = CType::from_base_type(subpairs.next().ok_or(Error::PairsNext)?.into_inner());
-        ret.push((
-            ctype,
-            subpairs.next().ok_or(Error::PairsNext)?.as_str().to_owned(),
-        ));
+        ret.push(CType::from_declaration(&mut p.into_inner())?);
     }
     Ok(ret)
 }
@@ -86,7 +108,11 @@ fn from_statements(pair: Pair<Rule>, symtable: &SymTable) -> Result<Node, Error>
     let mut statements: Vec<Node> = Vec::new();
 
     for p in pair.into_inner() {
-        statements.push(from_statement(p, symtable)?);
+        let span = statement_span(&p);
+        statements.push(Node::Located {
+            span,
+            node: Box::new(from_statement(p, symtable).map_err(|e| e.at(span))?),
+        });
     }
 
     if statements.is_empty() {
@@ -95,6 +121,29 @@ fn from_statements(pair: Pair<Rule>, symtable: &SymTable) -> Result<Node, Error>
     Ok(Node::StatementList { statements })
 }
 
+// A construct is located by its head, since its body is located statement by
+// statement. A do-while is tested at the end, so its head is the condition.
+fn statement_span(pair: &Pair<Rule>) -> Span {
+    let span = Span::from(pair.as_span());
+    let mut inner = pair.clone().into_inner();
+    match pair.as_rule() {
+        Rule::if_stmt | Rule::while_stmt | Rule::switch_stmt => match inner.next() {
+            // Up to the parenthesis that closes the condition.
+            Some(head) => {
+                let end = head.as_span().end();
+                let paren = pair.as_str()[end - span.start..].find(')');
+                Span {
+                    end: paren.map_or(end, |i| end + i + 1),
+                    ..span
+                }
+            }
+            None => span,
+        },
+        Rule::do_while_stmt => inner.nth(1).map_or(span, |cond| cond.as_span().into()),
+        _ => span,
+    }
+}
+
 // Build AST for a statement.
 fn from_statement(pair: Pair<Rule>, symtable: &SymTable) -> Result<Node, Error> {
     match pair.as_rule() {
@@ -109,47 +158,36 @@ fn from_statement(pair: Pair<Rule>, symtable: &SymTable) -> Result<Node, Error>
             let mut pairs = pair.into_inner();
             let lhs =
                 climbers::from_lval(pairs.next().ok_or(Error::PairsNext)?.into_inner(), symtable)?;
-            let ctype = match &lhs {
-                Node::Address { .. } => lhs.strip_ctype()?,
-                _ => lhs.ctype(),
-            };
-            let mut rhs =
+            let rhs =
                 climbers::from_expr(pairs.next().ok_or(Error::PairsNext)?.into_inner(), symtable)?;
-            if rhs.ctype() != ctype {
-                if matches!(ctype, CType::Int | CType::Float) {
-                    rhs = rhs.cast(&ctype);
-                } else {
-                    rhs = rhs.set_ctype(&ctype)?;
-                }
-            }
-
             Ok(Node::Assign {
-                ctype,
+                ctype: CType::Void,
                 rhs: Box::new(rhs),
                 lhs: Box::new(lhs),
             })
         }
-        Rule::return_stmt => {
-            let ctype = symtable.get_scope_ctype()?;
-            match pair.into_inner().peek() {
-                Some(p) => {
-                    let expr = climbers::from_expr(p.into_inner(), symtable)?.set_ctype(&ctype)?;
-                    Ok(Node::Return {
-                        ctype,
-                        function: symtable.get_function()?,
-                        expr: Box::new(expr),
-                    })
-                }
-                None => {
-                    if !matches!(ctype, CType::Void) {
-                        return Err(Error::Type);
-                    }
-                    Ok(Node::Return {
-                        ctype,
-                        function: symtable.get_function()?,
-                        expr: Box::new(Node::Empty),
-                    })
-                }
+        Rule::return_stmt => Ok(Node::Return {
+            ctype: symtable.get_scope_ctype()?,
+            function: symtable.get_function()?,
+            expr: Box::new(match pair.into_inner().peek() {
+                Some(p) => climbers::from_expr(p.into_inner(), symtable)?,
+                None => Node::Empty,
+            }),
+        }),
+        Rule::break_stmt => Ok(Node::Break),
+        Rule::expr_stmt => {
+            let text = pair.as_str().to_owned();
+            match climbers::from_expr(
+                pair.into_inner()
+                    .next()
+                    .ok_or(Error::PairsNext)?
+                    .into_inner(),
+                symtable,
+            )? {
+                call @ Node::IndirectCall { .. } => Ok(call),
+                _ => Err(Error::Other(format!(
+                    "from_statement: {text} is not a statement"
+                ))),
             }
         }
 
@@ -182,9 +220,65 @@ fn from_statement(pair: Pair<Rule>, symtable: &SymTable) -> Result<Node, Error>
             })
         }
 
+        Rule::do_while_stmt => {
+            let mut pairs = pair.into_inner();
+            let statements = from_statements(pairs.next().ok_or(Error::PairsNext)?, symtable)?;
+            Ok(Node::DoWhile {
+                cond: Box::new(climbers::from_cond(
+                    pairs.next().ok_or(Error::PairsNext)?.into_inner(),
+                    symtable,
+                )?),
+                statements: Box::new(statements),
+            })
+        }
+        Rule::switch_stmt => {
+            let mut pairs = pair.into_inner();
+            let expr =
+                climbers::from_expr(pairs.next().ok_or(Error::PairsNext)?.into_inner(), symtable)?;
+            let mut cases: Vec<(Option<i32>, Node)> = Vec::new();
+            for p in pairs {
+                let rule = p.as_rule();
+                let mut subpairs = p.into_inner();
+                let val = match rule {
+                    Rule::case_clause => Some(from_case_label(
+                        subpairs.next().ok_or(Error::PairsNext)?,
+                        symtable,
+                    )?),
+                    _ => None,
+                };
+                if cases.iter().any(|(v, _)| *v == val) {
+                    return Err(Error::Other(match val {
+                        Some(v) => format!("from_statement: duplicate case {v} in switch"),
+                        None => String::from("from_statement: duplicate default in switch"),
+                    }));
+                }
+                let statements = match subpairs.next() {
+                    Some(p) => from_statements(p, symtable)?,
+                    None => Node::Empty,
+                };
+                cases.push((val, statements));
+            }
+            Ok(Node::Switch {
+                expr: Box::new(expr),
+                cases,
+            })
+        }
+
         // Unexpected
         r => Err(Error::Other(format!(
             "from_statement: expected statement, found {r:?}",
         ))),
     }
 }
+
+// Case labels must be integer constants.
+fn from_case_label(pair: Pair<Rule>, symtable: &SymTable) -> Result<i32, Error> {
+    let text = pair.as_str().to_owned();
+    typecheck::check(climbers::from_expr(pair.into_inner(), symtable)?, symtable)?
+        .const_int()
+        .ok_or_else(|| {
+            Error::Other(format!(
+                "from_case_label: case {text} is not an integer constant"
+            ))
+        })
+}
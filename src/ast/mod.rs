@@ -4,3 +4,4 @@ mod call;
 mod climbers;
 pub mod construct;
 mod node;
+pub mod typecheck;
@@ -1,4 +1,4 @@
-use crate::error::Error;
+use crate::listing::Span;
 use crate::symtable::CType;
 
 #[derive(Debug)]
@@ -46,6 +46,20 @@ pub enum Node {
         cond: Box<Self>,
         statements: Box<Self>,
     },
+    DoWhile {
+        cond: Box<Self>,
+        statements: Box<Self>,
+    },
+    Switch {
+        expr: Box<Self>,
+        cases: Vec<(Option<i32>, Self)>, // None is the default case
+    },
+    Break,
+    // A statement or function with where it is in the source.
+    Located {
+        span: Span,
+        node: Box<Self>,
+    },
 
     // Operations
     BinaryOp {
@@ -60,6 +74,12 @@ pub enum Node {
         lhs: Box<Self>,
         rhs: Box<Self>,
     },
+    Ternary {
+        ctype: CType,
+        cond: Box<Self>,
+        lhs: Box<Self>, // then
+        rhs: Box<Self>, // else
+    },
     UnaryOp {
         ctype: CType,
         expr: Box<Self>,
@@ -93,6 +113,16 @@ pub enum Node {
         scope: usize,
         arguments: Vec<Self>,
     },
+    IndirectCall {
+        ctype: CType,
+        expr: Box<Self>, // the function pointer
+        arguments: Vec<Self>,
+    },
+    FunctionAddress {
+        ctype: CType,
+        ident: String,
+        scope: usize,
+    },
 
     // Literals
     FloatLit {
@@ -119,12 +149,15 @@ impl Node {
             Self::Return { ctype, .. } => ctype.clone(),
             Self::Write { ctype, .. } => ctype.clone(),
             Self::BinaryOp { ctype, .. } => ctype.clone(),
+            Self::Ternary { ctype, .. } => ctype.clone(),
             Self::UnaryOp { ctype, .. } => ctype.clone(),
             Self::Cast { ctype, .. } => ctype.clone(),
             Self::Address { ctype, .. } => ctype.clone(),
             Self::Dereference { ctype, .. } => ctype.clone(),
             Self::Reference { ctype, .. } => ctype.clone(),
             Self::Call { ctype, .. } => ctype.clone(),
+            Self::IndirectCall { ctype, .. } => ctype.clone(),
+            Self::FunctionAddress { ctype, .. } => ctype.clone(),
             Self::FloatLit { ctype, .. } => ctype.clone(),
             Self::IntLit { ctype, .. } => ctype.clone(),
             Self::Var { ctype, .. } => ctype.clone(),
@@ -132,67 +165,108 @@ impl Node {
         }
     }
 
-    pub fn strip_ctype(&self) -> Result<CType, Error> {
-        match self {
-            Self::Address { expr, .. } => expr.strip_ctype()?.dereference(),
-            _ => Ok(self.ctype()),
+    pub fn cast(self, ctype: &CType) -> Self {
+        Self::Cast {
+            ctype: ctype.clone(),
+            expr: Box::new(self),
         }
     }
 
-    pub fn set_ctype(self, new: &CType) -> Result<Self, Error> {
-        if self.ctype() == *new {
-            return Ok(self);
-        }
-        if !(self.ctype().is_mutable() && new.is_mutable()) {
-            return Err(Error::Type);
-        }
-
+    // Value of an integer expression made only of literals, if it has one.
+    pub fn const_int(&self) -> Option<i32> {
         match self {
-            Self::BinaryOp { op, lhs, rhs, .. } => Ok(Self::BinaryOp {
-                ctype: new.clone(),
+            Self::IntLit {
+                ctype: CType::Int,
+                val,
+            } => Some(*val),
+            Self::UnaryOp {
+                ctype: CType::Int,
+                expr,
+            } => expr.const_int()?.checked_neg(),
+            Self::BinaryOp {
+                ctype: CType::Int,
                 op,
-                lhs: Box::new(lhs.set_ctype(new)?),
-                rhs: Box::new(rhs.set_ctype(new)?),
-            }),
-            Self::UnaryOp { expr, .. } => Ok(Self::UnaryOp {
-                ctype: new.clone(),
-                expr: Box::new(expr.set_ctype(new)?),
-            }),
-            Self::FloatLit { val, .. } => {
-                if !matches!(new, CType::Float) {
-                    return Err(Error::Type);
+                lhs,
+                rhs,
+            } => {
+                let (lhs, rhs) = (lhs.const_int()?, rhs.const_int()?);
+                match op {
+                    BinOp::Plus => lhs.checked_add(rhs),
+                    BinOp::Minus => lhs.checked_sub(rhs),
+                    BinOp::Times => lhs.checked_mul(rhs),
+                    BinOp::Divide => lhs.checked_div(rhs),
                 }
-                Ok(Self::FloatLit {
-                    ctype: new.clone(),
-                    val,
-                })
             }
-            Self::IntLit { val, .. } => {
-                if !matches!(new, CType::Float | CType::Ptr(_)) {
-                    return Err(Error::Type);
-                }
-                Ok(Self::IntLit {
-                    ctype: new.clone(),
-                    val,
-                })
+            _ => None,
+        }
+    }
+
+    pub fn children(&self) -> Vec<&Self> {
+        match self {
+            Self::Assign { lhs, rhs, .. }
+            | Self::BinaryOp { lhs, rhs, .. }
+            | Self::ConditionalOp { lhs, rhs, .. } => vec![lhs, rhs],
+            Self::IfElse { cond, lhs, rhs } | Self::Ternary { cond, lhs, rhs, .. } => {
+                vec![cond, lhs, rhs]
             }
-            Self::Malloc { expr, .. } => {
-                if !matches!(new, CType::Ptr(_)) {
-                    return Err(Error::Type);
-                }
-                Ok(Self::Malloc {
-                    ctype: new.clone(),
-                    expr,
-                })
+            Self::While { cond, statements } | Self::DoWhile { cond, statements } => {
+                vec![cond, statements]
             }
-            _ => Err(Error::Type),
+            Self::Switch { expr, cases } => {
+                let mut children = vec![expr.as_ref()];
+                children.extend(cases.iter().map(|(_, s)| s));
+                children
+            }
+            Self::Free { expr }
+            | Self::Malloc { expr, .. }
+            | Self::Return { expr, .. }
+            | Self::Write { expr, .. }
+            | Self::UnaryOp { expr, .. }
+            | Self::Cast { expr, .. }
+            | Self::Address { expr, .. }
+            | Self::Dereference { expr, .. }
+            | Self::Reference { expr, .. } => vec![expr],
+            Self::Read { var, .. } => vec![var],
+            Self::Located { node, .. } => vec![node],
+            Self::Function { statements, .. } => vec![statements],
+            Self::StatementList { statements } => statements.iter().collect(),
+            Self::Call { arguments, .. } => arguments.iter().collect(),
+            Self::IndirectCall {
+                expr, arguments, ..
+            } => {
+                let mut children = vec![expr.as_ref()];
+                children.extend(arguments);
+                children
+            }
+            Self::Empty
+            | Self::Break
+            | Self::FloatLit { .. }
+            | Self::IntLit { .. }
+            | Self::FunctionAddress { .. }
+            | Self::Var { .. } => Vec::new(),
         }
     }
 
-    pub fn cast(self, ctype: &CType) -> Self {
-        Self::Cast {
-            ctype: ctype.clone(),
-            expr: Box::new(self),
+    // Whether evaluating the expression ends a block. Branches and calls do.
+    pub fn ends_block(&self) -> bool {
+        matches!(
+            self,
+            Self::Ternary { .. } | Self::Call { .. } | Self::IndirectCall { .. }
+        ) || self.children().into_iter().any(Self::ends_block)
+    }
+
+    // Names of the top-level functions and variables the tree uses.
+    pub fn referenced_globals(&self, names: &mut Vec<String>) {
+        match self {
+            Self::Call { ident, .. }
+            | Self::FunctionAddress { ident, .. }
+            | Self::Var {
+                ident, scope: 0, ..
+            } if !names.contains(ident) => names.push(ident.clone()),
+            _ => {}
+        }
+        for child in self.children() {
+            child.referenced_globals(names);
         }
     }
 }
@@ -0,0 +1,437 @@
+use super::{BinOp, Node};
+
+use crate::error::{self, Error};
+use crate::symtable::{CType, SymTable};
+
+// Type check a tree built by construct, filling in the ctype of every
+// operation and inserting the implicit conversions it needs.
+pub fn check(node: Node, symtable: &SymTable) -> Result<Node, Error> {
+    match node {
+        Node::Assign { lhs, rhs, .. } => {
+            let lhs = check(*lhs, symtable)?;
+            let ctype = lhs.ctype();
+            if !matches!(lhs, Node::Var { .. } | Node::Address { .. }) || !ctype.is_mutable() {
+                return Err(Error::Type);
+            }
+            let rhs = check(*rhs, symtable)?;
+            check_malloc_size(&rhs, &ctype);
+            Ok(Node::Assign {
+                rhs: Box::new(convert(rhs, &ctype)?),
+                lhs: Box::new(lhs),
+                ctype,
+            })
+        }
+        Node::Free { expr } => {
+            let expr = check(*expr, symtable)?;
+            if !matches!(expr.ctype(), CType::Ptr(_)) {
+                return Err(Error::Type);
+            }
+            Ok(Node::Free {
+                expr: Box::new(expr),
+            })
+        }
+        Node::Malloc { ctype, expr } => Ok(Node::Malloc {
+            ctype,
+            expr: Box::new(convert(check(*expr, symtable)?, &CType::Int)?),
+        }),
+        Node::Read { ctype, var } => {
+            if !ctype.is_mutable() {
+                return Err(Error::Type);
+            }
+            Ok(Node::Read { ctype, var })
+        }
+        Node::Return {
+            ctype,
+            function,
+            expr,
+        } => {
+            let expr = match (*expr, &ctype) {
+                (Node::Empty, CType::Void) => Node::Empty,
+                (Node::Empty, _) | (_, CType::Void) => return Err(Error::Type),
+                (expr, _) => convert(check(expr, symtable)?, &ctype)?,
+            };
+            Ok(Node::Return {
+                ctype,
+                function,
+                expr: Box::new(expr),
+            })
+        }
+        Node::StatementList { statements } => Ok(Node::StatementList {
+            statements: statements
+                .into_iter()
+                .map(|s| check(s, symtable))
+                .collect::<Result<_, _>>()?,
+        }),
+        Node::Write { expr, .. } => {
+            let expr = check(*expr, symtable)?;
+            if expr.ctype() == CType::Void {
+                return Err(Error::Type);
+            }
+            Ok(Node::Write {
+                ctype: expr.ctype(),
+                expr: Box::new(expr),
+            })
+        }
+
+        Node::IfElse { cond, lhs, rhs } => Ok(Node::IfElse {
+            cond: Box::new(check(*cond, symtable)?),
+            lhs: Box::new(check(*lhs, symtable)?),
+            rhs: Box::new(check(*rhs, symtable)?),
+        }),
+        Node::While { cond, statements } => Ok(Node::While {
+            cond: Box::new(check(*cond, symtable)?),
+            statements: Box::new(check(*statements, symtable)?),
+        }),
+        Node::Located { span, node } => Ok(Node::Located {
+            span,
+            node: Box::new(check(*node, symtable).map_err(|e| e.at(span))?),
+        }),
+        Node::DoWhile { cond, statements } => Ok(Node::DoWhile {
+            cond: Box::new(check(*cond, symtable)?),
+            statements: Box::new(check(*statements, symtable)?),
+        }),
+        Node::Switch { expr, cases } => {
+            let expr = check(*expr, symtable)?;
+            if expr.ctype() != CType::Int {
+                return Err(Error::Type);
+            }
+            Ok(Node::Switch {
+                expr: Box::new(expr),
+                cases: cases
+                    .into_iter()
+                    .map(|(val, s)| Ok((val, check(s, symtable)?)))
+                    .collect::<Result<_, Error>>()?,
+            })
+        }
+
+        Node::BinaryOp { op, lhs, rhs, .. } => {
+            check_binary_op(op, check(*lhs, symtable)?, check(*rhs, symtable)?)
+        }
+        Node::ConditionalOp { op, lhs, rhs, .. } => {
+            let (lhs, rhs) = unify_comparison(check(*lhs, symtable)?, check(*rhs, symtable)?)?;
+            Ok(Node::ConditionalOp {
+                ctype: lhs.ctype(),
+                op,
+                lhs: Box::new(lhs),
+                rhs: Box::new(rhs),
+            })
+        }
+        Node::Ternary { cond, lhs, rhs, .. } => {
+            let cond = check(*cond, symtable)?;
+            let (lhs, rhs) = unify_comparison(check(*lhs, symtable)?, check(*rhs, symtable)?)?;
+            // A void* arm makes the result void*, as in C.
+            let ctype = match lhs.ctype() {
+                CType::Ptr(l) if *l == CType::Void => lhs.ctype(),
+                _ => rhs.ctype(),
+            };
+            if ctype == CType::Void {
+                return Err(Error::Type);
+            }
+            Ok(Node::Ternary {
+                cond: Box::new(cond),
+                lhs: Box::new(convert(lhs, &ctype)?),
+                rhs: Box::new(convert(rhs, &ctype)?),
+                ctype,
+            })
+        }
+        Node::UnaryOp { expr, .. } => {
+            // Negated literals fold into literals.
+            let expr = match check(*expr, symtable)? {
+                Node::IntLit {
+                    ctype: CType::Int,
+                    val,
+                } if val != i32::MIN => {
+                    return Ok(Node::IntLit {
+                        ctype: CType::Int,
+                        val: -val,
+                    })
+                }
+                Node::FloatLit { ctype, val } => return Ok(Node::FloatLit { ctype, val: -val }),
+                expr => expr,
+            };
+            if !matches!(expr.ctype(), CType::Int | CType::Float) {
+                return Err(Error::Type);
+            }
+            Ok(Node::UnaryOp {
+                ctype: expr.ctype(),
+                expr: Box::new(expr),
+            })
+        }
+        Node::Cast { ctype, expr } => check_cast(ctype, check(*expr, symtable)?),
+        Node::Address { expr, .. } => {
+            let expr = check(*expr, symtable)?;
+            Ok(Node::Address {
+                ctype: pointee(&expr)?,
+                expr: Box::new(expr),
+            })
+        }
+        Node::Dereference { expr, .. } => {
+            let expr = check(*expr, symtable)?;
+            match pointee(&expr)? {
+                // *f is f again for a function pointer, as in C.
+                CType::Func(..) => Ok(expr),
+                ctype => Ok(Node::Dereference {
+                    ctype,
+                    expr: Box::new(expr),
+                }),
+            }
+        }
+        Node::Reference { expr, .. } => match *expr {
+            Node::Var { .. } if expr.ctype().is_mutable() => Ok(Node::Reference {
+                ctype: CType::Ptr(Box::new(expr.ctype())),
+                expr,
+            }),
+            _ => Err(Error::Type),
+        },
+
+        Node::Function {
+            ident,
+            scope,
+            statements,
+        } => Ok(Node::Function {
+            ident,
+            scope,
+            statements: Box::new(check(*statements, symtable)?),
+        }),
+        Node::Call {
+            ctype,
+            ident,
+            scope,
+            arguments,
+        } => {
+            let params = symtable.get_symbol_in_scope(&ident, scope)?.arguments()?;
+            if params.len() != arguments.len() {
+                return Err(Error::Type);
+            }
+            Ok(Node::Call {
+                ctype,
+                ident,
+                scope,
+                arguments: arguments
+                    .into_iter()
+                    .zip(params.iter())
+                    .map(|(a, p)| convert(check(a, symtable)?, p))
+                    .collect::<Result<_, _>>()?,
+            })
+        }
+
+        Node::IndirectCall {
+            expr, arguments, ..
+        } => {
+            let expr = check(*expr, symtable)?;
+            let (ctype, params) = match expr.ctype().dereference()? {
+                CType::Func(ret, params) => (*ret, params),
+                _ => return Err(Error::Type),
+            };
+            if params.len() != arguments.len() {
+                return Err(Error::Type);
+            }
+            Ok(Node::IndirectCall {
+                ctype,
+                expr: Box::new(expr),
+                arguments: arguments
+                    .into_iter()
+                    .zip(params.iter())
+                    .map(|(a, p)| convert(check(a, symtable)?, p))
+                    .collect::<Result<_, _>>()?,
+            })
+        }
+
+        Node::Empty
+        | Node::Break
+        | Node::FunctionAddress { .. }
+        | Node::FloatLit { .. }
+        | Node::IntLit { .. }
+        | Node::Var { .. } => Ok(node),
+    }
+}
+
+// Type of the value a pointer expression points to.
+fn pointee(expr: &Node) -> Result<CType, Error> {
+    match expr.ctype().dereference()? {
+        CType::Void => Err(Error::Type),
+        ctype => Ok(ctype),
+    }
+}
+
+// The usual arithmetic conversions: int is promoted to float when mixed.
+fn common_type(lhs: &CType, rhs: &CType) -> Result<CType, Error> {
+    match (lhs, rhs) {
+        (CType::Int, CType::Int) => Ok(CType::Int),
+        (CType::Int | CType::Float, CType::Int | CType::Float) => Ok(CType::Float),
+        _ => Err(Error::Type),
+    }
+}
+
+// Implicitly convert a value to the type it is assigned, passed or returned
+// as. Arithmetic types convert freely, pointers only to and from void* and
+// from integer constants.
+fn convert(node: Node, ctype: &CType) -> Result<Node, Error> {
+    match (node.ctype(), ctype) {
+        (from, to) if from == *to => Ok(node),
+        (CType::Int | CType::Float, CType::Int | CType::Float) => Ok(arithmetic_cast(node, ctype)),
+        (CType::Ptr(from), CType::Ptr(to)) if *from == CType::Void || **to == CType::Void => {
+            Ok(match node {
+                Node::Malloc { expr, .. } => Node::Malloc {
+                    ctype: ctype.clone(),
+                    expr,
+                },
+                Node::IntLit { val, .. } => Node::IntLit {
+                    ctype: ctype.clone(),
+                    val,
+                },
+                _ => node.cast(ctype),
+            })
+        }
+        (CType::Int, CType::Ptr(_)) => match node.const_int() {
+            Some(val) => {
+                if val != 0 {
+                    error::warning(format!(
+                        "conversion to {ctype} makes a pointer from an integer without a cast"
+                    ));
+                }
+                Ok(Node::IntLit {
+                    ctype: ctype.clone(),
+                    val,
+                })
+            }
+            None => Err(Error::Type),
+        },
+        _ => Err(Error::Type),
+    }
+}
+
+// Convert between int and float, folding literals.
+fn arithmetic_cast(node: Node, ctype: &CType) -> Node {
+    match (node, ctype) {
+        (Node::IntLit { val, .. }, CType::Float) => Node::FloatLit {
+            ctype: CType::Float,
+            val: val as f32,
+        },
+        (Node::FloatLit { val, .. }, CType::Int) => Node::IntLit {
+            ctype: CType::Int,
+            val: val as i32,
+        },
+        (node, _) => node.cast(ctype),
+    }
+}
+
+// Explicit casts also allow reinterpreting pointers as other pointers or ints.
+fn check_cast(ctype: CType, expr: Node) -> Result<Node, Error> {
+    match (&ctype, expr.ctype()) {
+        (to, from) if *to == from => Ok(expr),
+        (CType::Int | CType::Float, CType::Int | CType::Float) => Ok(arithmetic_cast(expr, &ctype)),
+        (CType::Int, CType::Ptr(_)) | (CType::Ptr(_), CType::Int) => Ok(expr.cast(&ctype)),
+        (CType::Ptr(to), CType::Ptr(from)) => {
+            if **to != CType::Void && *from != CType::Void {
+                error::warning(format!(
+                    "cast between incompatible pointer types {from}* and {to}*"
+                ));
+            }
+            Ok(expr.cast(&ctype))
+        }
+        _ => Err(Error::Type),
+    }
+}
+
+// Pointer arithmetic scales the integer operand by the size of the pointee,
+// everything else goes through the usual arithmetic conversions.
+fn check_binary_op(op: BinOp, lhs: Node, rhs: Node) -> Result<Node, Error> {
+    let lctype = lhs.ctype();
+    let rctype = rhs.ctype();
+    match (&lctype, &rctype, &op) {
+        (CType::Ptr(_), CType::Int, BinOp::Plus | BinOp::Minus) => {
+            let size = lctype.clone().dereference()?.size()?;
+            Ok(Node::BinaryOp {
+                ctype: lctype,
+                op,
+                lhs: Box::new(lhs),
+                rhs: Box::new(scale(rhs, size)),
+            })
+        }
+        (CType::Int, CType::Ptr(_), BinOp::Plus) => check_binary_op(op, rhs, lhs),
+        (CType::Ptr(_), CType::Ptr(_), BinOp::Minus) if lctype == rctype => {
+            let size = lctype.dereference()?.size()?;
+            Ok(Node::BinaryOp {
+                ctype: CType::Int,
+                op: BinOp::Divide,
+                lhs: Box::new(Node::BinaryOp {
+                    ctype: CType::Int,
+                    op,
+                    lhs: Box::new(lhs),
+                    rhs: Box::new(rhs),
+                }),
+                rhs: Box::new(Node::IntLit {
+                    ctype: CType::Int,
+                    val: size,
+                }),
+            })
+        }
+        _ => {
+            let ctype = common_type(&lctype, &rctype)?;
+            Ok(Node::BinaryOp {
+                lhs: Box::new(convert(lhs, &ctype)?),
+                rhs: Box::new(convert(rhs, &ctype)?),
+                ctype,
+                op,
+            })
+        }
+    }
+}
+
+// Multiply an integer offset by an element size.
+fn scale(offset: Node, size: i32) -> Node {
+    if size == 1 {
+        return offset;
+    }
+    match offset {
+        Node::IntLit { ctype, val } => Node::IntLit {
+            ctype,
+            val: val * size,
+        },
+        _ => Node::BinaryOp {
+            ctype: CType::Int,
+            op: BinOp::Times,
+            lhs: Box::new(offset),
+            rhs: Box::new(Node::IntLit {
+                ctype: CType::Int,
+                val: size,
+            }),
+        },
+    }
+}
+
+// Bring both sides of a comparison, or both arms of a ?:, to a common type.
+// Pointers match pointers of the same type, void* and null pointer constants.
+fn unify_comparison(lhs: Node, rhs: Node) -> Result<(Node, Node), Error> {
+    match (lhs.ctype(), rhs.ctype()) {
+        (l, r) if l == r => Ok((lhs, rhs)),
+        (CType::Ptr(l), CType::Ptr(r)) if *l == CType::Void || *r == CType::Void => Ok((lhs, rhs)),
+        (CType::Ptr(_), CType::Int) if rhs.const_int() == Some(0) => {
+            let ctype = lhs.ctype();
+            Ok((lhs, convert(rhs, &ctype)?))
+        }
+        (CType::Int, CType::Ptr(_)) if lhs.const_int() == Some(0) => {
+            let ctype = rhs.ctype();
+            Ok((convert(lhs, &ctype)?, rhs))
+        }
+        (l, r) => {
+            let ctype = common_type(&l, &r)?;
+            Ok((convert(lhs, &ctype)?, convert(rhs, &ctype)?))
+        }
+    }
+}
+
+// Warn when malloc'ing a constant number of bytes that cannot hold a whole
+// number of elements of the pointee.
+fn check_malloc_size(rhs: &Node, ctype: &CType) {
+    if let (Node::Malloc { expr, .. }, CType::Ptr(pointee)) = (rhs, ctype) {
+        if let (Some(bytes), Ok(size)) = (expr.const_int(), pointee.size()) {
+            if bytes % size != 0 {
+                error::warning(format!(
+                    "malloc of {bytes} bytes is not a multiple of sizeof({pointee}) = {size}"
+                ));
+            }
+        }
+    }
+}
@@ -1,6 +1,7 @@
 use std::fmt;
 use std::io;
 
+use crate::listing::Span;
 use crate::parser::Rule;
 
 pub enum Error {
@@ -9,11 +10,40 @@ pub enum Error {
     SymTable(String),
     ThreeAC(String),
     RegAlloc(String),
+    Link(String),
+    // An error in the statement or declaration at the span of the
+    // preprocessed text, and the same once pointed at the original file.
+    Spanned(Span, Box<Error>),
+    Located(String, Box<Error>),
     PairsNext,
     Type,
     Other(String),
 }
 
+// Report a suspicious but valid construct without stopping compilation.
+pub fn warning(message: String) {
+    eprintln!("WARNING: {message}");
+}
+
+impl Error {
+    // Tie the error to the source at span, unless it already is to a part of
+    // it.
+    pub fn at(self, span: Span) -> Self {
+        match self {
+            Self::Parse(_) | Self::Spanned(..) => self,
+            error => Self::Spanned(span, Box::new(error)),
+        }
+    }
+
+    // The error without where it is.
+    pub fn cause(&self) -> &Self {
+        match self {
+            Self::Spanned(_, e) | Self::Located(_, e) => e.cause(),
+            error => error,
+        }
+    }
+}
+
 impl From<io::Error> for Error {
     fn from(e: io::Error) -> Self {
         Self::Io(Box::new(e))
@@ -34,6 +64,9 @@ impl fmt::Display for Error {
             Self::SymTable(s) => write!(f, "{s}"),
             Self::ThreeAC(s) => write!(f, "{s}"),
             Self::RegAlloc(s) => write!(f, "{s}"),
+            Self::Link(s) => write!(f, "{s}"),
+            Self::Spanned(_, e) => write!(f, "{e}"),
+            Self::Located(at, e) => write!(f, "{at}: {e}"),
             Self::PairsNext => write!(f, "failed to get next sub pair"),
             Self::Type => write!(f, "TYPE ERROR"),
             Self::Other(s) => write!(f, "{s}"),
@@ -1,34 +1,215 @@
 use crate::ast;
 use crate::error::Error;
+use crate::listing::Code;
+use crate::parser::{GLOBAL_BASE, STRING_BASE};
 use crate::regalloc;
-use crate::symtable::SymTable;
-use crate::three_ac::{Count, Instructions};
+use crate::symtable::{Entry, SymTable, SymbolType};
+use crate::three_ac::{Cfg, Count, Instructions, Label};
 
 pub fn generate_code(
     ast: ast::Node,
     mut symtable: SymTable,
     reg_count: u32,
-) -> Result<String, Error> {
-    let instructions = get_instructions(ast, &mut symtable)?;
-    // println!("{}", instructions);
-    regalloc::from_instructions(instructions, reg_count)
+    optimize: bool,
+) -> Result<Code, Error> {
+    let main = check_program(&ast, &symtable)?;
+    let cfg = get_cfg(ast, &mut symtable, main, optimize, false)?;
+    regalloc::from_cfg(cfg, reg_count, global_names(&symtable), optimize)
 }
 
-fn get_instructions(ast: ast::Node, symtable: &mut SymTable) -> Result<Instructions, Error> {
+// The 3AC of a program, one instruction per line.
+pub fn generate_3ac(ast: ast::Node, mut symtable: SymTable, optimize: bool) -> Result<Code, Error> {
+    let main = check_program(&ast, &symtable)?;
+    Ok(get_cfg(ast, &mut symtable, main, optimize, false)?
+        .instructions()
+        .map(|i| (i.span, i.to_string()))
+        .collect())
+}
+
+// The 3AC of a program by function and basic block, with the edges and the
+// immediate dominator of each block. With ssa it is shown in SSA form.
+pub fn generate_cfg(
+    ast: ast::Node,
+    mut symtable: SymTable,
+    optimize: bool,
+    ssa: bool,
+) -> Result<Code, Error> {
+    let main = check_program(&ast, &symtable)?;
+    let cfg = get_cfg(ast, &mut symtable, main, optimize, ssa)?;
+    let list = |blocks: &[usize]| match blocks {
+        [] => String::from("-"),
+        _ => blocks
+            .iter()
+            .map(|b| b.to_string())
+            .collect::<Vec<_>>()
+            .join(" "),
+    };
+
+    let mut code: Code = cfg.header.iter().map(|i| (i.span, i.to_string())).collect();
+    for f in &cfg.functions {
+        code.push((None, format!("function {} exit {}\n", f.name, f.exit)));
+        let dominators = f.dominators();
+        for (k, b) in f.blocks.iter().enumerate() {
+            let idom = dominators
+                .idom(k)
+                .map_or(String::from("-"), |d| d.to_string());
+            code.push((
+                None,
+                format!(
+                    "block {k} succ {} pred {} idom {idom}\n",
+                    list(&b.successors),
+                    list(&b.predecessors)
+                ),
+            ));
+            code.extend(b.instructions.iter().map(|i| (i.span, format!("    {i}"))));
+        }
+    }
+    code.extend(cfg.trailer.iter().map(|i| (i.span, i.to_string())));
+    Ok(code)
+}
+
+// Every function that is called (and main) must have a body somewhere. Gives
+// the entry point, if there is one.
+fn check_program(ast: &ast::Node, symtable: &SymTable) -> Result<Option<&'static str>, Error> {
+    let main = String::from("main");
+    let mut used = Vec::new();
+    ast.referenced_globals(&mut used);
+    if symtable.contains_symbol(&main) {
+        used.push(main.clone());
+    }
+    for name in used {
+        if !symtable.get_symbol(&name)?.is_defined() {
+            return Err(Error::SymTable(format!(
+                "generate_code: {name} is declared but never defined"
+            )));
+        }
+    }
+    Ok(symtable.contains_symbol(&main).then_some("main"))
+}
+
+// A module exports everything it defines and imports what it uses without
+// defining, for the linker to connect. Strings stay private to the module.
+pub fn generate_module(
+    ast: ast::Node,
+    mut symtable: SymTable,
+    reg_count: u32,
+    optimize: bool,
+) -> Result<Code, Error> {
+    let mut used = Vec::new();
+    ast.referenced_globals(&mut used);
+    let mut imports = Vec::new();
+    for name in used {
+        let entry = symtable.get_symbol(&name)?;
+        if !entry.is_defined() {
+            imports.push((name, entry));
+        }
+    }
+
+    let cfg = get_cfg(ast, &mut symtable, None, optimize, false)?;
+    let (globals, strings) = symtable.get_addr_offset_val();
+    let mut header = format!(
+        ".module\n.data {}\n.strings {}\n",
+        globals - GLOBAL_BASE,
+        strings - STRING_BASE
+    );
+    for (name, entry) in symtable.global_entries() {
+        let is_str = matches!(
+            entry,
+            Entry::Symbol {
+                symtype: SymbolType::Str(_),
+                ..
+            }
+        );
+        if entry.is_defined() && !is_str {
+            header.push_str(&format!(
+                ".export {name} {}\n",
+                module_symbol(&name, &entry)
+            ));
+        }
+    }
+    for (name, entry) in imports {
+        header.push_str(&format!(
+            ".import {name} {}\n",
+            module_symbol(&name, &entry)
+        ));
+    }
+
+    let mut code = vec![(None, header)];
+    code.extend(regalloc::from_cfg(
+        cfg,
+        reg_count,
+        global_names(&symtable),
+        optimize,
+    )?);
+    Ok(code)
+}
+
+// The label or address of a symbol followed by its type.
+fn module_symbol(name: &str, entry: &Entry) -> String {
+    match entry {
+        Entry::Function {
+            ctype, arguments, ..
+        } => {
+            let arguments: Vec<String> = arguments.iter().map(|a| a.to_string()).collect();
+            format!(
+                "{} {ctype}({})",
+                Label::FunctionHead(name.to_owned()),
+                arguments.join(", ")
+            )
+        }
+        Entry::Symbol { ctype, address, .. } => format!("0x{address:08x} {ctype}"),
+    }
+}
+
+fn global_names(symtable: &SymTable) -> regalloc::Names {
+    symtable
+        .global_entries()
+        .into_iter()
+        .filter_map(|(name, entry)| match entry {
+            Entry::Symbol { address, .. } => Some((address, name)),
+            Entry::Function { .. } => None,
+        })
+        .collect()
+}
+
+// The 3AC of a program as control-flow graphs. With optimize small functions
+// are inlined, tail calls jump, and it is optimized in SSA form, and with ssa
+// it is left in SSA form.
+fn get_cfg(
+    ast: ast::Node,
+    symtable: &mut SymTable,
+    main: Option<&str>,
+    optimize: bool,
+    ssa: bool,
+) -> Result<Cfg, Error> {
     let instrs = Instructions::from_ast(
         ast,
         &mut Count {
             regular: 0,
             float: 0,
             label: 0,
+            slot: 0,
+            function: String::new(),
+            breaks: Vec::new(),
         },
         symtable,
     )?
-    .add_headers(
-        symtable.get_symbol(&String::from("main"))?.address(),
-        symtable.strings_in_asm(),
-    )
+    .add_headers(main, symtable.strings_in_asm())
     .optimize();
 
-    Ok(instrs)
+    let mut cfg = Cfg::from_instructions(instrs)?;
+    if optimize {
+        cfg.inline()?;
+        cfg.eliminate_tail_calls()?;
+    }
+    if optimize || ssa {
+        cfg.enter_ssa();
+        if optimize {
+            cfg.optimize()?;
+        }
+        if !ssa {
+            cfg.leave_ssa()?;
+        }
+    }
+    Ok(cfg)
 }
@@ -0,0 +1,217 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::error::Error;
+use crate::parser::{GLOBAL_BASE, STRING_BASE};
+use crate::regalloc;
+
+// A symbol in a module header: its label or address, and its type.
+struct Symbol {
+    name: String,
+    location: String,
+    ctype: String,
+}
+
+struct Module {
+    path: String,
+    data: i32,    // bytes of globals
+    strings: i32, // bytes of string slots
+    exports: Vec<Symbol>,
+    imports: Vec<Symbol>,
+    text: Vec<String>,
+    strs: Vec<String>,
+}
+
+enum Section {
+    Header,
+    Text,
+    Strings,
+}
+
+// Combine modules made by gencode::generate_module into one program. Each
+// module gets its own label prefix and slice of the global and string areas,
+// and every import is bound to the export of the same name.
+pub fn link(modules: Vec<(String, String)>) -> Result<String, Error> {
+    let modules = modules
+        .into_iter()
+        .map(|(path, text)| Module::parse(path, &text))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let mut offsets = Vec::new();
+    let (mut data, mut strings) = (0, 0);
+    for m in &modules {
+        offsets.push((data, strings));
+        data += m.data;
+        strings += m.strings;
+    }
+    let labels: Vec<HashSet<&str>> = modules.iter().map(|m| m.labels()).collect();
+
+    // Where every exported symbol ends up.
+    let mut exports: HashMap<&str, (&Symbol, &Module, String)> = HashMap::new();
+    for (k, m) in modules.iter().enumerate() {
+        for s in &m.exports {
+            let location = m.translate(&s.location, k, &labels[k], offsets[k]);
+            if exports.insert(&s.name, (s, m, location)).is_some() {
+                return Err(Error::Link(format!(
+                    "link: multiple definitions of {}",
+                    s.name
+                )));
+            }
+        }
+    }
+
+    let main = match exports.get("main") {
+        Some((s, _, location)) if s.ctype.ends_with(')') => location.clone(),
+        _ => return Err(Error::Link(String::from("link: main is not defined"))),
+    };
+    let mut text = regalloc::entry_code(&main);
+    let mut strs = String::from(".section .strings\n");
+
+    for (k, m) in modules.iter().enumerate() {
+        let mut imports = HashMap::new();
+        for s in &m.imports {
+            let (export, from, location) = exports.get(s.name.as_str()).ok_or_else(|| {
+                Error::Link(format!(
+                    "link: undefined reference to {} in {}",
+                    s.name, m.path
+                ))
+            })?;
+            if export.ctype != s.ctype {
+                return Err(Error::Link(format!(
+                    "link: {} is {} in {} but {} in {}",
+                    s.name, s.ctype, m.path, export.ctype, from.path
+                )));
+            }
+            imports.insert(s.location.as_str(), location.as_str());
+        }
+
+        let translate = |word: &str| match imports.get(word) {
+            Some(location) => location.to_string(),
+            None => m.translate(word, k, &labels[k], offsets[k]),
+        };
+        for line in &m.text {
+            text.push_str(&rewrite(line, translate));
+            text.push('\n');
+        }
+        for line in &m.strs {
+            let (address, value) = line.split_once(' ').unwrap_or((line, ""));
+            strs.push_str(&format!("{} {value}\n", translate(address)));
+        }
+    }
+
+    Ok(text + &strs)
+}
+
+impl Module {
+    fn parse(path: String, text: &str) -> Result<Self, Error> {
+        let mut lines = text.lines();
+        if lines.next() != Some(".module") {
+            return Err(Error::Link(format!("link: {path} is not a module")));
+        }
+
+        let mut module = Module {
+            path,
+            data: 0,
+            strings: 0,
+            exports: Vec::new(),
+            imports: Vec::new(),
+            text: Vec::new(),
+            strs: Vec::new(),
+        };
+        let mut section = Section::Header;
+        for line in lines {
+            match line {
+                ".section .text" => section = Section::Text,
+                ".section .strings" => section = Section::Strings,
+                _ => match section {
+                    Section::Header => module.parse_header(line)?,
+                    Section::Text => module.text.push(line.to_owned()),
+                    Section::Strings => module.strs.push(line.to_owned()),
+                },
+            }
+        }
+        Ok(module)
+    }
+
+    fn parse_header(&mut self, line: &str) -> Result<(), Error> {
+        let mut words = line.splitn(4, ' ');
+        match (words.next(), words.next(), words.next(), words.next()) {
+            (Some(".data"), Some(n), None, None) => {
+                self.data = n.parse().map_err(|_| self.invalid(line))?;
+            }
+            (Some(".strings"), Some(n), None, None) => {
+                self.strings = n.parse().map_err(|_| self.invalid(line))?;
+            }
+            (Some(directive), Some(name), Some(location), Some(ctype)) => {
+                let symbol = Symbol {
+                    name: name.to_owned(),
+                    location: location.to_owned(),
+                    ctype: ctype.to_owned(),
+                };
+                match directive {
+                    ".export" => self.exports.push(symbol),
+                    ".import" => self.imports.push(symbol),
+                    _ => return Err(self.invalid(line)),
+                }
+            }
+            _ => return Err(self.invalid(line)),
+        }
+        Ok(())
+    }
+
+    fn invalid(&self, line: &str) -> Error {
+        Error::Link(format!("link: invalid line in {}: {line}", self.path))
+    }
+
+    fn labels(&self) -> HashSet<&str> {
+        self.text
+            .iter()
+            .filter_map(|line| line.strip_suffix(':'))
+            .collect()
+    }
+
+    // Move a label or address of module k to where it is in the program.
+    fn translate(
+        &self,
+        word: &str,
+        k: usize,
+        labels: &HashSet<&str>,
+        (data, strings): (i32, i32),
+    ) -> String {
+        if labels.contains(word) {
+            return format!("m{k}_{word}");
+        }
+        match word
+            .strip_prefix("0x")
+            .and_then(|a| i32::from_str_radix(a, 16).ok())
+        {
+            Some(a) if (GLOBAL_BASE..GLOBAL_BASE + self.data).contains(&a) => {
+                format!("0x{:08x}", a + data)
+            }
+            Some(a) if (STRING_BASE..STRING_BASE + self.strings).contains(&a) => {
+                format!("0x{:08x}", a + strings)
+            }
+            _ => word.to_owned(),
+        }
+    }
+}
+
+// Instructions whose last operand is a label or an address.
+const TARGETED: [&str; 9] = ["J", "JR", "LA", "BEQ", "BNE", "BLT", "BLE", "BGT", "BGE"];
+
+// Apply f to the label an assembly line defines, or to the target of a jump,
+// branch or address load. Mnemonics, registers and comments are left alone,
+// so a symbol may be named like any of them.
+fn rewrite(line: &str, f: impl Fn(&str) -> String) -> String {
+    let code = &line[..line.find('#').unwrap_or(line.len())];
+    let end = code.trim_end().len();
+    if let Some(label) = code[..end].strip_suffix(':') {
+        return format!("{}:{}", f(label), &line[end..]);
+    }
+    match code.split_once(' ') {
+        Some((mnemonic, _)) if TARGETED.contains(&mnemonic) => {
+            let start = code[..end].rfind([' ', ',']).map_or(0, |i| i + 1);
+            format!("{}{}{}", &code[..start], f(&code[start..end]), &line[end..])
+        }
+        _ => line.to_owned(),
+    }
+}
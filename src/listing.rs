@@ -0,0 +1,52 @@
+use crate::preprocess::Source;
+
+// Byte range of a statement in the preprocessed text.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
+impl From<pest::Span<'_>> for Span {
+    fn from(span: pest::Span) -> Self {
+        Self {
+            start: span.start(),
+            end: span.end(),
+        }
+    }
+}
+
+// Emitted text, each piece with the span of the statement it came from.
+pub type Code = Vec<(Option<Span>, String)>;
+
+// Join the code into the final text, and a listing that gives the source span
+// of each of its lines. With annotate, the source lines of each statement are
+// added as comments before its code.
+pub fn render(code: Code, source: &Source, annotate: bool) -> (String, String) {
+    let mut text = String::new();
+    let mut listing = String::new();
+    let mut lines = 0;
+    let mut last = Vec::new();
+    for (span, piece) in code {
+        let mut chunk = String::new();
+        let comments = match span {
+            Some(span) if annotate && !piece.is_empty() => source.lines_of(span),
+            _ => Vec::new(),
+        };
+        if !comments.is_empty() && comments != last {
+            for comment in &comments {
+                chunk.push_str(&format!("# {comment}\n"));
+            }
+            last = comments;
+        }
+        chunk.push_str(&piece);
+
+        let located = span.map(|span| source.describe(span)).unwrap_or_default();
+        for line in chunk.lines() {
+            lines += 1;
+            listing.push_str(&format!("{lines:>6}  {located:<24}  {line}\n"));
+        }
+        text.push_str(&chunk);
+    }
+    (text, listing)
+}
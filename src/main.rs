@@ -3,45 +3,126 @@ extern crate pest;
 extern crate pest_derive;
 
 use std::env;
+use std::fs;
 use std::process::ExitCode;
 
+use error::Error;
+
 mod ast;
 mod error;
 mod gencode;
+mod linker;
+mod listing;
 mod parser;
+mod preprocess;
 mod regalloc;
 mod symtable;
 mod three_ac;
 
-fn main() -> ExitCode {
-    let args: Vec<String> = env::args().collect();
-    if args.len() != 3 {
-        eprintln!("main: expected two arguments");
-        return ExitCode::FAILURE;
-    }
+const USAGE: &str = "main: expected [-c] [-O] [--3ac] [--cfg] [--ssa] [--source] \
+                     [--listing <out>] <file> <regs> or -l <module>...";
 
-    let (ast, symtable) = match parser::parse_file(&args[1]) {
-        Ok(res) => res,
-        Err(error) => {
-            eprintln!("{error}");
-            return match error {
-                error::Error::Type => ExitCode::from(7),
-                _ => ExitCode::FAILURE,
-            };
-        }
+// What to make of a source file.
+struct Options {
+    module: bool,            // -c: a module for the linker
+    optimize: bool,          // -O: optimize in SSA form
+    tac: bool,               // --3ac: the 3AC instead of assembly
+    cfg: bool,               // --cfg: the 3AC by basic block
+    ssa: bool,               // --ssa: as --cfg, in SSA form
+    source: bool,            // --source: source lines as comments
+    listing: Option<String>, // --listing: where to write the line map
+}
+
+// project [options] <file> <regs>   compile a file
+// project -l <module>...            link modules into a program
+fn main() -> ExitCode {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let result = match args.first().map(String::as_str) {
+        Some("-l") if args.len() > 1 => link(&args[1..]),
+        _ => match parse_args(&args) {
+            Some((options, path, regs)) => compile(&options, path, regs),
+            None => {
+                eprintln!("{USAGE}");
+                return ExitCode::FAILURE;
+            }
+        },
     };
 
-    match gencode::generate_code(ast, symtable, args[2].parse::<u32>().unwrap()) {
+    match result {
         Ok(s) => {
             print!("{s}");
             ExitCode::SUCCESS
         }
         Err(error) => {
             eprintln!("{error}");
-            match error {
-                error::Error::Type => ExitCode::from(7),
+            match error.cause() {
+                Error::Type => ExitCode::from(7),
                 _ => ExitCode::FAILURE,
             }
         }
     }
 }
+
+fn parse_args(args: &[String]) -> Option<(Options, &str, &str)> {
+    let mut options = Options {
+        module: false,
+        optimize: false,
+        tac: false,
+        cfg: false,
+        ssa: false,
+        source: false,
+        listing: None,
+    };
+    let mut positional = Vec::new();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-c" => options.module = true,
+            "-O" => options.optimize = true,
+            "--3ac" => options.tac = true,
+            "--cfg" => options.cfg = true,
+            "--ssa" => options.ssa = true,
+            "--source" => options.source = true,
+            "--listing" => options.listing = Some(args.next()?.clone()),
+            _ => positional.push(arg.as_str()),
+        }
+    }
+    match positional[..] {
+        [path, regs] if !(options.module && (options.tac || options.cfg || options.ssa)) => {
+            Some((options, path, regs))
+        }
+        _ => None,
+    }
+}
+
+fn compile(options: &Options, path: &str, regs: &str) -> Result<String, Error> {
+    let regs = regs
+        .parse::<u32>()
+        .map_err(|_| Error::Other(format!("main: invalid register count {regs}")))?;
+    let source = preprocess::from_file(path)?;
+    let (ast, symtable) = parser::parse_source(&source)?;
+    let code = if options.cfg || options.ssa {
+        gencode::generate_cfg(ast, symtable, options.optimize, options.ssa)
+    } else if options.tac {
+        gencode::generate_3ac(ast, symtable, options.optimize)
+    } else if options.module {
+        gencode::generate_module(ast, symtable, regs, options.optimize)
+    } else {
+        gencode::generate_code(ast, symtable, regs, options.optimize)
+    }
+    .map_err(|e| source.locate(e))?;
+
+    let (text, listing) = listing::render(code, &source, options.source);
+    if let Some(out) = &options.listing {
+        fs::write(out, listing)?;
+    }
+    Ok(text)
+}
+
+fn link(paths: &[String]) -> Result<String, Error> {
+    let modules = paths
+        .iter()
+        .map(|path| Ok((path.clone(), fs::read_to_string(path)?)))
+        .collect::<Result<Vec<_>, Error>>()?;
+    linker::link(modules)
+}
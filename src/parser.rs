@@ -1,75 +1,94 @@
-use pest::iterators::Pairs;
+use pest::iterators::{Pair, Pairs};
 use pest::Parser;
-use std::fs;
 
 use crate::ast;
 use crate::error::Error;
+use crate::listing::Span;
+use crate::preprocess::Source;
 use crate::symtable::{CType, SymTable, SymbolType};
 
-const GLOBAL_BASE: i32 = 0x20000000;
-const STRING_BASE: i32 = 0x10000000;
+pub const GLOBAL_BASE: i32 = 0x20000000;
+pub const STRING_BASE: i32 = 0x10000000;
+// Placeholder addresses for extern variables, never seen after linking.
+pub const EXTERN_BASE: i32 = 0x28000000;
 
 #[derive(Parser)]
 #[grammar = "micro_c.pest"]
 pub struct MicroC;
 
-pub fn parse_file(path: &String) -> Result<(ast::Node, SymTable), Error> {
-    let unparsed = fs::read_to_string(path)?;
-    let parse_result = MicroC::parse(Rule::program, &unparsed)?;
-    parse_program(parse_result)
+pub fn parse_source(source: &Source) -> Result<(ast::Node, SymTable), Error> {
+    MicroC::parse(Rule::program, &source.text)
+        .map_err(Error::from)
+        .and_then(parse_program)
+        .map_err(|e| source.locate(e))
 }
 
-// Build AST and SymTable from the declaration and function trees.
+// Build AST and SymTable from the top-level declarations and functions, in
+// source order, so that a symbol is only visible after its declaration.
 pub fn parse_program(pairs: Pairs<Rule>) -> Result<(ast::Node, SymTable), Error> {
     let mut statements: Vec<ast::Node> = Vec::new();
-    let mut symtable = SymTable::new(GLOBAL_BASE, STRING_BASE);
+    let mut symtable = SymTable::new(GLOBAL_BASE, STRING_BASE, EXTERN_BASE);
 
     for pair in pairs {
-        match pair.as_rule() {
-            // Declarations
-            Rule::func_decl => {
-                let mut subpairs = pair.into_inner();
-                let ctype =
-                    CType::from_base_type(subpairs.next().ok_or(Error::PairsNext)?.into_inner());
-                let name = subpairs.next().ok_or(Error::PairsNext)?.as_str().to_owned();
-                let arguments: Vec<CType> = subpairs
-                    .next()
-                    .ok_or(Error::PairsNext)?
-                    .into_inner()
-                    .map(|pair| match pair.into_inner().peek() {
-                        Some(p) => CType::from_base_type(p.into_inner()),
-                        None => unreachable!("expected base_type, got nothing"),
-                    })
-                    .collect();
-                symtable.add_function(ctype, name, arguments)?;
-            }
-            Rule::var_decl => {
-                let mut subpairs = pair.into_inner();
-                let ctype =
-                    CType::from_base_type(subpairs.next().ok_or(Error::PairsNext)?.into_inner());
-                symtable.add_symbol(
-                    ctype,
-                    subpairs.next().ok_or(Error::PairsNext)?.as_str().to_owned(),
-                    SymbolType::Global,
-                )?;
-            }
-            Rule::str_decl => {
-                let mut subpairs = pair.into_inner();
-                symtable.add_symbol(
-                    CType::Str,
-                    subpairs.next().ok_or(Error::PairsNext)?.as_str().to_owned(),
-                    SymbolType::Str(subpairs.next().ok_or(Error::PairsNext)?.as_str().to_owned()),
-                )?;
-            }
-            Rule::function => statements.push(ast::construct::from_function(pair, &mut symtable)?),
-            r => {
-                return Err(Error::Other(format!(
-                    "parse_program: expected decls and function, found {:?}",
-                    r
-                )))
-            }
-        }
+        let span = Span::from(pair.as_span());
+        parse_item(pair, &mut symtable, &mut statements).map_err(|e| e.at(span))?;
     }
 
     Ok((ast::Node::StatementList { statements }, symtable))
 }
+
+// Add a top-level declaration to the symbol table, or a function to the
+// statements as well.
+fn parse_item(
+    pair: Pair<Rule>,
+    symtable: &mut SymTable,
+    statements: &mut Vec<ast::Node>,
+) -> Result<(), Error> {
+    match pair.as_rule() {
+        // Declarations
+        Rule::func_decl => {
+            // Functions are always extern, the keyword changes nothing.
+            let mut subpairs = pair.into_inner().filter(|p| p.as_rule() != Rule::extern_kw);
+            let ctype =
+                CType::from_base_type(subpairs.next().ok_or(Error::PairsNext)?.into_inner());
+            let name = subpairs.next().ok_or(Error::PairsNext)?.as_str().to_owned();
+            let arguments: Vec<CType> = subpairs
+                .next()
+                .ok_or(Error::PairsNext)?
+                .into_inner()
+                .map(|pair| Ok(CType::from_declaration(&mut pair.into_inner())?.0))
+                .collect::<Result<_, Error>>()?;
+            symtable.add_function(ctype, name, arguments)?;
+        }
+        Rule::var_decl => {
+            let (ctype, name) = CType::from_declaration(&mut pair.into_inner())?;
+            symtable.add_symbol(ctype, name, SymbolType::Global)?;
+        }
+        Rule::extern_decl => {
+            let mut subpairs = pair.into_inner();
+            subpairs.next().ok_or(Error::PairsNext)?; // extern_kw
+            let (ctype, name) = CType::from_declaration(&mut subpairs)?;
+            symtable.add_symbol(ctype, name, SymbolType::Extern)?;
+        }
+        Rule::str_decl => {
+            let mut subpairs = pair.into_inner();
+            symtable.add_symbol(
+                CType::Str,
+                subpairs.next().ok_or(Error::PairsNext)?.as_str().to_owned(),
+                SymbolType::Str(subpairs.next().ok_or(Error::PairsNext)?.as_str().to_owned()),
+            )?;
+        }
+        Rule::function => {
+            let function = ast::construct::from_function(pair, symtable)?;
+            statements.push(ast::typecheck::check(function, symtable)?);
+        }
+        Rule::EOI => {}
+        r => {
+            return Err(Error::Other(format!(
+                "parse_program: expected declaration or function, found {:?}",
+                r
+            )))
+        }
+    }
+    Ok(())
+}
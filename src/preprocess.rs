@@ -0,0 +1,564 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use pest::error::LineColLocation;
+
+use crate::error::{self, Error};
+use crate::listing::Span;
+
+// Deep enough for any sane nesting of headers, shallow enough to stop an
+// include cycle quickly.
+const MAX_INCLUDE_DEPTH: usize = 64;
+
+// Preprocessed text along with the file and line each of its lines came from.
+pub struct Source {
+    pub text: String,
+    files: Vec<String>,
+    contents: Vec<String>,
+    lines: Vec<(usize, usize)>,
+}
+
+impl Source {
+    // Point an error at the original file and line instead of the
+    // preprocessed text.
+    pub fn locate(&self, error: Error) -> Error {
+        let mut e = match error {
+            Error::Parse(e) => e,
+            Error::Spanned(span, e) => {
+                return match self.position(span.start) {
+                    Some((file, line, col)) => {
+                        Error::Located(format!("{}:{line}:{col}", self.files[file]), e)
+                    }
+                    None => *e,
+                }
+            }
+            error => return error,
+        };
+        let map = |(line, col): (usize, usize)| {
+            self.lines
+                .get(line.wrapping_sub(1))
+                .map(|&(file, line)| (file, (line, col)))
+        };
+        let file = match e.line_col.clone() {
+            LineColLocation::Pos(pos) => map(pos).map(|(file, pos)| {
+                e.line_col = LineColLocation::Pos(pos);
+                file
+            }),
+            LineColLocation::Span(start, end) => map(start).map(|(file, start)| {
+                e.line_col = match map(end) {
+                    Some((end_file, end)) if end_file == file => LineColLocation::Span(start, end),
+                    _ => LineColLocation::Pos(start),
+                };
+                file
+            }),
+        };
+        match file {
+            Some(file) => Error::Parse(Box::new(e.with_path(&self.files[file]))),
+            None => Error::Parse(e),
+        }
+    }
+
+    // Original file, line and column of an offset in the preprocessed text.
+    fn position(&self, offset: usize) -> Option<(usize, usize, usize)> {
+        let (line, col) = pest::Position::new(&self.text, offset)?.line_col();
+        let &(file, line) = self.lines.get(line - 1)?;
+        Some((file, line, col))
+    }
+
+    // A span as file:line:col-line:col.
+    pub fn describe(&self, span: Span) -> String {
+        match (self.position(span.start), self.position(span.end)) {
+            (Some((file, line, col)), Some((end_file, end_line, end_col))) if file == end_file => {
+                format!("{}:{line}:{col}-{end_line}:{end_col}", self.files[file])
+            }
+            (Some((file, line, col)), _) => format!("{}:{line}:{col}", self.files[file]),
+            _ => String::new(),
+        }
+    }
+
+    // The original lines a span covers, each as file:line: text.
+    pub fn lines_of(&self, span: Span) -> Vec<String> {
+        let (start, end) = match (self.position(span.start), self.position(span.end)) {
+            (Some(start), Some(end)) => (start, end),
+            _ => return Vec::new(),
+        };
+        let (file, first, _) = start;
+        let last = match end {
+            (end_file, line, _) if end_file == file => line.max(first),
+            _ => first,
+        };
+        self.contents[file]
+            .lines()
+            .enumerate()
+            .skip(first - 1)
+            .take(last - first + 1)
+            .map(|(n, text)| format!("{}:{}: {}", self.files[file], n + 1, text.trim()))
+            .collect()
+    }
+}
+
+pub fn from_file(path: &str) -> Result<Source, Error> {
+    let mut preprocessor = Preprocessor {
+        source: Source {
+            text: String::new(),
+            files: Vec::new(),
+            contents: Vec::new(),
+            lines: Vec::new(),
+        },
+        macros: HashMap::new(),
+    };
+    preprocessor.include(Path::new(path), 0)?;
+    Ok(preprocessor.source)
+}
+
+struct Macro {
+    params: Option<Vec<String>>, // None for object-like macros
+    body: String,
+}
+
+// An open #ifdef or #ifndef.
+struct Cond {
+    parent: bool, // whether the enclosing group is kept
+    active: bool,
+    has_else: bool,
+}
+
+struct Preprocessor {
+    source: Source,
+    macros: HashMap<String, Macro>,
+}
+
+impl Preprocessor {
+    fn include(&mut self, path: &Path, depth: usize) -> Result<(), Error> {
+        let text = fs::read_to_string(path).map_err(|e| {
+            Error::Other(format!("preprocess: cannot read {}: {e}", path.display()))
+        })?;
+        let file = self.source.files.len();
+        self.source.files.push(path.display().to_string());
+        self.source.contents.push(text.clone());
+
+        let mut conds: Vec<Cond> = Vec::new();
+        let mut in_comment = false;
+        let mut lines = text.lines().enumerate().peekable();
+        while let Some((n, line)) = lines.next() {
+            let here = (file, n + 1);
+            let active = conds.last().is_none_or(|c| c.active);
+
+            if !in_comment && line.trim_start().starts_with('#') {
+                let mut line = line.to_owned();
+                while line.ends_with('\\') {
+                    line.pop();
+                    match lines.next() {
+                        Some((_, next)) => line.push_str(next),
+                        None => break,
+                    }
+                }
+                let tokens = tokenize(&line, &mut in_comment);
+                let header = self
+                    .directive(&tokens, &mut conds, path, depth)
+                    .map_err(|message| self.error(here, message))?;
+                if let Some(header) = header {
+                    self.include(&header, depth + 1)?;
+                }
+                continue;
+            }
+
+            // A macro call may go on over the next lines, which are joined to
+            // this one and counted as part of it.
+            let mut joined = line.to_owned();
+            while active && self.open_call(&tokenize(&joined, &mut in_comment.clone())) {
+                match lines.peek() {
+                    Some((_, next)) if !next.trim_start().starts_with('#') => {
+                        joined.push('\n');
+                        joined.push_str(next);
+                        lines.next();
+                    }
+                    _ => break,
+                }
+            }
+
+            let tokens = tokenize(&joined, &mut in_comment);
+            if active {
+                let line = self
+                    .expand(&tokens, &mut Vec::new())
+                    .map_err(|message| self.error(here, message))?
+                    .replace('\n', " ");
+                self.source.text.push_str(&line);
+                self.source.text.push('\n');
+                self.source.lines.push(here);
+            }
+        }
+
+        if !conds.is_empty() {
+            return Err(self.error(
+                (file, text.lines().count()),
+                String::from("unterminated #ifdef"),
+            ));
+        }
+        Ok(())
+    }
+
+    // Handle a directive line. Gives the path of the file to splice in for
+    // an #include.
+    fn directive(
+        &mut self,
+        tokens: &[Token],
+        conds: &mut Vec<Cond>,
+        path: &Path,
+        depth: usize,
+    ) -> Result<Option<PathBuf>, String> {
+        let start = tokens
+            .iter()
+            .position(|t| *t == Token::Other("#"))
+            .map_or(tokens.len(), |p| p + 1);
+        let tokens = &tokens[start..];
+        let mut words = tokens
+            .iter()
+            .enumerate()
+            .filter(|(_, t)| !matches!(t, Token::Space(_)));
+        let (at, name) = match words.next() {
+            Some((at, Token::Ident(name))) => (at, *name),
+            Some((_, t)) => return Err(format!("invalid directive #{}", t.text())),
+            None => return Ok(None),
+        };
+        let mut words = words.map(|(_, t)| t);
+        let active = conds.last().is_none_or(|c| c.active);
+
+        match name {
+            "ifdef" | "ifndef" => {
+                let defined = match words.next() {
+                    Some(Token::Ident(m)) => self.macros.contains_key(*m),
+                    _ => return Err(format!("#{name} expects a macro name")),
+                };
+                conds.push(Cond {
+                    parent: active,
+                    active: active && defined == (name == "ifdef"),
+                    has_else: false,
+                });
+            }
+            "else" => {
+                let cond = conds
+                    .last_mut()
+                    .ok_or_else(|| String::from("#else without #ifdef"))?;
+                if cond.has_else {
+                    return Err(String::from("#else after #else"));
+                }
+                cond.has_else = true;
+                cond.active = cond.parent && !cond.active;
+            }
+            "endif" => {
+                conds
+                    .pop()
+                    .ok_or_else(|| String::from("#endif without #ifdef"))?;
+            }
+
+            // Everything else is ignored in a skipped group.
+            _ if !active => {}
+            "define" => self.define(&tokens[at + 1..])?,
+            "undef" => match words.next() {
+                Some(Token::Ident(m)) => {
+                    self.macros.remove(*m);
+                }
+                _ => return Err(String::from("#undef expects a macro name")),
+            },
+            "include" => {
+                let file = match words.next() {
+                    Some(Token::Other(s))
+                        if s.len() > 1 && s.starts_with('"') && s.ends_with('"') =>
+                    {
+                        &s[1..s.len() - 1]
+                    }
+                    _ => return Err(String::from("#include expects \"file\"")),
+                };
+                if depth >= MAX_INCLUDE_DEPTH {
+                    return Err(String::from("#include nested too deeply"));
+                }
+                return Ok(Some(path.parent().unwrap_or(Path::new("")).join(file)));
+            }
+            _ => return Err(format!("unknown directive #{name}")),
+        }
+        Ok(None)
+    }
+
+    // Parse `NAME body` or `NAME(params) body`. A function-like macro must
+    // have the '(' right after its name.
+    fn define(&mut self, tokens: &[Token]) -> Result<(), String> {
+        let mut tokens = tokens.iter().skip_while(|t| matches!(t, Token::Space(_)));
+        let name = match tokens.next() {
+            Some(Token::Ident(name)) => name.to_string(),
+            _ => return Err(String::from("#define expects a macro name")),
+        };
+
+        let mut rest: Vec<Token> = tokens.copied().collect();
+        let params = match rest.first() {
+            Some(Token::Other("(")) => {
+                let close = rest
+                    .iter()
+                    .position(|t| *t == Token::Other(")"))
+                    .ok_or_else(|| format!("missing ')' in parameters of {name}"))?;
+                let mut params = Vec::new();
+                for param in rest[1..close].split(|t| *t == Token::Other(",")) {
+                    let param: Vec<&Token> = param
+                        .iter()
+                        .filter(|t| !matches!(t, Token::Space(_)))
+                        .collect();
+                    match param.as_slice() {
+                        [Token::Ident(p)] => params.push(p.to_string()),
+                        [] if close == 1 => {}
+                        _ => return Err(format!("invalid parameter list for {name}")),
+                    }
+                }
+                rest.drain(..=close);
+                Some(params)
+            }
+            _ => None,
+        };
+
+        // Comments become plain spaces.
+        let body = rest
+            .iter()
+            .map(|t| match t {
+                Token::Space(_) => " ",
+                t => t.text(),
+            })
+            .collect::<String>()
+            .trim()
+            .to_owned();
+        if let Some(old) = self.macros.get(&name) {
+            if old.body != body || old.params != params {
+                error::warning(format!("macro {name} redefined"));
+            }
+        }
+        self.macros.insert(name, Macro { params, body });
+        Ok(())
+    }
+
+    // Expand the macros in a line. Names in `disabled` are being expanded
+    // already and are left alone, which stops recursive macros.
+    fn expand(&self, tokens: &[Token], disabled: &mut Vec<String>) -> Result<String, String> {
+        let mut out = String::new();
+        let mut i = 0;
+        while i < tokens.len() {
+            let token = tokens[i];
+            i += 1;
+            let (name, m) = match token {
+                Token::Ident(name) if !disabled.iter().any(|d| d == name) => {
+                    match self.macros.get(name) {
+                        Some(m) => (name, m),
+                        None => {
+                            out.push_str(name);
+                            continue;
+                        }
+                    }
+                }
+                _ => {
+                    out.push_str(token.text());
+                    continue;
+                }
+            };
+
+            let body = match &m.params {
+                None => m.body.clone(),
+                Some(params) => {
+                    let (args, next) = match arguments(tokens, i)
+                        .ok_or_else(|| format!("unterminated arguments to {name}"))?
+                    {
+                        Some(res) => res,
+                        // A function-like macro name without arguments is
+                        // not an invocation.
+                        None => {
+                            out.push_str(name);
+                            continue;
+                        }
+                    };
+                    let args = match (params.len(), args.as_slice()) {
+                        (0, [arg]) if arg.iter().all(|t| matches!(t, Token::Space(_))) => {
+                            Vec::new()
+                        }
+                        _ => args,
+                    };
+                    if args.len() != params.len() {
+                        return Err(format!(
+                            "{name} expects {} arguments, found {}",
+                            params.len(),
+                            args.len()
+                        ));
+                    }
+                    i = next;
+                    let args = args
+                        .into_iter()
+                        .map(|a| Ok(self.expand(a, disabled)?.trim().to_owned()))
+                        .collect::<Result<Vec<_>, String>>()?;
+                    substitute(&m.body, params, &args)
+                }
+            };
+
+            disabled.push(name.to_owned());
+            let expanded = self.expand(&tokenize(&body, &mut false), disabled);
+            disabled.pop();
+            let expanded = expanded?;
+
+            // An expansion is rescanned with the rest of the line, so a
+            // function-like macro it ends with takes its arguments from there.
+            let trimmed = expanded.trim_end();
+            let start = trimmed
+                .rfind(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                .map_or(0, |p| p + 1);
+            let last = &trimmed[start..];
+            if last != name
+                && !disabled.iter().any(|d| d == last)
+                && self.macros.get(last).is_some_and(|m| m.params.is_some())
+                && arguments(tokens, i) != Some(None)
+            {
+                out.push_str(&expanded[..start]);
+                let rest: String = std::iter::once(last)
+                    .chain(tokens[i..].iter().map(Token::text))
+                    .collect();
+                out.push_str(&self.expand(&tokenize(&rest, &mut false), disabled)?);
+                return Ok(out);
+            }
+            out.push_str(&expanded);
+        }
+        Ok(out)
+    }
+
+    // Whether a line ends inside the arguments of a function-like macro, or
+    // right after its name where they may still follow.
+    fn open_call(&self, tokens: &[Token]) -> bool {
+        tokens.iter().enumerate().any(|(i, t)| match t {
+            Token::Ident(name) if self.macros.get(*name).is_some_and(|m| m.params.is_some()) => {
+                arguments(tokens, i + 1).is_none()
+                    || tokens[i + 1..].iter().all(|t| matches!(t, Token::Space(_)))
+            }
+            _ => false,
+        })
+    }
+
+    fn error(&self, (file, line): (usize, usize), message: String) -> Error {
+        Error::Other(format!(
+            "preprocess: {}:{line}: {message}",
+            self.source.files[file]
+        ))
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Token<'a> {
+    Ident(&'a str),
+    Space(&'a str), // whitespace and comments
+    Other(&'a str), // literals, and punctuation one character at a time
+}
+
+impl<'a> Token<'a> {
+    fn text(&self) -> &'a str {
+        match self {
+            Self::Ident(s) | Self::Space(s) | Self::Other(s) => s,
+        }
+    }
+}
+
+// Split a line into tokens. `in_comment` carries an open /* */ comment from
+// one line to the next.
+fn tokenize<'a>(text: &'a str, in_comment: &mut bool) -> Vec<Token<'a>> {
+    let mut tokens = Vec::new();
+    let mut rest = text;
+    while let Some(c) = rest.chars().next() {
+        let is_ident = |c: char| c.is_ascii_alphanumeric() || c == '_';
+        let (token, len): (fn(&'a str) -> Token<'a>, usize) =
+            if *in_comment || rest.starts_with("/*") {
+                let start = if *in_comment { 0 } else { 2 };
+                match rest[start..].find("*/") {
+                    Some(end) => {
+                        *in_comment = false;
+                        (Token::Space, start + end + 2)
+                    }
+                    None => {
+                        *in_comment = true;
+                        (Token::Space, rest.len())
+                    }
+                }
+            } else if rest.starts_with("//") {
+                (Token::Space, rest.find('\n').unwrap_or(rest.len()))
+            } else if c.is_whitespace() {
+                (
+                    Token::Space,
+                    rest.find(|c: char| !c.is_whitespace())
+                        .unwrap_or(rest.len()),
+                )
+            } else if c.is_ascii_alphabetic() || c == '_' {
+                (
+                    Token::Ident,
+                    rest.find(|c| !is_ident(c)).unwrap_or(rest.len()),
+                )
+            } else if c.is_ascii_digit() {
+                (
+                    Token::Other,
+                    rest.find(|c| !is_ident(c) && c != '.')
+                        .unwrap_or(rest.len()),
+                )
+            } else if c == '"' {
+                (
+                    Token::Other,
+                    rest[1..].find('"').map_or(rest.len(), |end| end + 2),
+                )
+            } else {
+                (Token::Other, c.len_utf8())
+            };
+        tokens.push(token(&rest[..len]));
+        rest = &rest[len..];
+    }
+    tokens
+}
+
+// Arguments of a function-like macro invocation starting at tokens[i]. Gives
+// Some(None) when no '(' follows, and None when the ')' is missing.
+#[allow(clippy::type_complexity)]
+fn arguments<'t, 'a>(
+    tokens: &'t [Token<'a>],
+    i: usize,
+) -> Option<Option<(Vec<&'t [Token<'a>]>, usize)>> {
+    let open = i + tokens[i..]
+        .iter()
+        .take_while(|t| matches!(t, Token::Space(_)))
+        .count();
+    if tokens.get(open) != Some(&Token::Other("(")) {
+        return Some(None);
+    }
+
+    let mut args = Vec::new();
+    let mut depth = 0;
+    let mut start = open + 1;
+    for (j, t) in tokens.iter().enumerate().skip(open) {
+        match t {
+            Token::Other("(") => depth += 1,
+            Token::Other(")") => {
+                depth -= 1;
+                if depth == 0 {
+                    args.push(&tokens[start..j]);
+                    return Some(Some((args, j + 1)));
+                }
+            }
+            Token::Other(",") if depth == 1 => {
+                args.push(&tokens[start..j]);
+                start = j + 1;
+            }
+            _ => {}
+        }
+    }
+    None
+}
+
+// Replace the parameters in a macro body with the arguments.
+fn substitute(body: &str, params: &[String], args: &[String]) -> String {
+    tokenize(body, &mut false)
+        .iter()
+        .map(|t| match t {
+            Token::Ident(name) => match params.iter().position(|p| p == name) {
+                Some(k) => args[k].as_str(),
+                None => name,
+            },
+            _ => t.text(),
+        })
+        .collect()
+}
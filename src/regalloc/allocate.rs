@@ -1,20 +1,66 @@
+use std::collections::HashSet;
+
 use crate::error::Error;
-use crate::three_ac::Instructions;
+use crate::listing::Code;
+use crate::three_ac::{Cfg, Variant};
 
 use super::code_block;
 use super::liveness_analysis;
-use super::reg_table::RegTable;
-
-pub fn from_instructions(instructions: Instructions, reg_count: u32) -> Result<String, Error> {
-    let mut instrs = instructions.instructions;
-    let mut output = String::new();
-    let mut reg_table = RegTable::new(reg_count)?;
-
-    while let Some((block, mut live_set)) = code_block::next_from_instructions(&mut instrs) {
-        let analyzed = liveness_analysis::for_codeblock(block, &mut live_set);
-        for (i, l) in analyzed {
-            // println!("{i} {l:?}");
-            output.push_str(&i.to_code(l.clone(), &mut reg_table));
+use super::promote::{self, Promotion};
+use super::reg_table::{Names, RegTable};
+
+// With promote, the variables used most in each function are kept in
+// registers across its blocks rather than in memory.
+pub fn from_cfg(cfg: Cfg, reg_count: u32, names: Names, promote: bool) -> Result<Code, Error> {
+    let mut output = Code::new();
+    let mut span = None;
+
+    // Variables whose address is taken may be written through a pointer.
+    let aliased = cfg
+        .instructions()
+        .filter(|i| i.variant == Variant::Reference)
+        .map(|i| i.opm.variant)
+        .collect::<HashSet<_>>();
+    let mut reg_table = RegTable::new(reg_count, aliased, names)?;
+
+    // The entry block of a function comes with how to keep its variables in
+    // registers.
+    let mut blocks = vec![(cfg.header, false, None)];
+    for mut f in cfg.functions {
+        let mut pinned = Some(if promote {
+            promote::plan(&mut f)
+        } else {
+            Promotion::default()
+        });
+        let count = f.blocks.len();
+        for (k, b) in f.blocks.into_iter().enumerate() {
+            blocks.push((b.instructions, k + 1 < count, pinned.take()));
+        }
+    }
+    blocks.push((cfg.trailer, false, None));
+
+    for (instructions, falls_through, pinned) in blocks {
+        // Arguments are loaded into their registers after the function head.
+        let mut entry = pinned.map(|p| reg_table.pin(p));
+        for (piece, mut live_set) in code_block::from_basic_block(instructions, falls_through) {
+            let analyzed = liveness_analysis::for_codeblock(piece, &mut live_set);
+            for (mut i, l) in analyzed {
+                // Spills added at the end of a block belong to the code before them.
+                if i.variant != Variant::SpillRegisters {
+                    span = i.span;
+                }
+                output.push((span, i.to_code(l.clone(), &mut reg_table)?));
+                if let Some(code) = entry.take().filter(|c| !c.is_empty()) {
+                    output.push((span, code));
+                }
+
+                // A result nothing reads must not keep its register. That of a
+                // call is read after the piece ends.
+                let calls = matches!(i.variant, Variant::Call(..) | Variant::CallIndirect(_));
+                if let Some(d) = i.def_mut().filter(|d| !calls && !l.contains(d)) {
+                    reg_table.forget(d);
+                }
+            }
         }
     }
 
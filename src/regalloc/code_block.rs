@@ -1,47 +1,48 @@
-use std::collections::{HashSet, VecDeque};
+use std::collections::HashSet;
 
 use crate::three_ac::{Instruction, Operand, Variant};
 
-pub fn next_from_instructions(
-    instructions: &mut VecDeque<Instruction>,
-) -> Option<(Vec<Instruction>, HashSet<Operand>)> {
+// Split a basic block into the pieces registers are allocated over. Values do
+// not stay in registers across calls and jumps, so these end a piece. Each
+// piece comes with the variables it uses. falls_through tells whether control
+// goes on into the next block.
+pub fn from_basic_block(
+    instructions: Vec<Instruction>,
+    falls_through: bool,
+) -> Vec<(Vec<Instruction>, HashSet<Operand>)> {
+    let mut pieces = Vec::new();
     let mut collection = Vec::new();
     let mut live_set = HashSet::new();
 
-    while let Some(i) = instructions.pop_front() {
-        if matches!(&i.variant, Variant::Label(_)) && !collection.is_empty() {
-            instructions.push_front(i);
-            collection.push(Instruction::spill_registers());
-            break;
-        }
-
+    for i in instructions {
         i.add_operands_to_set(&mut live_set);
         if matches!(
             &i.variant,
             Variant::HeaderText(_)
                 | Variant::HeaderStrings(_)
-                | Variant::Ret
-                | Variant::Equal(_)
-                | Variant::NotEqual(_)
-                | Variant::Less(_)
-                | Variant::LessEqual(_)
-                | Variant::Greater(_)
-                | Variant::GreaterEqual(_)
-                | Variant::Jump(_)
                 | Variant::Call(..)
-        ) {
+                | Variant::CallIndirect(..)
+        ) || i.is_terminator()
+        {
             if matches!(&i.variant, Variant::Ret | Variant::Jump(_)) {
                 collection.push(Instruction::spill_registers());
             }
             collection.push(i);
-            break;
+            pieces.push((
+                std::mem::take(&mut collection),
+                std::mem::take(&mut live_set),
+            ));
+            continue;
         }
         collection.push(i);
     }
-    if collection.is_empty() {
-        return None;
+    if !collection.is_empty() {
+        if falls_through {
+            collection.push(Instruction::spill_registers());
+        }
+        pieces.push((collection, live_set));
     }
-    Some((collection, live_set))
+    pieces
 }
 
 impl Instruction {
@@ -1,19 +1,25 @@
 use std::collections::HashSet;
 
+use crate::error::Error;
 use crate::three_ac::{operand, Instruction, Label, Operand, Set, Variant};
 
 use super::reg_table::RegTable;
 
 impl Instruction {
-    pub fn to_code(&self, live_set: HashSet<Operand>, reg_table: &mut RegTable) -> String {
+    pub fn to_code(
+        &self,
+        live_set: HashSet<Operand>,
+        reg_table: &mut RegTable,
+    ) -> Result<String, Error> {
         let mut code = String::new();
         match &self.variant {
-            Variant::HeaderText(l) => return format!(".section .text\nMV fp, sp\nJR {l}\nHALT\n"),
-            Variant::HeaderStrings(s) => return format!(".section .strings\n{s}"),
+            Variant::HeaderText(Some(l)) => return Ok(entry_code(l)),
+            Variant::HeaderText(None) => return Ok(String::from(".section .text\n")),
+            Variant::HeaderStrings(s) => return Ok(format!(".section .strings\n{s}")),
 
             Variant::AddrAssign => {
-                let opd = reg_table.ensure(&self.opdt, &live_set, &mut code);
-                let opm = reg_table.ensure(&self.opm, &live_set, &mut code);
+                let opd = reg_table.ensure(&self.opdt, &live_set, &mut code)?;
+                let opm = reg_table.ensure(&self.opm, &live_set, &mut code)?;
                 if !live_set.contains(&self.opdt) {
                     reg_table.free(&opd, &live_set, &mut code);
                 }
@@ -24,9 +30,10 @@ impl Instruction {
                     Set::T => code.push_str(&format!("SW {opm}, 0({opd})\n")),
                     Set::F => code.push_str(&format!("FSW {opm}, 0({opd})\n")),
                 }
+                reg_table.invalidate_aliased();
             }
             Variant::Assign => {
-                let opm = reg_table.ensure(&self.opm, &live_set, &mut code);
+                let opm = reg_table.ensure(&self.opm, &live_set, &mut code)?;
                 if !live_set.contains(&self.opm) {
                     reg_table.free(&opm, &live_set, &mut code);
                 }
@@ -38,7 +45,7 @@ impl Instruction {
                 reg_table.mark_dirty(&opd, &mut code);
             }
             Variant::Free => {
-                let opt = reg_table.ensure(&self.opdt, &live_set, &mut code);
+                let opt = reg_table.ensure(&self.opdt, &live_set, &mut code)?;
                 if !live_set.contains(&self.opdt) {
                     reg_table.free(&opt, &live_set, &mut code);
                 }
@@ -53,7 +60,7 @@ impl Instruction {
                 reg_table.mark_dirty(&opd, &mut code);
             }
             Variant::Malloc => {
-                let opm = reg_table.ensure(&self.opm, &live_set, &mut code);
+                let opm = reg_table.ensure(&self.opm, &live_set, &mut code)?;
                 if !live_set.contains(&self.opm) {
                     reg_table.free(&opm, &live_set, &mut code);
                 }
@@ -62,7 +69,7 @@ impl Instruction {
                 reg_table.mark_dirty(&opd, &mut code);
             }
             Variant::Put => {
-                let opt = reg_table.ensure(&self.opdt, &live_set, &mut code);
+                let opt = reg_table.ensure(&self.opdt, &live_set, &mut code)?;
                 if !live_set.contains(&self.opdt) {
                     reg_table.free(&opt, &live_set, &mut code);
                 }
@@ -72,15 +79,19 @@ impl Instruction {
                 }
             }
             Variant::PutS => {
-                let opt = reg_table.ensure(&self.opdt, &live_set, &mut code);
+                let opt = reg_table.ensure(&self.opdt, &live_set, &mut code)?;
                 if !live_set.contains(&self.opdt) {
                     reg_table.free(&opt, &live_set, &mut code);
                 }
                 code.push_str(&format!("PUTS {opt}\n"));
             }
-            Variant::Ret => return String::from("MV sp, fp\nLW fp, 0(fp)\nADDI sp, sp, 4\nRET\n"),
+            Variant::Ret => {
+                return Ok(String::from(
+                    "MV sp, fp\nLW fp, 0(fp)\nADDI sp, sp, 4\nRET\n",
+                ))
+            }
             Variant::Save => {
-                let opt = reg_table.ensure(&self.opdt, &live_set, &mut code);
+                let opt = reg_table.ensure(&self.opdt, &live_set, &mut code)?;
                 if !live_set.contains(&self.opdt) {
                     reg_table.free(&opt, &live_set, &mut code);
                 }
@@ -98,8 +109,14 @@ impl Instruction {
                 reg_table.mark_dirty(&opd, &mut code);
             }
 
+            Variant::LoadLabel(l) => {
+                let opd = reg_table.allocate(&self.opdt, &live_set, &mut code);
+                code.push_str(&format!("LA {opd}, {l}\n"));
+                reg_table.mark_dirty(&opd, &mut code);
+            }
+
             Variant::Address => {
-                let opm = reg_table.ensure(&self.opm, &live_set, &mut code);
+                let opm = reg_table.ensure(&self.opm, &live_set, &mut code)?;
                 if !live_set.contains(&self.opm) {
                     reg_table.free(&opm, &live_set, &mut code);
                 }
@@ -109,7 +126,7 @@ impl Instruction {
             }
 
             Variant::Dereference => {
-                let opm = reg_table.ensure(&self.opm, &live_set, &mut code);
+                let opm = reg_table.ensure(&self.opm, &live_set, &mut code)?;
                 if !live_set.contains(&self.opm) {
                     reg_table.free(&opm, &live_set, &mut code);
                 }
@@ -123,8 +140,9 @@ impl Instruction {
             Variant::Reference => {
                 let opd = reg_table.allocate(&self.opdt, &live_set, &mut code);
                 match self.opm.variant {
+                    operand::Variant::Global(a) => code.push_str(&reg_table.load_address(&opd, a)),
                     operand::Variant::Local(i) => code.push_str(&format!("ADDI {opd}, fp, {i}\n")),
-                    _ => unreachable!(),
+                    _ => unreachable!("to_code: cannot reference {}", self.opm),
                 };
                 reg_table.mark_dirty(&opd, &mut code);
             }
@@ -141,8 +159,8 @@ impl Instruction {
                     op = format!("F{op}.S");
                 }
 
-                let opm = reg_table.ensure(&self.opm, &live_set, &mut code);
-                let opn = reg_table.ensure(&self.opn, &live_set, &mut code);
+                let opm = reg_table.ensure(&self.opm, &live_set, &mut code)?;
+                let opn = reg_table.ensure(&self.opn, &live_set, &mut code)?;
                 if !live_set.contains(&self.opm) {
                     reg_table.free(&opm, &live_set, &mut code);
                 }
@@ -160,7 +178,7 @@ impl Instruction {
                     Set::F => "FNEG.S",
                 });
 
-                let opm = reg_table.ensure(&self.opm, &live_set, &mut code);
+                let opm = reg_table.ensure(&self.opm, &live_set, &mut code)?;
                 if !live_set.contains(&self.opm) {
                     reg_table.free(&opm, &live_set, &mut code);
                 }
@@ -175,7 +193,7 @@ impl Instruction {
                     Set::F => "IMOVF.S",
                 });
 
-                let opm = reg_table.ensure(&self.opm, &live_set, &mut code);
+                let opm = reg_table.ensure(&self.opm, &live_set, &mut code)?;
                 if !live_set.contains(&self.opm) {
                     reg_table.free(&opm, &live_set, &mut code);
                 }
@@ -191,8 +209,8 @@ impl Instruction {
             | Variant::Greater(l)
             | Variant::GreaterEqual(l) => match self.set {
                 Set::T => {
-                    let opm = reg_table.ensure(&self.opm, &live_set, &mut code);
-                    let opn = reg_table.ensure(&self.opn, &live_set, &mut code);
+                    let opm = reg_table.ensure(&self.opm, &live_set, &mut code)?;
+                    let opn = reg_table.ensure(&self.opn, &live_set, &mut code)?;
                     if !live_set.contains(&self.opm) {
                         reg_table.free(&opm, &live_set, &mut code);
                     }
@@ -214,8 +232,8 @@ impl Instruction {
                     ));
                 }
                 Set::F => {
-                    let opm = reg_table.ensure(&self.opm, &live_set, &mut code);
-                    let opn = reg_table.ensure(&self.opn, &live_set, &mut code);
+                    let opm = reg_table.ensure(&self.opm, &live_set, &mut code)?;
+                    let opn = reg_table.ensure(&self.opn, &live_set, &mut code)?;
                     if !live_set.contains(&self.opm) {
                         reg_table.free(&opm, &live_set, &mut code);
                     }
@@ -242,36 +260,75 @@ impl Instruction {
             },
 
             Variant::Label(l) => {
-                return match l {
+                return Ok(match l {
                     Label::FunctionHead(_) => {
                         format!("{l}:\nADDI sp, sp, -4\nSW fp, 0(sp)\nMV fp, sp\n")
                     }
                     _ => format!("{l}:\n"),
+                })
+            }
+            Variant::Jump(l) => return Ok(format!("J {l}\n")),
+            Variant::JumpTable(table, targets) => {
+                let opm = reg_table.ensure(&self.opm, &live_set, &mut code)?;
+                if !live_set.contains(&self.opm) {
+                    reg_table.free(&opm, &live_set, &mut code);
+                }
+                code.push_str(&reg_table.spill_registers());
+                // The index is scaled in place.
+                let opm = reg_table.writable(opm, &mut code);
+                // Every entry is a single 4 byte jump.
+                code.push_str(&format!(
+                    "ADD {opm}, {opm}, {opm}\nADD {opm}, {opm}, {opm}\nLA x3, {table}\nADD x3, x3, {opm}\nJALR x0, 0(x3)\n{table}:\n"
+                ));
+                for l in targets {
+                    code.push_str(&format!("J {l}\n"));
                 }
             }
-            Variant::Jump(l) => return format!("J {l}\n"),
-            Variant::Call(l, o) => {
+            Variant::Call(_, o) | Variant::CallIndirect(o) => {
                 let total_offset = (o.len() + 2) * 4;
                 code.push_str(&format!("ADDI sp, sp, -{total_offset}\nSW ra, 0(sp)\n"));
 
-                let mut offset = 8;
-                for arg in o {
-                    let op = reg_table.ensure(arg, &live_set, &mut code);
+                // An argument passed more than once is stored to each of its
+                // places at once, as its register may be gone after the first.
+                let offset = 8;
+                for (k, arg) in o.iter().enumerate() {
+                    if o[..k].contains(arg) {
+                        continue;
+                    }
+                    let op = reg_table.ensure(arg, &live_set, &mut code)?;
                     if !live_set.contains(arg) {
                         reg_table.free(&op, &live_set, &mut code);
                     }
-                    code.push_str(&format!(
-                        "{} {op}, {offset}(sp)\n",
-                        match arg.otype {
-                            operand::Type::T => "SW",
-                            operand::Type::F => "FSW",
-                        }
-                    ));
-                    offset += 4;
+                    let store = match arg.otype {
+                        operand::Type::T => "SW",
+                        operand::Type::F => "FSW",
+                    };
+                    for (j, _) in o.iter().enumerate().skip(k).filter(|(_, a)| *a == arg) {
+                        code.push_str(&format!("{store} {op}, {}(sp)\n", offset + 4 * j));
+                    }
                 }
 
+                // The target is read last so that no argument evicts it, from
+                // where it was stored if it is one of them too.
+                let jump = match &self.variant {
+                    Variant::Call(l, _) => format!("JR {l}\n"),
+                    _ => match o.iter().position(|a| *a == self.opm) {
+                        Some(j) => format!("LW x3, {}(sp)\nJALR ra, 0(x3)\n", offset + 4 * j),
+                        None => {
+                            let opm = reg_table.ensure(&self.opm, &live_set, &mut code)?;
+                            if !live_set.contains(&self.opm) {
+                                reg_table.free(&opm, &live_set, &mut code);
+                            }
+                            format!("JALR ra, 0({opm})\n")
+                        }
+                    },
+                };
                 code.push_str(&reg_table.spill_registers());
-                code.push_str(&format!("JR {l}\nLW ra, 0(sp)\n"));
+                let (save, restore) = reg_table.around_call();
+                code.push_str(&save);
+                code.push_str(&jump);
+                code.push_str("LW ra, 0(sp)\n");
+                code.push_str(&restore);
                 if self.opdt.variant != operand::Variant::Null {
                     let opd = reg_table.allocate(&self.opdt, &live_set, &mut code);
                     code.push_str(&format!(
@@ -286,10 +343,50 @@ impl Instruction {
                 code.push_str(&format!("ADDI sp, sp, {total_offset}\n"));
             }
 
-            Variant::Alloc(v) => return format!("ADDI sp, sp, -{v}\n"),
-            Variant::SpillRegisters => return reg_table.spill_registers(),
+            // The arguments are stored below the stack first, since the
+            // function's own that they replace may be among them.
+            Variant::TailCall(l, o) => {
+                if !o.is_empty() {
+                    code.push_str(&format!("ADDI sp, sp, -{}\n", o.len() * 4));
+                }
+                for (k, arg) in o.iter().enumerate() {
+                    if o[..k].contains(arg) {
+                        continue;
+                    }
+                    let op = reg_table.ensure(arg, &live_set, &mut code)?;
+                    if !live_set.contains(arg) {
+                        reg_table.free(&op, &live_set, &mut code);
+                    }
+                    let store = match arg.otype {
+                        operand::Type::T => "SW",
+                        operand::Type::F => "FSW",
+                    };
+                    for (j, _) in o.iter().enumerate().skip(k).filter(|(_, a)| *a == arg) {
+                        code.push_str(&format!("{store} {op}, {}(sp)\n", 4 * j));
+                    }
+                }
+                code.push_str(&reg_table.spill_registers());
+                for k in 0..o.len() {
+                    code.push_str(&format!(
+                        "LW x3, {}(sp)\nSW x3, {}(fp)\n",
+                        k * 4,
+                        12 + k * 4
+                    ));
+                }
+                code.push_str(&format!("MV sp, fp\nLW fp, 0(fp)\nADDI sp, sp, 4\nJ {l}\n"));
+            }
+
+            Variant::Phi(_) => unreachable!("to_code: phi outside of SSA form"),
+            Variant::Alloc(v) => return Ok(format!("ADDI sp, sp, -{v}\n")),
+            Variant::SpillRegisters => return Ok(reg_table.spill_registers()),
         }
 
-        code
+        Ok(code)
     }
 }
+
+// Start of the text section of a whole program: set up the frame pointer,
+// call main and halt when it returns.
+pub fn entry_code(main: &impl std::fmt::Display) -> String {
+    format!(".section .text\nMV fp, sp\nJR {main}\nHALT\n")
+}
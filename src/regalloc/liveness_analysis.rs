@@ -20,7 +20,7 @@ pub fn for_codeblock(
                 i.opdt.remove_from_set(set);
                 i.opm.insert_to_set(set);
             }
-            Variant::Get | Variant::Load(_) => {
+            Variant::Get | Variant::Load(_) | Variant::LoadLabel(_) => {
                 i.opdt.remove_from_set(set);
             }
             Variant::Address | Variant::Dereference | Variant::Reference => {
@@ -47,6 +47,10 @@ pub fn for_codeblock(
                 i.opn.insert_to_set(set);
             }
 
+            Variant::JumpTable(..) => {
+                i.opm.insert_to_set(set);
+            }
+
             Variant::Call(_, ops) => {
                 i.opdt.remove_from_set(set);
                 for o in ops {
@@ -54,6 +58,22 @@ pub fn for_codeblock(
                 }
             }
 
+            Variant::TailCall(_, ops) => {
+                for o in ops {
+                    o.insert_to_set(set);
+                }
+            }
+
+            Variant::Phi(_) => unreachable!("for_codeblock: phi outside of SSA form"),
+
+            Variant::CallIndirect(ops) => {
+                i.opdt.remove_from_set(set);
+                i.opm.insert_to_set(set);
+                for o in ops {
+                    o.insert_to_set(set);
+                }
+            }
+
             Variant::HeaderText(_)
             | Variant::HeaderStrings(_)
             | Variant::Label(_)
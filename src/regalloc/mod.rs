@@ -1,7 +1,10 @@
-pub use self::allocate::from_instructions;
+pub use self::allocate::from_cfg;
+pub use self::instruction::entry_code;
+pub use self::reg_table::Names;
 
 mod allocate;
 mod code_block;
 mod instruction;
 mod liveness_analysis;
+mod promote;
 mod reg_table;
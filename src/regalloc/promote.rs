@@ -0,0 +1,203 @@
+use std::cmp::Reverse;
+use std::collections::{HashMap, HashSet, VecDeque};
+
+use crate::three_ac::{operand, Function, Instruction, Operand, Variant};
+
+// What a call does with the pinned variables, whose registers the function
+// called may change: store those read after it that changed since they were
+// last stored, and load those read after it again.
+#[derive(Debug)]
+pub struct Around {
+    pub save: HashSet<Operand>,
+    pub restore: HashSet<Operand>,
+}
+
+// How the variables of a function are kept in registers from its entry to
+// its return: which ones, most worth it first, those stored whenever they
+// are written so that memory always has them, and what each of its calls
+// does with them.
+#[derive(Debug, Default)]
+pub struct Promotion {
+    pub variables: Vec<Operand>,
+    pub stored: HashSet<Operand>,
+    pub calls: VecDeque<Around>,
+}
+
+// Leaving SSA form puts the values that live across blocks, what was left of
+// the locals among them, in frame slots, since the allocator only keeps
+// values in registers between calls and jumps. This picks the slots, and the
+// arguments, to keep in registers across the whole function instead.
+//
+// In memory, a variable is loaded once in each stretch between calls that
+// reads it and stored once in each that writes it. In a register, it is
+// loaded after the calls it lives across, and stored before them when it
+// changed since, or else wherever it is written, whichever is less. What
+// happens inside a loop counts for as many times as the loop is likely to
+// run, taken as ten for each loop around it. Variables whose address is taken
+// stay in memory, where pointers reach them.
+pub fn plan(f: &mut Function) -> Promotion {
+    let aliased = f.address_taken();
+    let dominators = f.dominators();
+    let mut depth = vec![0; f.blocks.len()];
+    for l in f.loops(&dominators) {
+        for b in l.blocks {
+            depth[b] += 1;
+        }
+    }
+    let weights: Vec<i64> = depth.iter().map(|&d| 10i64.pow(d.min(9))).collect();
+    let local = |o: &Operand| {
+        matches!(o.variant, operand::Variant::Local(_)) && !aliased.contains(&o.variant)
+    };
+
+    // How many loads and stores each variable saves in a register, and how
+    // many stores writing it costs.
+    let mut variables = Vec::new();
+    let mut saved: HashMap<Operand, i64> = HashMap::new();
+    let mut writes: HashMap<Operand, i64> = HashMap::new();
+    let mut call_weights = Vec::new();
+    for (b, block) in f.blocks.iter_mut().enumerate() {
+        let mut used = HashSet::new();
+        let mut written = HashSet::new();
+        for i in &mut block.instructions {
+            for u in i.uses_mut().into_iter().filter(|u| local(u)) {
+                if !written.contains(u) {
+                    used.insert(*u);
+                }
+            }
+            if is_call(i) {
+                for v in used.drain().chain(written.drain()) {
+                    *saved.entry(v).or_default() += weights[b];
+                }
+                call_weights.push(weights[b]);
+            }
+            if let Some(d) = i.def_mut().filter(|d| local(d)) {
+                written.insert(*d);
+                *writes.entry(*d).or_default() += weights[b];
+            }
+            for o in i.operands_mut().into_iter().filter(|o| local(o)) {
+                if !saved.contains_key(o) {
+                    saved.insert(*o, 0);
+                    variables.push(*o);
+                }
+            }
+        }
+        for v in used.into_iter().chain(written) {
+            *saved.entry(v).or_default() += weights[b];
+        }
+    }
+
+    let mut calls = around_calls(f, &variables);
+    let mut saves: HashMap<Operand, i64> = HashMap::new();
+    for (around, weight) in calls.iter().zip(call_weights) {
+        for v in &around.save {
+            *saves.entry(*v).or_default() += weight;
+        }
+        for v in &around.restore {
+            *saved.entry(*v).or_default() -= weight;
+        }
+    }
+    let mut stored = HashSet::new();
+    for (v, cost) in saves {
+        let write = writes.get(&v).copied().unwrap_or(0);
+        if write < cost {
+            stored.insert(v);
+        }
+        *saved.entry(v).or_default() -= cost.min(write);
+    }
+    for around in &mut calls {
+        around.save.retain(|v| !stored.contains(v));
+    }
+    // Arguments are loaded when the function is entered.
+    for v in &variables {
+        if let operand::Variant::Local(1..) = v.variant {
+            *saved.entry(*v).or_default() -= 1;
+        }
+    }
+
+    variables.retain(|v| saved[v] > 0);
+    variables.sort_by_key(|v| Reverse(saved[v]));
+    Promotion {
+        variables,
+        stored,
+        calls,
+    }
+}
+
+// What each call of the function does with the variables, in the order of
+// the calls. A variable is only in memory when the function is entered.
+fn around_calls(f: &mut Function, variables: &[Operand]) -> VecDeque<Around> {
+    let tracked: HashSet<Operand> = variables.iter().copied().collect();
+    let count = f.blocks.len();
+    let live_out = f.live_out(|o| tracked.contains(o));
+
+    // The variables whose registers may differ from memory when each block
+    // is entered. A call leaves none that matter.
+    let mut stale_in: Vec<HashSet<Operand>> = vec![HashSet::new(); count];
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for b in 0..count {
+            let mut stale = stale_in[b].clone();
+            for i in &mut f.blocks[b].instructions {
+                if is_call(i) {
+                    stale.clear();
+                }
+                if let Some(d) = i.def_mut().filter(|d| tracked.contains(d)) {
+                    stale.insert(*d);
+                }
+            }
+            for s in f.blocks[b].successors.clone() {
+                if !stale.is_subset(&stale_in[s]) {
+                    stale_in[s].extend(stale.iter().copied());
+                    changed = true;
+                }
+            }
+        }
+    }
+
+    let mut calls = VecDeque::new();
+    for (b, block) in f.blocks.iter_mut().enumerate() {
+        // The variables read after each call, last call first.
+        let mut live = live_out[b].clone();
+        let mut after = Vec::new();
+        for i in block.instructions.iter_mut().rev() {
+            let d = i.def_mut().copied();
+            if is_call(i) {
+                // The result is written after the variables are loaded, so
+                // its old value is not needed.
+                let mut restore = live.clone();
+                if let Some(d) = &d {
+                    restore.remove(d);
+                }
+                after.push(restore);
+            }
+            if let Some(d) = d {
+                live.remove(&d);
+            }
+            live.extend(
+                i.uses_mut()
+                    .into_iter()
+                    .filter(|u| tracked.contains(u))
+                    .map(|u| *u),
+            );
+        }
+
+        let mut stale = stale_in[b].clone();
+        for i in &mut block.instructions {
+            if is_call(i) {
+                let restore = after.pop().unwrap_or_default();
+                let save = stale.intersection(&restore).copied().collect();
+                calls.push_back(Around { save, restore });
+                stale.clear();
+            }
+            if let Some(d) = i.def_mut().filter(|d| tracked.contains(d)) {
+                stale.insert(*d);
+            }
+        }
+    }
+    calls
+}
+
+fn is_call(i: &Instruction) -> bool {
+    matches!(i.variant, Variant::Call(..) | Variant::CallIndirect(_))
+}
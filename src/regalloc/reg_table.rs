@@ -1,9 +1,11 @@
-use std::collections::{BTreeMap, HashSet};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::fmt;
 
 use crate::error::Error;
 use crate::three_ac::{operand, Operand};
 
+use super::promote::Promotion;
+
 #[derive(Eq, PartialEq)]
 pub enum Register {
     X(Regular),
@@ -37,10 +39,13 @@ impl fmt::Display for Float {
     }
 }
 
+// A pinned entry keeps its variable until the table is pinned again. Its
+// register is the variable's home, so it is never dirty and never freed.
 #[derive(Clone, Debug)]
 struct Entry {
     operand: Operand,
     dirty: bool,
+    pinned: bool,
 }
 
 impl Entry {
@@ -48,16 +53,21 @@ impl Entry {
         Self {
             operand: Operand::new_null(),
             dirty: false,
+            pinned: false,
         }
     }
 
     fn reset(&mut self) {
-        self.operand = Operand::new_null();
-        self.dirty = false;
+        if !self.pinned {
+            self.operand = Operand::new_null();
+            self.dirty = false;
+        }
     }
 
     fn dirty(&mut self) {
-        self.dirty = true;
+        if !self.pinned {
+            self.dirty = true;
+        }
     }
 
     fn update(&mut self, operand: Operand) {
@@ -69,20 +79,20 @@ impl Entry {
         self.operand.variant == operand::Variant::Null
     }
 
-    fn spill_regular_entry(&self, r: Regular) -> String {
+    fn spill_regular_entry(&self, r: Regular, names: &Names) -> String {
         match &self.operand.variant {
             operand::Variant::Global(a) => {
-                format!("LA x3, 0x{a:08x}\nSW {r}, 0(x3)\n")
+                format!("{}SW {r}, 0(x3)\n", load_address("x3", *a, names))
             }
             operand::Variant::Local(i) => format!("SW {r}, {i}(fp)\n"),
             _ => String::new(),
         }
     }
 
-    fn spill_float_entry(&self, r: Float) -> String {
+    fn spill_float_entry(&self, r: Float, names: &Names) -> String {
         match &self.operand.variant {
             operand::Variant::Global(a) => {
-                format!("LA x3, 0x{a:08x}\nFSW {r}, 0(x3)\n")
+                format!("{}FSW {r}, 0(x3)\n", load_address("x3", *a, names))
             }
             operand::Variant::Local(i) => format!("FSW {r}, {i}(fp)\n"),
             _ => String::new(),
@@ -90,14 +100,42 @@ impl Entry {
     }
 }
 
+// Names of globals and strings by address, for comments in the output.
+pub type Names = HashMap<i32, String>;
+
+fn load_local(r: &Register, i: i32) -> String {
+    match r {
+        Register::X(_) => format!("LW {r}, {i}(fp)\n"),
+        Register::F(_) => format!("FLW {r}, {i}(fp)\n"),
+    }
+}
+
+// Registers the allocator keeps for temps and the variables not pinned, as
+// many as the smallest table has.
+const UNPINNED: usize = 4;
+
+fn load_address(r: impl fmt::Display, a: i32, names: &Names) -> String {
+    match names.get(&a) {
+        Some(name) => format!("LA {r}, 0x{a:08x} # {name}\n"),
+        None => format!("LA {r}, 0x{a:08x}\n"),
+    }
+}
+
 #[derive(Debug)]
 pub struct RegTable {
     regular: BTreeMap<Regular, Entry>,
     float: BTreeMap<Float, Entry>,
+    aliased: HashSet<operand::Variant>,
+    names: Names,
+    promotion: Promotion, // what is left of it for the function
 }
 
 impl RegTable {
-    pub fn new(reg_count: u32) -> Result<Self, Error> {
+    pub fn new(
+        reg_count: u32,
+        aliased: HashSet<operand::Variant>,
+        names: Names,
+    ) -> Result<Self, Error> {
         if reg_count < 8 {
             return Err(Error::RegAlloc(format!(
                 "new: reg_count {reg_count} less than 8"
@@ -110,7 +148,103 @@ impl RegTable {
         let float = (1..reg_count)
             .map(|u| (Float(u), Entry::new_null()))
             .collect::<BTreeMap<Float, Entry>>();
-        Ok(Self { regular, float })
+        Ok(Self {
+            regular,
+            float,
+            aliased,
+            names,
+            promotion: Promotion::default(),
+        })
+    }
+
+    pub fn load_address(&self, r: impl fmt::Display, a: i32) -> String {
+        load_address(r, a, &self.names)
+    }
+
+    // Keep the first of the variables to promote, all locals, in registers of
+    // their own until pin is called again, as far as registers are left for
+    // everything else.
+    // Those that are arguments are loaded here, so this comes at the start of
+    // a function.
+    pub fn pin(&mut self, promotion: Promotion) -> String {
+        for entry in self.regular.values_mut().chain(self.float.values_mut()) {
+            entry.pinned = false;
+            entry.reset();
+        }
+
+        let mut code = String::new();
+        let spare = self.regular.len().saturating_sub(UNPINNED);
+        let mut regular = self.regular.iter_mut().rev().take(spare);
+        let spare = self.float.len().saturating_sub(UNPINNED);
+        let mut float = self.float.iter_mut().rev().take(spare);
+        for v in &promotion.variables {
+            let (r, entry) = match v.otype {
+                operand::Type::T => match regular.next() {
+                    Some((r, entry)) => (Register::X(*r), entry),
+                    None => continue,
+                },
+                operand::Type::F => match float.next() {
+                    Some((r, entry)) => (Register::F(*r), entry),
+                    None => continue,
+                },
+            };
+            entry.update(*v);
+            entry.pinned = true;
+            if let operand::Variant::Local(i @ 1..) = v.variant {
+                code.push_str(&load_local(&r, i));
+            }
+        }
+        self.promotion = promotion;
+        code
+    }
+
+    // Calls may change any register, so the pinned variables the next call
+    // needs are stored before it and loaded again after it. Gives the code
+    // for before and after.
+    pub fn around_call(&mut self) -> (String, String) {
+        let Some(around) = self.promotion.calls.pop_front() else {
+            return (String::new(), String::new());
+        };
+        let (mut save, mut restore) = (String::new(), String::new());
+        for (r, entry) in self.regular.iter().filter(|(_, e)| e.pinned) {
+            if around.save.contains(&entry.operand) {
+                save.push_str(&entry.spill_regular_entry(*r, &self.names));
+            }
+            if let operand::Variant::Local(i) = entry.operand.variant {
+                if around.restore.contains(&entry.operand) {
+                    restore.push_str(&load_local(&Register::X(*r), i));
+                }
+            }
+        }
+        for (r, entry) in self.float.iter().filter(|(_, e)| e.pinned) {
+            if around.save.contains(&entry.operand) {
+                save.push_str(&entry.spill_float_entry(*r, &self.names));
+            }
+            if let operand::Variant::Local(i) = entry.operand.variant {
+                if around.restore.contains(&entry.operand) {
+                    restore.push_str(&load_local(&Register::F(*r), i));
+                }
+            }
+        }
+        (save, restore)
+    }
+
+    // A register holding the same value as r that may be changed, for use
+    // once the registers are spilled: r, or a copy when r is pinned.
+    pub fn writable(&self, r: Register, code: &mut String) -> Register {
+        let Register::X(x) = r else {
+            return r;
+        };
+        if !self.regular.get(&x).is_some_and(|e| e.pinned) {
+            return r;
+        }
+        match self.regular.iter().find(|(_, e)| !e.pinned) {
+            Some((free, _)) => {
+                code.push_str(&format!("MV {free}, {x}\n"));
+                Register::X(*free)
+            }
+            None => r,
+        }
     }
 
     pub fn spill_registers(&mut self) -> String {
@@ -118,13 +252,13 @@ impl RegTable {
 
         for (r, entry) in self.regular.iter_mut() {
             if entry.dirty {
-                out.push_str(&entry.spill_regular_entry(*r));
+                out.push_str(&entry.spill_regular_entry(*r, &self.names));
             }
             entry.reset();
         }
         for (r, entry) in self.float.iter_mut() {
             if entry.dirty {
-                out.push_str(&entry.spill_float_entry(*r));
+                out.push_str(&entry.spill_float_entry(*r, &self.names));
             }
             entry.reset();
         }
@@ -132,47 +266,95 @@ impl RegTable {
         out
     }
 
-    pub fn ensure(&mut self, op: &Operand, set: &HashSet<Operand>, code: &mut String) -> Register {
+    // Drop cached copies of address-taken variables after a store through a
+    // pointer so that the next use reloads them from memory. Variables are
+    // always written back as soon as they are dirtied, so nothing is lost.
+    pub fn invalidate_aliased(&mut self) {
+        for entry in self.regular.values_mut() {
+            if self.aliased.contains(&entry.operand.variant) {
+                entry.reset();
+            }
+        }
+        for entry in self.float.values_mut() {
+            if self.aliased.contains(&entry.operand.variant) {
+                entry.reset();
+            }
+        }
+    }
+
+    // The register holding op, loaded from memory if it is not in one. Only
+    // variables are kept in memory, so a temp that is not is an error.
+    pub fn ensure(
+        &mut self,
+        op: &Operand,
+        set: &HashSet<Operand>,
+        code: &mut String,
+    ) -> Result<Register, Error> {
         if let Operand {
             variant: operand::Variant::Str(a),
             ..
         } = op
         {
-            code.push_str(&format!("LA x3, 0x{a:08x}\n"));
-            return Register::X(Regular(3));
+            code.push_str(&load_address("x3", *a, &self.names));
+            return Ok(Register::X(Regular(3)));
         }
         match op.otype {
             operand::Type::T => {
                 for (reg, Entry { operand, .. }) in &self.regular {
                     if operand == op {
-                        return Register::X(*reg);
+                        return Ok(Register::X(*reg));
                     }
                 }
                 let r = self.allocate(op, set, code);
                 match &op.variant {
-                    operand::Variant::Global(a) => {
-                        code.push_str(&format!("LA x3, 0x{a:08x}\nLW {r}, 0(x3)\n"))
-                    }
+                    operand::Variant::Global(a) => code.push_str(&format!(
+                        "{}LW {r}, 0(x3)\n",
+                        load_address("x3", *a, &self.names)
+                    )),
                     operand::Variant::Local(i) => code.push_str(&format!("LW {r}, {i}(fp)\n")),
-                    _ => return Register::X(Regular(99)),
+                    _ => {
+                        return Err(Error::RegAlloc(format!(
+                            "ensure: {op} is not in a register"
+                        )))
+                    }
                 }
-                r
+                Ok(r)
             }
             operand::Type::F => {
                 for (reg, Entry { operand, .. }) in &self.float {
                     if operand == op {
-                        return Register::F(*reg);
+                        return Ok(Register::F(*reg));
                     }
                 }
                 let r = self.allocate(op, set, code);
                 match &op.variant {
-                    operand::Variant::Global(i) => {
-                        code.push_str(&format!("LA x3, 0x{i:08x}\nFLW {r}, 0(x3)\n"))
-                    }
+                    operand::Variant::Global(i) => code.push_str(&format!(
+                        "{}FLW {r}, 0(x3)\n",
+                        load_address("x3", *i, &self.names)
+                    )),
                     operand::Variant::Local(i) => code.push_str(&format!("FLW {r}, {i}(fp)\n")),
-                    _ => return Register::F(Float(99)),
+                    _ => {
+                        return Err(Error::RegAlloc(format!(
+                            "ensure: {op} is not in a register"
+                        )))
+                    }
                 }
-                r
+                Ok(r)
+            }
+        }
+    }
+
+    // Let go of the register holding a value nothing reads any more.
+    // Variables are written back when they are dirtied, so this loses nothing.
+    pub fn forget(&mut self, op: &Operand) {
+        for entry in self.regular.values_mut() {
+            if entry.operand == *op {
+                entry.reset();
+            }
+        }
+        for entry in self.float.values_mut() {
+            if entry.operand == *op {
+                entry.reset();
             }
         }
     }
@@ -206,32 +388,42 @@ impl RegTable {
     fn choose_register(&self, operand: Operand) -> Option<Register> {
         match operand.otype {
             operand::Type::T => {
-                if let Some((reg, _)) = self.regular.iter().reduce(|(r, e), (reg, entry)| {
-                    if e.operand == operand {
-                        return (r, e);
-                    } else if entry.operand == operand {
-                        return (reg, entry);
-                    }
-                    if !e.is_null() && entry.is_null() || (e.dirty && !entry.dirty) {
-                        return (reg, entry);
-                    }
-                    (r, e)
-                }) {
+                if let Some((reg, _)) = self
+                    .regular
+                    .iter()
+                    .filter(|(_, e)| !e.pinned || e.operand == operand)
+                    .reduce(|(r, e), (reg, entry)| {
+                        if e.operand == operand {
+                            return (r, e);
+                        } else if entry.operand == operand {
+                            return (reg, entry);
+                        }
+                        if !e.is_null() && entry.is_null() || (e.dirty && !entry.dirty) {
+                            return (reg, entry);
+                        }
+                        (r, e)
+                    })
+                {
                     return Some(Register::X(*reg));
                 }
             }
             operand::Type::F => {
-                if let Some((reg, _)) = self.float.iter().reduce(|(r, e), (reg, entry)| {
-                    if e.operand == operand {
-                        return (r, e);
-                    } else if entry.operand == operand {
-                        return (reg, entry);
-                    }
-                    if !e.is_null() && entry.is_null() || (e.dirty && !entry.dirty) {
-                        return (reg, entry);
-                    }
-                    (r, e)
-                }) {
+                if let Some((reg, _)) = self
+                    .float
+                    .iter()
+                    .filter(|(_, e)| !e.pinned || e.operand == operand)
+                    .reduce(|(r, e), (reg, entry)| {
+                        if e.operand == operand {
+                            return (r, e);
+                        } else if entry.operand == operand {
+                            return (reg, entry);
+                        }
+                        if !e.is_null() && entry.is_null() || (e.dirty && !entry.dirty) {
+                            return (reg, entry);
+                        }
+                        (r, e)
+                    })
+                {
                     return Some(Register::F(*reg));
                 }
             }
@@ -244,7 +436,7 @@ impl RegTable {
             Register::X(x) => {
                 if let Some(entry) = self.regular.get_mut(x) {
                     if entry.dirty && set.contains(&entry.operand) {
-                        code.push_str(&entry.spill_regular_entry(*x));
+                        code.push_str(&entry.spill_regular_entry(*x, &self.names));
                     }
                     entry.reset();
                 }
@@ -253,9 +445,10 @@ impl RegTable {
                 if let Some(entry) = self.float.get_mut(f) {
                     if entry.dirty && set.contains(&entry.operand) {
                         match entry.operand.variant {
-                            operand::Variant::Global(i) => {
-                                code.push_str(&format!("LA x3, 0x{i:08x}\nFSW {r}, 0(x3)\n"))
-                            }
+                            operand::Variant::Global(i) => code.push_str(&format!(
+                                "{}FSW {r}, 0(x3)\n",
+                                load_address("x3", i, &self.names)
+                            )),
                             operand::Variant::Local(i) => {
                                 code.push_str(&format!("FSW {r}, {i}(fp)\n"))
                             }
@@ -268,16 +461,24 @@ impl RegTable {
         }
     }
 
+    // Pinned variables to be stored whenever written are stored here.
     pub fn mark_dirty(&mut self, r: &Register, code: &mut String) {
+        let stored = &self.promotion.stored;
         match r {
             Register::X(x) => {
                 if let Some(entry) = self.regular.get_mut(x) {
                     entry.dirty();
+                    if entry.pinned && stored.contains(&entry.operand) {
+                        code.push_str(&entry.spill_regular_entry(*x, &self.names));
+                    }
                 }
             }
             Register::F(f) => {
                 if let Some(entry) = self.float.get_mut(f) {
                     entry.dirty();
+                    if entry.pinned && stored.contains(&entry.operand) {
+                        code.push_str(&entry.spill_float_entry(*f, &self.names));
+                    }
                 }
             }
         }
@@ -293,7 +494,7 @@ impl RegTable {
                 operand::Variant::Global(_) | operand::Variant::Local(_)
             ) {
                 if entry.dirty {
-                    out.push_str(&entry.spill_regular_entry(*reg));
+                    out.push_str(&entry.spill_regular_entry(*reg, &self.names));
                 }
                 entry.reset();
             }
@@ -305,7 +506,7 @@ impl RegTable {
                 operand::Variant::Global(_) | operand::Variant::Local(_)
             ) {
                 if entry.dirty {
-                    out.push_str(&entry.spill_float_entry(*flt));
+                    out.push_str(&entry.spill_float_entry(*flt, &self.names));
                 }
                 entry.reset();
             }
@@ -1,4 +1,6 @@
-use pest::iterators::Pairs;
+use std::fmt;
+
+use pest::iterators::{Pair, Pairs};
 use pest::pratt_parser::PrattParser;
 
 use crate::error::Error;
@@ -19,6 +21,8 @@ pub enum CType {
     Float,
     Str,
     Ptr(Box<Self>),
+    // A function by return and parameter types, only used behind a pointer.
+    Func(Box<Self>, Vec<Self>),
     Void,
 }
 
@@ -42,6 +46,63 @@ impl CType {
             .parse(pairs)
     }
 
+    // Type of a base_type or fptr_type pair.
+    pub fn from_type(pair: Pair<Rule>) -> Self {
+        match pair.as_rule() {
+            Rule::fptr_type => Self::from_fptr(pair).0,
+            _ => Self::from_base_type(pair.into_inner()),
+        }
+    }
+
+    // Type and name of the declaration in pairs, `base_type ident` or an
+    // fptr_decl like `int (*f)(int)`.
+    pub fn from_declaration(pairs: &mut Pairs<Rule>) -> Result<(Self, String), Error> {
+        let first = pairs.next().ok_or(Error::PairsNext)?;
+        match first.as_rule() {
+            Rule::fptr_decl => match Self::from_fptr(first) {
+                (ctype, Some(ident)) => Ok((ctype, ident)),
+                (_, None) => Err(Error::PairsNext),
+            },
+            _ => Ok((
+                Self::from_base_type(first.into_inner()),
+                pairs.next().ok_or(Error::PairsNext)?.as_str().to_owned(),
+            )),
+        }
+    }
+
+    // `ret (*...ident)(params)`, where the ident is only there in a declaration.
+    fn from_fptr(pair: Pair<Rule>) -> (Self, Option<String>) {
+        let mut ctype = Self::Void;
+        let mut ident = None;
+        let mut ptrs = 0;
+        for p in pair.into_inner() {
+            match p.as_rule() {
+                Rule::base_type => ctype = Self::from_base_type(p.into_inner()),
+                Rule::ptr => ptrs += 1,
+                Rule::ident => ident = Some(p.as_str().to_owned()),
+                Rule::type_list => {
+                    ctype = Self::Func(
+                        Box::new(ctype),
+                        p.into_inner().map(Self::from_type).collect(),
+                    )
+                }
+                _ => unreachable!("from_fptr: expected part of a function pointer, found other"),
+            }
+        }
+        for _ in 0..ptrs {
+            ctype = Self::Ptr(Box::new(ctype));
+        }
+        (ctype, ident)
+    }
+
+    // Size in bytes of a value of this type.
+    pub fn size(&self) -> Result<i32, Error> {
+        match self {
+            Self::Int | Self::Float | Self::Str | Self::Ptr(_) => Ok(4),
+            Self::Func(..) | Self::Void => Err(Error::Type),
+        }
+    }
+
     pub fn dereference(self) -> Result<Self, Error> {
         match self {
             Self::Ptr(t) => Ok(*t),
@@ -49,3 +110,19 @@ impl CType {
         }
     }
 }
+
+impl fmt::Display for CType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Int => write!(f, "int"),
+            Self::Float => write!(f, "float"),
+            Self::Str => write!(f, "string"),
+            Self::Ptr(t) => write!(f, "{t}*"),
+            Self::Func(ret, params) => {
+                let params: Vec<String> = params.iter().map(|p| p.to_string()).collect();
+                write!(f, "{ret}({})", params.join(", "))
+            }
+            Self::Void => write!(f, "void"),
+        }
+    }
+}
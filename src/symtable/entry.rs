@@ -20,6 +20,7 @@ pub enum Entry {
 #[derive(Clone, Debug)]
 pub enum SymbolType {
     Global,
+    Extern, // a global defined in another module
     Local,
     Argument,
     Str(String),
@@ -33,6 +34,21 @@ impl Entry {
         }
     }
 
+    // Type of the function's address, for calls through a pointer.
+    pub fn function_type(&self) -> Result<CType, Error> {
+        match self {
+            Entry::Symbol { .. } => Err(Error::SymTable(String::from(
+                "function_type: symbol is not a function",
+            ))),
+            Entry::Function {
+                ctype, arguments, ..
+            } => Ok(CType::Ptr(Box::new(CType::Func(
+                Box::new(ctype.clone()),
+                arguments.clone(),
+            )))),
+        }
+    }
+
     pub fn address(&self) -> i32 {
         match self {
             Entry::Symbol { address, .. } => *address,
@@ -56,6 +72,15 @@ impl Entry {
         }
     }
 
+    // A function only gets a scope once its body has been parsed, and an
+    // extern variable is defined in another module.
+    pub fn is_defined(&self) -> bool {
+        match self {
+            Entry::Symbol { symtype, .. } => !matches!(symtype, SymbolType::Extern),
+            Entry::Function { scope, .. } => *scope != 0,
+        }
+    }
+
     pub fn set_scope(self, scope: usize) -> Self {
         match self {
             Entry::Symbol {
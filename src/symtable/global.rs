@@ -12,16 +12,18 @@ pub struct Scope {
     children: Vec<usize>,
     global_base: i32,
     string_base: i32,
+    extern_base: i32,
     function_base: i32,
 }
 
 impl Scope {
-    pub fn new(global_base: i32, string_base: i32) -> Scope {
+    pub fn new(global_base: i32, string_base: i32, extern_base: i32) -> Scope {
         Scope {
             table: HashMap::new(),
             children: Vec::new(),
             global_base,
             string_base,
+            extern_base,
             function_base: 0,
         }
     }
@@ -30,7 +32,31 @@ impl Scope {
         self.children.push(child);
     }
 
-    pub fn add_function(&mut self, ctype: CType, name: String, arguments: Vec<CType>) {
+    pub fn add_function(
+        &mut self,
+        ctype: CType,
+        name: String,
+        arguments: Vec<CType>,
+    ) -> Result<(), Error> {
+        match self.table.get(&name) {
+            Some(Entry::Function {
+                ctype: c,
+                arguments: a,
+                ..
+            }) => {
+                // Redeclaring a function is only allowed with the same signature.
+                if *c != ctype || *a != arguments {
+                    return Err(Error::Type);
+                }
+                return Ok(());
+            }
+            Some(Entry::Symbol { .. }) => {
+                return Err(Error::SymTable(format!(
+                    "add_function: {name} is already declared as a variable"
+                )))
+            }
+            None => {}
+        }
         self.table.insert(
             name,
             Entry::Function {
@@ -41,6 +67,7 @@ impl Scope {
             },
         );
         self.function_base += 1;
+        Ok(())
     }
 
     pub fn set_function_scope(&mut self, name: &String, scope: usize) -> Result<(), Error> {
@@ -61,6 +88,39 @@ impl Scope {
         name: String,
         symtype: SymbolType,
     ) -> Result<(), Error> {
+        match (self.table.get(&name), &symtype) {
+            // An extern declaration may come before or after the definition,
+            // and both must agree on the type.
+            (
+                Some(Entry::Symbol {
+                    ctype: c,
+                    symtype: SymbolType::Extern,
+                    ..
+                }),
+                SymbolType::Global | SymbolType::Extern,
+            )
+            | (
+                Some(Entry::Symbol {
+                    ctype: c,
+                    symtype: SymbolType::Global,
+                    ..
+                }),
+                SymbolType::Extern,
+            ) => {
+                if *c != ctype {
+                    return Err(Error::Type);
+                }
+                if !matches!(symtype, SymbolType::Global) {
+                    return Ok(());
+                }
+            }
+            (Some(_), _) => {
+                return Err(Error::SymTable(format!(
+                    "add_symbol: {name} is already declared"
+                )))
+            }
+            (None, _) => {}
+        }
         self.table.insert(
             name,
             Entry::Symbol {
@@ -76,6 +136,12 @@ impl Scope {
                         self.string_base += 4;
                         addr
                     }
+                    // Resolved by the linker.
+                    SymbolType::Extern => {
+                        let addr = self.extern_base;
+                        self.extern_base += 4;
+                        addr
+                    }
                     s => {
                         return Err(Error::SymTable(format!(
                             "add_symbol: expected global or string, got {s:?}"
@@ -120,4 +186,14 @@ impl Scope {
     pub fn get_addr_val(&self) -> (i32, i32) {
         (self.global_base, self.string_base)
     }
+
+    pub fn entries(&self) -> Vec<(String, Entry)> {
+        let mut entries: Vec<(String, Entry)> = self
+            .table
+            .iter()
+            .map(|(name, entry)| (name.clone(), entry.clone()))
+            .collect();
+        entries.sort_by(|(a, _), (b, _)| a.cmp(b));
+        entries
+    }
 }
@@ -40,6 +40,11 @@ impl Scope {
         name: String,
         symtype: SymbolType,
     ) -> Result<(), Error> {
+        if self.table.contains_key(&name) {
+            return Err(Error::SymTable(format!(
+                "add_symbol: {name} is already declared in this function"
+            )));
+        }
         self.table.insert(
             name,
             Entry::Symbol {
@@ -1,7 +1,7 @@
 pub use ctype::CType;
 pub use entry::Entry;
 pub use entry::SymbolType;
-pub use object::SymTable;
+pub use object::{SymTable, ARG_OFFSET};
 
 mod ctype;
 mod entry;
@@ -13,12 +13,18 @@ pub struct SymTable {
     curr: usize,
 }
 
-const ARG_OFFSET: i32 = 8;
+// Arguments come after this offset from the frame pointer, each one word
+// further.
+pub const ARG_OFFSET: i32 = 8;
 const VAR_OFFSET: i32 = 0;
 
 impl SymTable {
-    pub fn new(global_base: i32, string_base: i32) -> SymTable {
-        let scopes = vec![Scope::Global(global::Scope::new(global_base, string_base))];
+    pub fn new(global_base: i32, string_base: i32, extern_base: i32) -> SymTable {
+        let scopes = vec![Scope::Global(global::Scope::new(
+            global_base,
+            string_base,
+            extern_base,
+        ))];
 
         SymTable { scopes, curr: 0 }
     }
@@ -109,7 +115,7 @@ impl SymTable {
             }
         }
         Err(Error::SymTable(format!(
-            "get_scope: symtable does not contain {name}"
+            "get_scope: {name} is used before its declaration"
         )))
     }
 
@@ -134,7 +140,7 @@ impl SymTable {
             return self.get_symbol_in_scope(name, curr);
         }
         Err(Error::SymTable(format!(
-            "get_scope: symtable does not contain {name}"
+            "get_symbol: {name} is used before its declaration"
         )))
     }
 
@@ -156,4 +162,12 @@ impl SymTable {
     pub fn get_addr_offset_val(&self) -> (i32, i32) {
         self.scopes[self.curr].get_addr_offset_val()
     }
+
+    // Functions and variables declared at the top level, sorted by name.
+    pub fn global_entries(&self) -> Vec<(String, Entry)> {
+        match &self.scopes[0] {
+            Scope::Global(scope) => scope.entries(),
+            Scope::Local(_) => Vec::new(),
+        }
+    }
 }
@@ -32,10 +32,7 @@ impl Scope {
         arguments: Vec<CType>,
     ) -> Result<(), Error> {
         match self {
-            Scope::Global(scope) => {
-                scope.add_function(ctype, name, arguments);
-                Ok(())
-            }
+            Scope::Global(scope) => scope.add_function(ctype, name, arguments),
             Scope::Local(_) => Err(Error::SymTable(String::from(
                 "add_function: local scope cannot add functions",
             ))),
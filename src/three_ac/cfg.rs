@@ -0,0 +1,566 @@
+use std::collections::{BTreeSet, HashMap, HashSet};
+
+use crate::error::Error;
+
+use super::instruction::{Instruction, Variant};
+use super::instructions::Instructions;
+use super::label::{Block, Label};
+use super::operand::{self, Operand};
+
+// A program's 3AC as one control-flow graph per function. The headers before
+// and after the functions are kept as they are.
+#[derive(Debug)]
+pub struct Cfg {
+    pub header: Vec<Instruction>,
+    pub functions: Vec<Function>,
+    pub trailer: Vec<Instruction>,
+}
+
+// The basic blocks of a function in program order, so a block without a jump
+// at its end falls through to the next one. Block 0 is the entry and the exit
+// is the block that returns.
+#[derive(Debug)]
+pub struct Function {
+    pub name: String,
+    pub blocks: Vec<BasicBlock>,
+    pub exit: usize,
+}
+
+// Instructions that are only entered at the top and only left at the bottom.
+// Calls return to where they were made, so they do not end a block.
+#[derive(Debug)]
+pub struct BasicBlock {
+    pub instructions: Vec<Instruction>,
+    pub successors: Vec<usize>,
+    pub predecessors: Vec<usize>,
+}
+
+// The dominator tree of a function, as the immediate dominator of each block.
+// The entry and unreachable blocks have none.
+#[derive(Debug)]
+pub struct Dominators {
+    idom: Vec<Option<usize>>,
+}
+
+// A natural loop: its header and the blocks that reach a back edge to it
+// without passing through it, the header included.
+#[derive(Debug)]
+pub struct Loop {
+    pub header: usize,
+    pub blocks: BTreeSet<usize>,
+}
+
+// A step of a depth-first walk of the dominator tree: entering a block, or
+// leaving one with what it added for the blocks it dominates.
+pub enum Visit<T> {
+    Enter(usize),
+    Leave(T),
+}
+
+impl Cfg {
+    pub fn from_instructions(instructions: Instructions) -> Result<Self, Error> {
+        let mut cfg = Self {
+            header: Vec::new(),
+            functions: Vec::new(),
+            trailer: Vec::new(),
+        };
+        let mut current: Option<(String, Vec<Instruction>)> = None;
+
+        for i in instructions.instructions {
+            match &i.variant {
+                Variant::Label(Label::FunctionHead(name)) => {
+                    if let Some((name, body)) = current.take() {
+                        cfg.functions.push(Function::new(name, body)?);
+                    }
+                    current = Some((name.clone(), vec![i]));
+                }
+                Variant::HeaderText(_) => cfg.header.push(i),
+                Variant::HeaderStrings(_) => cfg.trailer.push(i),
+                _ => match &mut current {
+                    Some((_, body)) => body.push(i),
+                    None => cfg.header.push(i),
+                },
+            }
+        }
+        if let Some((name, body)) = current {
+            cfg.functions.push(Function::new(name, body)?);
+        }
+
+        Ok(cfg)
+    }
+
+    pub fn instructions(&self) -> impl Iterator<Item = &Instruction> {
+        self.header
+            .iter()
+            .chain(
+                self.functions
+                    .iter()
+                    .flat_map(|f| f.blocks.iter().flat_map(|b| &b.instructions)),
+            )
+            .chain(&self.trailer)
+    }
+}
+
+impl Function {
+    pub fn new(name: String, instructions: Vec<Instruction>) -> Result<Self, Error> {
+        let mut blocks: Vec<BasicBlock> = Vec::new();
+        let mut current = Vec::new();
+
+        for i in instructions {
+            // Labels in a row all name the same block.
+            let labelled = matches!(i.variant, Variant::Label(_));
+            if labelled && current.iter().any(|i: &Instruction| !i.is_label()) {
+                blocks.push(BasicBlock::new(std::mem::take(&mut current)));
+            }
+            let ends = i.is_terminator();
+            current.push(i);
+            if ends {
+                blocks.push(BasicBlock::new(std::mem::take(&mut current)));
+            }
+        }
+        if !current.is_empty() {
+            blocks.push(BasicBlock::new(current));
+        }
+
+        let mut function = Self {
+            name,
+            blocks,
+            exit: 0,
+        };
+        function.connect()?;
+        Ok(function)
+    }
+
+    // Recompute the edges and the exit from the jumps at the ends of the
+    // blocks. Passes that change jumps or move blocks call this afterwards.
+    pub fn connect(&mut self) -> Result<(), Error> {
+        self.exit = self
+            .blocks
+            .iter()
+            .position(|b| matches!(b.last_variant(), Some(Variant::Ret)))
+            .ok_or_else(|| Error::ThreeAC(format!("connect: {} does not return", self.name)))?;
+
+        let mut labels = HashMap::new();
+        for (k, b) in self.blocks.iter().enumerate() {
+            for i in b.instructions.iter().take_while(|i| i.is_label()) {
+                if let Variant::Label(l) = &i.variant {
+                    labels.insert(l.clone(), k);
+                }
+            }
+        }
+        let find = |l: &Label| {
+            labels
+                .get(l)
+                .copied()
+                .ok_or_else(|| Error::ThreeAC(format!("connect: {l} is not in {}", self.name)))
+        };
+
+        let count = self.blocks.len();
+        let mut successors = Vec::new();
+        for (k, b) in self.blocks.iter().enumerate() {
+            let next = (k + 1 < count).then_some(k + 1);
+            let mut succ = match b.last_variant() {
+                Some(Variant::Ret | Variant::TailCall(..)) => Vec::new(),
+                Some(Variant::Jump(l)) => vec![find(l)?],
+                Some(Variant::JumpTable(_, targets)) => {
+                    targets.iter().map(find).collect::<Result<_, _>>()?
+                }
+                Some(
+                    Variant::Equal(l)
+                    | Variant::NotEqual(l)
+                    | Variant::Less(l)
+                    | Variant::LessEqual(l)
+                    | Variant::Greater(l)
+                    | Variant::GreaterEqual(l),
+                ) => next.into_iter().chain([find(l)?]).collect(),
+                _ => next.into_iter().collect(),
+            };
+            succ.sort_unstable();
+            succ.dedup();
+            successors.push(succ);
+        }
+
+        for b in &mut self.blocks {
+            b.predecessors.clear();
+        }
+        for (k, succ) in successors.into_iter().enumerate() {
+            for &s in &succ {
+                self.blocks[s].predecessors.push(k);
+            }
+            self.blocks[k].successors = succ;
+        }
+        Ok(())
+    }
+
+    // Keep only the blocks keep holds for. Phis name predecessors by their
+    // index, so they are renumbered.
+    pub fn retain_blocks(&mut self, keep: &[bool]) -> Result<(), Error> {
+        let mut index = Vec::new();
+        let mut n = 0;
+        for &k in keep {
+            index.push(n);
+            n += usize::from(k);
+        }
+
+        let blocks = std::mem::take(&mut self.blocks);
+        for (mut b, _) in blocks.into_iter().zip(keep).filter(|(_, k)| **k) {
+            for i in &mut b.instructions {
+                if let Variant::Phi(args) = &mut i.variant {
+                    args.retain(|(p, _)| keep[*p]);
+                    for (p, _) in args {
+                        *p = index[*p];
+                    }
+                }
+            }
+            self.blocks.push(b);
+        }
+        self.connect()
+    }
+
+    // The variables whose address is taken, which pointers may write.
+    pub fn address_taken(&self) -> HashSet<operand::Variant> {
+        self.blocks
+            .iter()
+            .flat_map(|b| &b.instructions)
+            .filter(|i| i.variant == Variant::Reference)
+            .map(|i| i.opm.variant)
+            .collect()
+    }
+
+    // Put a block before the one at index at. Phis name predecessors by
+    // their index, so they are renumbered.
+    pub fn insert_block(&mut self, at: usize, block: BasicBlock) -> Result<(), Error> {
+        for b in &mut self.blocks {
+            for i in &mut b.instructions {
+                if let Variant::Phi(args) = &mut i.variant {
+                    for (p, _) in args.iter_mut().filter(|(p, _)| *p >= at) {
+                        *p += 1;
+                    }
+                }
+            }
+        }
+        self.blocks.insert(at, block);
+        self.connect()
+    }
+
+    // The innermost loop whose header is not done, by its header's label.
+    // Passes that change loops one at a time find them again this way, as
+    // block indices change.
+    pub fn next_loop(&self, done: &HashSet<Label>) -> Option<(Loop, Label)> {
+        let mut loops = self.loops(&self.dominators());
+        loops.sort_by_key(|l| l.blocks.len());
+        loops.into_iter().find_map(|l| {
+            let label = self.blocks[l.header].label()?.clone();
+            (!done.contains(&label)).then_some((l, label))
+        })
+    }
+
+    // The one block control enters the loop from, and whether it can serve
+    // as the preheader itself, as it goes nowhere else. Loops with more ways
+    // in, or that a block of their own falls out of into the header, have
+    // none.
+    pub fn loop_entry(&self, l: &Loop) -> Option<(usize, bool)> {
+        let h = l.header;
+        let entries: Vec<usize> = self.blocks[h]
+            .predecessors
+            .iter()
+            .copied()
+            .filter(|p| !l.blocks.contains(p))
+            .collect();
+        let [p] = entries[..] else {
+            return None;
+        };
+        if self.blocks[p].successors.len() == 1 {
+            return Some((p, true));
+        }
+        let falls_in = l.blocks.contains(&(h - 1))
+            && !matches!(
+                self.blocks[h - 1].last_variant(),
+                Some(
+                    Variant::Jump(_)
+                        | Variant::JumpTable(..)
+                        | Variant::Ret
+                        | Variant::TailCall(..)
+                )
+            );
+        (!falls_in).then_some((p, false))
+    }
+
+    // Put a block right before the header that the entry goes through
+    // instead, and return its index.
+    pub fn insert_preheader(&mut self, l: &Loop, entry: usize) -> Result<usize, Error> {
+        let h = l.header;
+        let labels: HashSet<&Label> = self.blocks.iter().filter_map(|b| b.label()).collect();
+        let label = (1..)
+            .map(|n| Block::new(&self.name, "loop", n).label("preheader"))
+            .find(|l| !labels.contains(l))
+            .unwrap_or_else(|| unreachable!("insert_preheader: no label left"));
+        let targets: Vec<Label> = self.blocks[h].labels().into_iter().cloned().collect();
+        if let Some(i) = self.blocks[entry].instructions.last_mut() {
+            for target in &targets {
+                i.retarget(target, &label);
+            }
+        }
+        self.insert_block(h, BasicBlock::new(vec![Instruction::label(label)]))?;
+
+        let entry = if entry < h { entry } else { entry + 1 };
+        for i in &mut self.blocks[h + 1].instructions {
+            if let Variant::Phi(args) = &mut i.variant {
+                for (p, _) in args.iter_mut().filter(|(p, _)| *p == entry) {
+                    *p = h;
+                }
+            }
+        }
+        Ok(h)
+    }
+
+    // Blocks reachable from the entry, each before its successors except
+    // along back edges.
+    pub fn reverse_postorder(&self) -> Vec<usize> {
+        let mut visited = vec![false; self.blocks.len()];
+        let mut order = Vec::new();
+        // Blocks with the index of the next successor to visit.
+        let mut stack = vec![(0, 0)];
+        visited[0] = true;
+        while let Some((b, k)) = stack.pop() {
+            match self.blocks[b].successors.get(k) {
+                Some(&s) => {
+                    stack.push((b, k + 1));
+                    if !visited[s] {
+                        visited[s] = true;
+                        stack.push((s, 0));
+                    }
+                }
+                None => order.push(b),
+            }
+        }
+        order.reverse();
+        order
+    }
+
+    // Cooper, Harvey and Kennedy's iterative algorithm.
+    pub fn dominators(&self) -> Dominators {
+        let order = self.reverse_postorder();
+        let mut position = vec![usize::MAX; self.blocks.len()];
+        for (n, &b) in order.iter().enumerate() {
+            position[b] = n;
+        }
+
+        let mut idom: Vec<Option<usize>> = vec![None; self.blocks.len()];
+        idom[0] = Some(0);
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for &b in order.iter().skip(1) {
+                let mut new = None;
+                for &p in &self.blocks[b].predecessors {
+                    if idom[p].is_none() {
+                        continue;
+                    }
+                    new = Some(match new {
+                        None => p,
+                        Some(mut other) => {
+                            let mut p = p;
+                            while p != other {
+                                while position[p] > position[other] {
+                                    p = idom[p].unwrap_or(0);
+                                }
+                                while position[other] > position[p] {
+                                    other = idom[other].unwrap_or(0);
+                                }
+                            }
+                            p
+                        }
+                    });
+                }
+                if new.is_some() && idom[b] != new {
+                    idom[b] = new;
+                    changed = true;
+                }
+            }
+        }
+        idom[0] = None;
+
+        Dominators { idom }
+    }
+
+    // The natural loops of the function. An edge back to a block that
+    // dominates its source closes one, and loops sharing a header are one.
+    pub fn loops(&self, dominators: &Dominators) -> Vec<Loop> {
+        let mut loops: Vec<Loop> = Vec::new();
+        for (b, block) in self.blocks.iter().enumerate() {
+            for &h in block
+                .successors
+                .iter()
+                .filter(|&&h| dominators.dominates(h, b))
+            {
+                let mut blocks = BTreeSet::from([h]);
+                let mut work = vec![b];
+                while let Some(n) = work.pop() {
+                    if blocks.insert(n) {
+                        work.extend(
+                            self.blocks[n]
+                                .predecessors
+                                .iter()
+                                .filter(|&&p| dominators.reachable(p)),
+                        );
+                    }
+                }
+                match loops.iter_mut().find(|l| l.header == h) {
+                    Some(l) => l.blocks.extend(blocks),
+                    None => loops.push(Loop { header: h, blocks }),
+                }
+            }
+        }
+        loops
+    }
+
+    // For each block, the blocks where its dominance ends: those it does not
+    // strictly dominate but that have a predecessor it dominates.
+    pub fn frontiers(&self, dominators: &Dominators) -> Vec<BTreeSet<usize>> {
+        let mut frontiers = vec![BTreeSet::new(); self.blocks.len()];
+        for (b, block) in self.blocks.iter().enumerate() {
+            if block.predecessors.len() < 2 {
+                continue;
+            }
+            for &p in &block.predecessors {
+                if !dominators.reachable(p) {
+                    continue;
+                }
+                let mut runner = Some(p);
+                while let Some(r) = runner.filter(|&r| Some(r) != dominators.idom(b)) {
+                    frontiers[r].insert(b);
+                    runner = dominators.idom(r);
+                }
+            }
+        }
+        frontiers
+    }
+
+    // For each block, the tracked operands read after it before being
+    // written. Phis are not looked at, so this is for outside SSA form.
+    pub fn live_out(&mut self, tracked: impl Fn(&Operand) -> bool) -> Vec<HashSet<Operand>> {
+        let count = self.blocks.len();
+        let mut reads = vec![HashSet::new(); count];
+        let mut writes = vec![HashSet::new(); count];
+        for (b, block) in self.blocks.iter_mut().enumerate() {
+            for i in &mut block.instructions {
+                for u in i.uses_mut() {
+                    if tracked(u) && !writes[b].contains(u) {
+                        reads[b].insert(*u);
+                    }
+                }
+                if let Some(d) = i.def_mut().filter(|d| tracked(d)) {
+                    writes[b].insert(*d);
+                }
+            }
+        }
+
+        let mut live_out: Vec<HashSet<Operand>> = vec![HashSet::new(); count];
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for b in (0..count).rev() {
+                let out: HashSet<Operand> = self.blocks[b]
+                    .successors
+                    .iter()
+                    .flat_map(|&s| reads[s].iter().chain(live_out[s].difference(&writes[s])))
+                    .copied()
+                    .collect();
+                if out != live_out[b] {
+                    live_out[b] = out;
+                    changed = true;
+                }
+            }
+        }
+        live_out
+    }
+}
+
+impl BasicBlock {
+    pub fn new(instructions: Vec<Instruction>) -> Self {
+        Self {
+            instructions,
+            successors: Vec::new(),
+            predecessors: Vec::new(),
+        }
+    }
+
+    pub fn last_variant(&self) -> Option<&Variant> {
+        self.instructions.last().map(|i| &i.variant)
+    }
+
+    pub fn label(&self) -> Option<&Label> {
+        match self.instructions.first().map(|i| &i.variant) {
+            Some(Variant::Label(l)) => Some(l),
+            _ => None,
+        }
+    }
+
+    // Every label the block starts with, as blocks that follow each other
+    // with nothing in between are merged.
+    pub fn labels(&self) -> Vec<&Label> {
+        self.instructions
+            .iter()
+            .map_while(|i| match &i.variant {
+                Variant::Label(l) => Some(l),
+                _ => None,
+            })
+            .collect()
+    }
+}
+
+impl Dominators {
+    pub fn idom(&self, b: usize) -> Option<usize> {
+        self.idom[b]
+    }
+
+    pub fn reachable(&self, b: usize) -> bool {
+        b == 0 || self.idom[b].is_some()
+    }
+
+    // Whether every way from the entry to b passes through a.
+    pub fn dominates(&self, a: usize, b: usize) -> bool {
+        if !self.reachable(b) {
+            return false;
+        }
+        let mut runner = Some(b);
+        while let Some(r) = runner {
+            if r == a {
+                return true;
+            }
+            runner = self.idom(r);
+        }
+        false
+    }
+
+    // Blocks whose immediate dominator is b.
+    pub fn children(&self, b: usize) -> Vec<usize> {
+        (0..self.idom.len())
+            .filter(|&c| self.idom[c] == Some(b))
+            .collect()
+    }
+}
+
+impl Instruction {
+    pub fn is_label(&self) -> bool {
+        matches!(self.variant, Variant::Label(_))
+    }
+
+    // Whether control can leave the block other than by falling through.
+    pub fn is_terminator(&self) -> bool {
+        matches!(
+            self.variant,
+            Variant::Ret
+                | Variant::TailCall(..)
+                | Variant::Equal(_)
+                | Variant::NotEqual(_)
+                | Variant::Less(_)
+                | Variant::LessEqual(_)
+                | Variant::Greater(_)
+                | Variant::GreaterEqual(_)
+                | Variant::Jump(_)
+                | Variant::JumpTable(..)
+        )
+    }
+}
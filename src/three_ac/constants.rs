@@ -0,0 +1,214 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::error::Error;
+
+use super::cfg::Function;
+use super::instruction::{Instruction, Set, Variant};
+use super::operand::{self, Operand};
+
+// A value known when compiling.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Constant {
+    Int(i32),
+    Float(f32),
+}
+
+impl Constant {
+    fn parse(set: &Set, lit: &str) -> Option<Self> {
+        match set {
+            Set::T => lit.parse().ok().map(Self::Int),
+            Set::F => lit.parse().ok().map(Self::Float),
+        }
+    }
+
+    // The literal to load the value with. Infinities and NaN have none.
+    fn literal(&self) -> Option<String> {
+        match self {
+            Self::Int(v) => Some(v.to_string()),
+            Self::Float(v) if v.is_finite() => Some(v.to_string()),
+            Self::Float(_) => None,
+        }
+    }
+
+    // Arithmetic as the target does it: 32 bit integers that wrap, and
+    // single precision floats. Division by zero is left to run time.
+    fn binary(variant: &Variant, m: Self, n: Self) -> Option<Self> {
+        match (m, n) {
+            (Self::Int(m), Self::Int(n)) => match variant {
+                Variant::Plus => Some(Self::Int(m.wrapping_add(n))),
+                Variant::Minus => Some(Self::Int(m.wrapping_sub(n))),
+                Variant::Times => Some(Self::Int(m.wrapping_mul(n))),
+                Variant::Divide if n != 0 => Some(Self::Int(m.wrapping_div(n))),
+                _ => None,
+            },
+            (Self::Float(m), Self::Float(n)) => match variant {
+                Variant::Plus => Some(Self::Float(m + n)),
+                Variant::Minus => Some(Self::Float(m - n)),
+                Variant::Times => Some(Self::Float(m * n)),
+                Variant::Divide if n != 0.0 => Some(Self::Float(m / n)),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    // Whether a conditional branch on m and n falls through.
+    fn holds(variant: &Variant, m: Self, n: Self) -> Option<bool> {
+        let ordering = match (m, n) {
+            (Self::Int(m), Self::Int(n)) => m.partial_cmp(&n),
+            (Self::Float(m), Self::Float(n)) => m.partial_cmp(&n),
+            _ => return None,
+        };
+        // Comparisons with NaN are false, except !=.
+        let Some(ordering) = ordering else {
+            return Some(matches!(variant, Variant::NotEqual(_)));
+        };
+        match variant {
+            Variant::Equal(_) => Some(ordering.is_eq()),
+            Variant::NotEqual(_) => Some(ordering.is_ne()),
+            Variant::Less(_) => Some(ordering.is_lt()),
+            Variant::LessEqual(_) => Some(ordering.is_le()),
+            Variant::Greater(_) => Some(ordering.is_gt()),
+            Variant::GreaterEqual(_) => Some(ordering.is_ge()),
+            _ => None,
+        }
+    }
+}
+
+impl Function {
+    // Fold arithmetic on constants and resolve branches on them. In SSA form a
+    // temp has the same value wherever it is used, so once one is known to be
+    // constant every instruction reading it can use that. Resolved branches
+    // remove edges, which can make phis constant too, so this runs until
+    // nothing changes.
+    pub fn fold_constants(&mut self) -> Result<(), Error> {
+        let mut constants = HashMap::new();
+        loop {
+            let order = self.reverse_postorder();
+            let reachable: HashSet<usize> = order.iter().copied().collect();
+            let mut changed = false;
+            let mut branches = false;
+
+            for b in order {
+                let block = &mut self.blocks[b];
+                for i in &mut block.instructions {
+                    let value = match &i.variant {
+                        Variant::Load(lit) => {
+                            if is_temp(&i.opdt) && !constants.contains_key(&i.opdt) {
+                                if let Some(c) = Constant::parse(&i.set, lit) {
+                                    constants.insert(i.opdt, c);
+                                    changed = true;
+                                }
+                            }
+                            continue;
+                        }
+                        Variant::Assign => constants.get(&i.opm).copied(),
+                        Variant::Negate => match constants.get(&i.opm) {
+                            Some(Constant::Int(v)) => Some(Constant::Int(v.wrapping_neg())),
+                            Some(Constant::Float(v)) => Some(Constant::Float(-v)),
+                            None => None,
+                        },
+                        Variant::Plus | Variant::Minus | Variant::Times | Variant::Divide => {
+                            match (constants.get(&i.opm), constants.get(&i.opn)) {
+                                (Some(&m), Some(&n)) => Constant::binary(&i.variant, m, n),
+                                _ => None,
+                            }
+                        }
+                        // Only the edges control can take count.
+                        Variant::Phi(args) => {
+                            let mut values = args
+                                .iter()
+                                .filter(|(p, o)| reachable.contains(p) && *o != i.opdt)
+                                .map(|(_, o)| constants.get(o));
+                            match values.next() {
+                                Some(Some(&c)) if values.all(|v| v == Some(&c)) => Some(c),
+                                _ => None,
+                            }
+                        }
+                        _ => None,
+                    };
+                    if let Some(lit) = value.and_then(|c| c.literal()) {
+                        let mut load = Instruction::load(Set::from_type(i.opdt.otype), i.opdt, lit);
+                        load.span = i.span;
+                        *i = load;
+                        changed = true;
+                    }
+                }
+
+                // A branch that always falls through goes, and one that never
+                // does becomes a jump.
+                let Some(last) = block.instructions.last_mut() else {
+                    continue;
+                };
+                match &last.variant {
+                    Variant::Equal(l)
+                    | Variant::NotEqual(l)
+                    | Variant::Less(l)
+                    | Variant::LessEqual(l)
+                    | Variant::Greater(l)
+                    | Variant::GreaterEqual(l) => {
+                        let holds = match (constants.get(&last.opm), constants.get(&last.opn)) {
+                            (Some(&m), Some(&n)) => Constant::holds(&last.variant, m, n),
+                            _ => None,
+                        };
+                        match holds {
+                            Some(true) => {
+                                block.instructions.pop();
+                            }
+                            Some(false) => {
+                                let mut jump = Instruction::jump(l.clone());
+                                jump.span = last.span;
+                                *last = jump;
+                            }
+                            None => continue,
+                        }
+                        branches = true;
+                    }
+                    Variant::JumpTable(_, targets) => {
+                        let target = match constants.get(&last.opm) {
+                            Some(&Constant::Int(k)) => usize::try_from(k)
+                                .ok()
+                                .and_then(|k| targets.get(k))
+                                .cloned(),
+                            _ => None,
+                        };
+                        if let Some(target) = target {
+                            let mut jump = Instruction::jump(target);
+                            jump.span = last.span;
+                            *last = jump;
+                            branches = true;
+                        }
+                    }
+                    _ => {}
+                }
+            }
+
+            if branches {
+                self.connect()?;
+                self.prune_phis();
+            }
+            if !changed && !branches {
+                return Ok(());
+            }
+        }
+    }
+
+    // Drop the operands of phis for edges that are gone.
+    pub fn prune_phis(&mut self) {
+        for block in &mut self.blocks {
+            let predecessors = &block.predecessors;
+            for i in &mut block.instructions {
+                if let Variant::Phi(args) = &mut i.variant {
+                    args.retain(|(p, _)| predecessors.contains(p));
+                }
+            }
+        }
+    }
+}
+
+fn is_temp(o: &Operand) -> bool {
+    matches!(
+        o.variant,
+        operand::Variant::Temp(_) | operand::Variant::TempFloat(_)
+    )
+}
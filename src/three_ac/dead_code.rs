@@ -0,0 +1,137 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::error::Error;
+
+use super::cfg::Function;
+use super::instruction::{Instruction, Variant};
+use super::label::Label;
+use super::operand::{self, Operand};
+
+impl Function {
+    // Remove blocks control never reaches, instructions whose results are
+    // never read and that do nothing else, jumps to the next block, and the
+    // labels no jump goes to.
+    pub fn remove_dead_code(&mut self) -> Result<(), Error> {
+        // The exit stays even when a loop never ends, as every function has one.
+        let mut keep = vec![false; self.blocks.len()];
+        for b in self.reverse_postorder() {
+            keep[b] = true;
+        }
+        keep[self.exit] = true;
+        self.retain_blocks(&keep)?;
+
+        self.remove_dead_instructions();
+
+        for k in 1..self.blocks.len() {
+            let next = self.blocks[k].label().cloned();
+            let block = &mut self.blocks[k - 1];
+            if next.is_some() && block.last_variant() == next.map(Variant::Jump).as_ref() {
+                block.instructions.pop();
+            }
+        }
+
+        let mut targets = HashSet::new();
+        for b in &self.blocks {
+            match b.last_variant() {
+                Some(
+                    Variant::Equal(l)
+                    | Variant::NotEqual(l)
+                    | Variant::Less(l)
+                    | Variant::LessEqual(l)
+                    | Variant::Greater(l)
+                    | Variant::GreaterEqual(l)
+                    | Variant::Jump(l),
+                ) => {
+                    targets.insert(l.clone());
+                }
+                Some(Variant::JumpTable(_, ls)) => targets.extend(ls.iter().cloned()),
+                _ => {}
+            }
+        }
+        // Calls and function addresses go to the head of a function.
+        for b in &mut self.blocks {
+            b.instructions.retain(|i| match &i.variant {
+                Variant::Label(l @ (Label::Block(..) | Label::FunctionTail(_))) => {
+                    targets.contains(l)
+                }
+                _ => true,
+            });
+        }
+
+        self.connect()
+    }
+
+    // Start from the instructions that matter beyond their result and keep
+    // those computing what they read, and so on. A phi that only feeds itself
+    // around a loop is not kept this way.
+    fn remove_dead_instructions(&mut self) {
+        let mut definitions = HashMap::new();
+        let mut live: Vec<Vec<bool>> = Vec::new();
+        let mut work = Vec::new();
+        for (b, block) in self.blocks.iter_mut().enumerate() {
+            let mut flags = Vec::new();
+            for (k, i) in block.instructions.iter_mut().enumerate() {
+                let pure = i.is_pure();
+                if pure {
+                    if let Some(d) = i.def_mut() {
+                        definitions.insert(*d, (b, k));
+                    }
+                } else {
+                    work.extend(i.reads());
+                }
+                flags.push(!pure);
+            }
+            live.push(flags);
+        }
+
+        while let Some(o) = work.pop() {
+            if let Some(&(b, k)) = definitions.get(&o) {
+                if !live[b][k] {
+                    live[b][k] = true;
+                    work.extend(self.blocks[b].instructions[k].reads());
+                }
+            }
+        }
+
+        for (block, flags) in self.blocks.iter_mut().zip(live) {
+            let mut flags = flags.into_iter();
+            block.instructions.retain(|_| flags.next().unwrap_or(true));
+        }
+    }
+}
+
+impl Instruction {
+    // Whether the instruction does nothing but compute a temp. Loads through
+    // pointers count, as they change nothing. Input, output, calls and the
+    // heap do not, even when their results go unused.
+    fn is_pure(&mut self) -> bool {
+        matches!(
+            self.variant,
+            Variant::Assign
+                | Variant::Load(_)
+                | Variant::LoadLabel(_)
+                | Variant::Address
+                | Variant::Dereference
+                | Variant::Reference
+                | Variant::Plus
+                | Variant::Minus
+                | Variant::Times
+                | Variant::Divide
+                | Variant::Negate
+                | Variant::Cast
+                | Variant::Phi(_)
+        ) && matches!(
+            self.opdt.variant,
+            operand::Variant::Temp(_) | operand::Variant::TempFloat(_)
+        )
+    }
+
+    // The operands whose values the instruction needs, phis included.
+    fn reads(&mut self) -> Vec<Operand> {
+        let mut reads: Vec<Operand> = self.uses_mut().into_iter().map(|o| *o).collect();
+        if let Variant::Phi(args) = &self.variant {
+            reads.extend(args.iter().map(|(_, o)| *o));
+        }
+        reads
+    }
+}
@@ -0,0 +1,244 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::error::Error;
+
+use super::cfg::{Function, Loop};
+use super::instruction::{Instruction, Set, Variant};
+use super::operand::{self, Operand};
+use super::ssa::Fresh;
+
+// A value that stays the same while a loop runs: a constant, which the loop
+// loads itself, or a value from before the loop.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+enum Factor {
+    Constant(String),
+    Before(Operand),
+}
+
+// A basic induction variable: a phi in the header that starts at init and
+// becomes next = phi + step or phi - step each time round.
+struct Induction {
+    phi: Operand,
+    init: Operand,
+    next: Operand,
+    latch: usize,
+    variant: Variant,
+    step: Factor,
+}
+
+// A multiplication of an induction variable, or its next value, by a factor.
+struct Product {
+    opdt: Operand,
+    induction: usize,
+    next: bool,
+    factor: Factor,
+}
+
+impl Function {
+    // Replace multiplications of an induction variable by a factor with a
+    // variable of their own that starts at the product and grows by step
+    // times the factor with it, so the loop adds where it multiplied. The
+    // target has no shift instruction, so multiplications and divisions by
+    // powers of two stay as they are.
+    pub fn reduce_strength(&mut self) -> Result<(), Error> {
+        let aliased = self.address_taken();
+        let mut done = HashSet::new();
+        while let Some((l, label)) = self.next_loop(&done) {
+            let (inductions, products) = self.find_products(&l, &aliased);
+            if products.is_empty() {
+                done.insert(label);
+                continue;
+            }
+            match self.loop_entry(&l) {
+                // The loop is found again with the preheader as its entry.
+                Some((p, false)) => {
+                    self.insert_preheader(&l, p)?;
+                }
+                Some((p, true)) => {
+                    self.reduce(&l, p, &inductions, products);
+                    done.insert(label);
+                }
+                None => {
+                    done.insert(label);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn find_products(
+        &mut self,
+        l: &Loop,
+        aliased: &HashSet<operand::Variant>,
+    ) -> (Vec<Induction>, Vec<Product>) {
+        let mut defined = HashMap::new();
+        for &b in &l.blocks {
+            for (k, i) in self.blocks[b].instructions.iter_mut().enumerate() {
+                if let Some(d) = i.def_mut() {
+                    defined.insert(*d, (b, k));
+                }
+            }
+        }
+        let factor = |o: &Operand| match o.variant {
+            operand::Variant::Temp(_) => match defined.get(o) {
+                Some(&(b, k)) => match &self.blocks[b].instructions[k].variant {
+                    Variant::Load(lit) => Some(Factor::Constant(lit.clone())),
+                    _ => None,
+                },
+                None => Some(Factor::Before(*o)),
+            },
+            operand::Variant::Local(_) if !aliased.contains(&o.variant) => Some(Factor::Before(*o)),
+            _ => None,
+        };
+
+        let mut inductions = Vec::new();
+        for phi in &self.blocks[l.header].instructions {
+            let Variant::Phi(args) = &phi.variant else {
+                continue;
+            };
+            let (init, (latch, next)) = match args[..] {
+                [(p, init), back] | [back, (p, init)] if !l.blocks.contains(&p) => (init, back),
+                _ => continue,
+            };
+            let Some(&(b, k)) = defined.get(&next).filter(|_| l.blocks.contains(&latch)) else {
+                continue;
+            };
+            let i = &self.blocks[b].instructions[k];
+            let step = match i.variant {
+                Variant::Plus if i.opm == phi.opdt => factor(&i.opn),
+                Variant::Plus if i.opn == phi.opdt => factor(&i.opm),
+                Variant::Minus if i.opm == phi.opdt => factor(&i.opn),
+                _ => None,
+            };
+            if let (Some(step), operand::Type::T) = (step, phi.opdt.otype) {
+                inductions.push(Induction {
+                    phi: phi.opdt,
+                    init,
+                    next,
+                    latch,
+                    variant: i.variant.clone(),
+                    step,
+                });
+            }
+        }
+
+        let mut products = Vec::new();
+        for &b in &l.blocks {
+            for i in &self.blocks[b].instructions {
+                if i.variant != Variant::Times || i.opdt.otype != operand::Type::T {
+                    continue;
+                }
+                for (x, k) in [(i.opm, i.opn), (i.opn, i.opm)] {
+                    let found = inductions
+                        .iter()
+                        .enumerate()
+                        .find_map(|(n, v)| (x == v.phi || x == v.next).then_some((n, x == v.next)));
+                    if let (Some((induction, next)), Some(factor)) = (found, factor(&k)) {
+                        products.push(Product {
+                            opdt: i.opdt,
+                            induction,
+                            next,
+                            factor,
+                        });
+                        break;
+                    }
+                }
+            }
+        }
+        (inductions, products)
+    }
+
+    // Give each induction variable and factor a variable of its own, set up
+    // in the preheader p and stepped where the induction variable is.
+    fn reduce(&mut self, l: &Loop, p: usize, inductions: &[Induction], products: Vec<Product>) {
+        let mut fresh = Fresh::new(self);
+        let mut before = Vec::new();
+        let mut phis = Vec::new();
+        let mut steps: Vec<(Operand, Vec<Instruction>)> = Vec::new();
+        let mut reduced: HashMap<(usize, Factor), (Operand, Operand)> = HashMap::new();
+        let mut replaced = HashMap::new();
+
+        for product in products {
+            let v = &inductions[product.induction];
+            let key = (product.induction, product.factor.clone());
+            let &mut (j, next) = reduced.entry(key).or_insert_with(|| {
+                let factor = materialize(&product.factor, &mut fresh, &mut before);
+                let start = fresh.temp(operand::Type::T);
+                before.push(Instruction::binary(Variant::Times, start, v.init, factor));
+
+                // A constant increment is loaded where it is added, as
+                // constants are, rather than kept in a register.
+                let increment = fresh.temp(operand::Type::T);
+                let mut step = Vec::new();
+                match constant_product(&product.factor, &v.step) {
+                    Some(lit) => step.push(Instruction::load(Set::T, increment, lit)),
+                    None => {
+                        let by = materialize(&v.step, &mut fresh, &mut before);
+                        before.push(Instruction::binary(Variant::Times, increment, by, factor));
+                    }
+                }
+
+                let j = fresh.temp(operand::Type::T);
+                let next = fresh.temp(operand::Type::T);
+                phis.push(Instruction::phi(j, vec![(p, start), (v.latch, next)]));
+                step.push(Instruction::binary(v.variant.clone(), next, j, increment));
+                steps.push((v.next, step));
+                (j, next)
+            });
+            replaced.insert(product.opdt, if product.next { next } else { j });
+        }
+
+        let block = &mut self.blocks[p].instructions;
+        let at = block.len() - usize::from(block.last().is_some_and(|i| i.is_terminator()));
+        block.splice(at..at, before);
+
+        let header = &mut self.blocks[l.header].instructions;
+        let at = header.iter().take_while(|i| i.is_label()).count();
+        header.splice(at..at, phis);
+
+        // Each step follows the one of its induction variable.
+        for (after, step) in steps {
+            for &b in &l.blocks {
+                let block = &mut self.blocks[b].instructions;
+                if let Some(k) = block.iter().position(|i| i.opdt == after) {
+                    block.splice(k + 1..k + 1, step);
+                    break;
+                }
+            }
+        }
+
+        for b in &mut self.blocks {
+            b.instructions
+                .retain_mut(|i| !i.def_mut().is_some_and(|d| replaced.contains_key(d)));
+            for i in &mut b.instructions {
+                for o in i.operands_mut() {
+                    if let Some(r) = replaced.get(o) {
+                        *o = *r;
+                    }
+                }
+            }
+        }
+    }
+}
+
+// The operand holding the factor before the loop, loading it if constant.
+fn materialize(factor: &Factor, fresh: &mut Fresh, before: &mut Vec<Instruction>) -> Operand {
+    match factor {
+        Factor::Constant(lit) => {
+            let t = fresh.temp(operand::Type::T);
+            before.push(Instruction::load(Set::T, t, lit.clone()));
+            t
+        }
+        Factor::Before(o) => *o,
+    }
+}
+
+fn constant_product(m: &Factor, n: &Factor) -> Option<String> {
+    match (m, n) {
+        (Factor::Constant(m), Factor::Constant(n)) => {
+            let (m, n) = (m.parse::<i32>().ok()?, n.parse::<i32>().ok()?);
+            Some(m.wrapping_mul(n).to_string())
+        }
+        _ => None,
+    }
+}
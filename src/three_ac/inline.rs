@@ -0,0 +1,242 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::error::Error;
+use crate::symtable::ARG_OFFSET;
+
+use super::cfg::{Cfg, Function};
+use super::instruction::{Instruction, Set, Variant};
+use super::label::{Block, Label};
+use super::operand::{self, Operand};
+use super::ssa::Fresh;
+
+// The most instructions a function may have to be copied into its callers.
+const SMALL: usize = 12;
+
+// Where the first argument of a call is in the frame of the function called.
+const ARGUMENTS: i32 = ARG_OFFSET + 4;
+
+impl Cfg {
+    // Copy the bodies of small functions into their callers in place of the
+    // calls, which saves passing arguments and the result through memory and
+    // setting up a frame. Callees go before their callers, so a function is
+    // measured with what was copied into it. Recursive functions are never
+    // copied, since that would not end.
+    pub fn inline(&mut self) -> Result<(), Error> {
+        let calls: HashMap<String, HashSet<String>> = self
+            .functions
+            .iter()
+            .map(|f| (f.name.clone(), f.callees()))
+            .collect();
+        let mut order = Vec::new();
+        let mut visited = HashSet::new();
+        for f in &self.functions {
+            postorder(&f.name, &calls, &mut visited, &mut order);
+        }
+
+        let mut bodies = HashMap::new();
+        for name in order {
+            let Some(f) = self.functions.iter_mut().find(|f| f.name == name) else {
+                continue;
+            };
+            f.inline_calls(&bodies)?;
+            if f.size() <= SMALL && !reaches(&calls, &name, &name) {
+                let body = f.blocks.iter().flat_map(|b| b.instructions.clone());
+                bodies.insert(name, body.collect::<Vec<_>>());
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Function {
+    fn callees(&self) -> HashSet<String> {
+        self.blocks
+            .iter()
+            .flat_map(|b| &b.instructions)
+            .filter_map(|i| match &i.variant {
+                Variant::Call(Label::FunctionHead(g), _) => Some(g.clone()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    // The instructions that would be left of the function once copied.
+    fn size(&self) -> usize {
+        self.blocks
+            .iter()
+            .flat_map(|b| &b.instructions)
+            .filter(|i| !i.is_label() && !matches!(i.variant, Variant::Alloc(_) | Variant::Ret))
+            .count()
+    }
+
+    // Replace the calls to the functions in bodies with their instructions.
+    // The locals and arguments of each copy get frame slots of their own
+    // below those of the function, and its labels a block of their own.
+    fn inline_calls(&mut self, bodies: &HashMap<String, Vec<Instruction>>) -> Result<(), Error> {
+        let body = |i: &Instruction| match &i.variant {
+            Variant::Call(Label::FunctionHead(g), _) => bodies.get(g),
+            _ => None,
+        };
+        let instructions: Vec<Instruction> = self
+            .blocks
+            .iter()
+            .flat_map(|b| b.instructions.clone())
+            .collect();
+        if !instructions.iter().any(|i| body(i).is_some()) {
+            return Ok(());
+        }
+
+        let mut fresh = Fresh::new(self);
+        let mut frame = instructions
+            .iter()
+            .find_map(|i| match i.variant {
+                Variant::Alloc(size) => Some(size),
+                _ => None,
+            })
+            .unwrap_or(0);
+        let mut sites = 0;
+        let mut output = Vec::new();
+        for i in instructions {
+            let Some(callee) = body(&i) else {
+                output.push(i);
+                continue;
+            };
+            sites += 1;
+            let site = Block::new(&self.name, "inline", sites);
+            output.extend(expand(i, callee, &site, &mut frame, &mut fresh));
+        }
+
+        let at = output.iter().take_while(|i| i.is_label()).count();
+        match output
+            .iter_mut()
+            .find(|i| matches!(i.variant, Variant::Alloc(_)))
+        {
+            Some(alloc) => alloc.variant = Variant::Alloc(frame),
+            None => output.insert(at, Instruction::alloc(frame as i32)),
+        }
+        *self = Function::new(self.name.clone(), output)?;
+        Ok(())
+    }
+}
+
+// The instructions of a copy of callee in place of call. The arguments are
+// assigned to the callee's argument slots, saving the result becomes an
+// assignment to the call's result, and returning a jump past the copy.
+fn expand(
+    call: Instruction,
+    callee: &[Instruction],
+    site: &Block,
+    frame: &mut u32,
+    fresh: &mut Fresh,
+) -> Vec<Instruction> {
+    let Variant::Call(_, args) = &call.variant else {
+        unreachable!("expand: not a call");
+    };
+    let locals = *frame as i32;
+    let own = callee
+        .iter()
+        .find_map(|i| match i.variant {
+            Variant::Alloc(size) => Some(size),
+            _ => None,
+        })
+        .unwrap_or(0);
+    let arguments = locals + own as i32;
+    *frame += own + 4 * args.len() as u32;
+    let relocate = |i: i32| match i {
+        ..0 => i - locals,
+        ARGUMENTS.. => -(arguments + i - ARGUMENTS + 4),
+        _ => i,
+    };
+
+    let mut output = Vec::new();
+    for a in args {
+        let slot = Operand {
+            variant: operand::Variant::Local(relocate(ARGUMENTS + 4 * output.len() as i32)),
+            otype: a.otype,
+        };
+        let mut assign = Instruction::assign(Set::from_type(a.otype), slot, *a);
+        assign.span = call.span;
+        output.push(assign);
+    }
+
+    let end = site.label("return");
+    let mut temps = HashMap::new();
+    for (k, i) in callee.iter().enumerate() {
+        let mut i = i.clone();
+        match i.variant {
+            Variant::Label(Label::FunctionHead(_)) | Variant::Alloc(_) => continue,
+            Variant::Ret if k + 1 == callee.len() => continue,
+            Variant::Ret => i = Instruction::jump(end.clone()),
+            _ => {}
+        }
+        for o in i.operands_mut() {
+            match o.variant {
+                operand::Variant::Local(n) => o.variant = operand::Variant::Local(relocate(n)),
+                operand::Variant::Temp(_) | operand::Variant::TempFloat(_) => {
+                    *o = *temps.entry(*o).or_insert_with(|| fresh.temp(o.otype));
+                }
+                _ => {}
+            }
+        }
+        for l in i.labels_mut() {
+            match l {
+                Label::Block(..) => *l = site.label(&l.to_string()),
+                Label::FunctionTail(_) => *l = end.clone(),
+                Label::FunctionHead(_) => {}
+            }
+        }
+        if i.variant == Variant::Save {
+            if call.opdt.variant == operand::Variant::Null {
+                continue;
+            }
+            let span = i.span;
+            i = Instruction::assign(call.set, call.opdt, i.opdt);
+            i.span = span;
+        }
+        output.push(i);
+    }
+
+    // Without a return from the middle, the copy runs on into what follows.
+    let returns = output
+        .iter_mut()
+        .filter(|i| !i.is_label())
+        .any(|i| i.labels_mut().into_iter().any(|l| *l == end));
+    if !returns {
+        output.retain(|i| !matches!(&i.variant, Variant::Label(l) if *l == end));
+    }
+    output
+}
+
+fn postorder(
+    name: &str,
+    calls: &HashMap<String, HashSet<String>>,
+    visited: &mut HashSet<String>,
+    order: &mut Vec<String>,
+) {
+    if !visited.insert(name.to_owned()) {
+        return;
+    }
+    for g in calls.get(name).into_iter().flatten() {
+        postorder(g, calls, visited, order);
+    }
+    if calls.contains_key(name) {
+        order.push(name.to_owned());
+    }
+}
+
+// Whether a chain of calls leads from one function to another.
+fn reaches(calls: &HashMap<String, HashSet<String>>, from: &str, to: &str) -> bool {
+    let mut seen = HashSet::new();
+    let mut work = vec![from];
+    while let Some(f) = work.pop() {
+        for g in calls.get(f).into_iter().flatten() {
+            if g == to {
+                return true;
+            }
+            if seen.insert(g.as_str()) {
+                work.push(g);
+            }
+        }
+    }
+    false
+}
@@ -1,25 +1,30 @@
 use std::fmt;
 
 use super::label::Label;
+
 use super::operand::{self, Operand};
+use crate::listing::Span;
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct Instruction {
     pub variant: Variant,
     pub set: Set,
     pub opdt: Operand,
     pub opm: Operand,
     pub opn: Operand,
+    // The statement the instruction was lowered from.
+    pub span: Option<Span>,
 }
 
 impl Instruction {
-    pub fn header_text(label: Label) -> Self {
+    pub fn header_text(label: Option<Label>) -> Self {
         Self {
             variant: Variant::HeaderText(label),
             set: Set::T,
             opdt: Operand::new_null(),
             opm: Operand::new_null(),
             opn: Operand::new_null(),
+            span: None,
         }
     }
 
@@ -30,6 +35,7 @@ impl Instruction {
             opdt: Operand::new_null(),
             opm: Operand::new_null(),
             opn: Operand::new_null(),
+            span: None,
         }
     }
 
@@ -40,6 +46,7 @@ impl Instruction {
             opdt,
             opm,
             opn: Operand::new_null(),
+            span: None,
         }
     }
 
@@ -50,6 +57,7 @@ impl Instruction {
             opdt,
             opm,
             opn: Operand::new_null(),
+            span: None,
         }
     }
 
@@ -60,6 +68,7 @@ impl Instruction {
             opdt,
             opm: Operand::new_null(),
             opn: Operand::new_null(),
+            span: None,
         }
     }
 
@@ -70,6 +79,7 @@ impl Instruction {
             opdt,
             opm: Operand::new_null(),
             opn: Operand::new_null(),
+            span: None,
         }
     }
 
@@ -80,6 +90,7 @@ impl Instruction {
             opdt,
             opm,
             opn: Operand::new_null(),
+            span: None,
         }
     }
 
@@ -94,6 +105,7 @@ impl Instruction {
             opdt,
             opm: Operand::new_null(),
             opn: Operand::new_null(),
+            span: None,
         }
     }
 
@@ -104,6 +116,7 @@ impl Instruction {
             opdt: Operand::new_null(),
             opm: Operand::new_null(),
             opn: Operand::new_null(),
+            span: None,
         }
     }
 
@@ -114,6 +127,7 @@ impl Instruction {
             opdt,
             opm: Operand::new_null(),
             opn: Operand::new_null(),
+            span: None,
         }
     }
 
@@ -124,6 +138,7 @@ impl Instruction {
             opdt,
             opm: Operand::new_null(),
             opn: Operand::new_null(),
+            span: None,
         }
     }
 
@@ -134,6 +149,7 @@ impl Instruction {
             opdt,
             opm,
             opn: Operand::new_null(),
+            span: None,
         }
     }
 
@@ -144,6 +160,7 @@ impl Instruction {
             opdt,
             opm,
             opn: Operand::new_null(),
+            span: None,
         }
     }
 
@@ -154,6 +171,7 @@ impl Instruction {
             opdt,
             opm,
             opn: Operand::new_null(),
+            span: None,
         }
     }
 
@@ -164,6 +182,7 @@ impl Instruction {
             opdt,
             opm,
             opn: Operand::new_null(),
+            span: None,
         }
     }
 
@@ -174,6 +193,19 @@ impl Instruction {
             opdt,
             opm,
             opn: Operand::new_null(),
+            span: None,
+        }
+    }
+
+    // Plus, Minus, Times or Divide.
+    pub fn binary(variant: Variant, opdt: Operand, opm: Operand, opn: Operand) -> Self {
+        Self {
+            variant,
+            set: Set::from_type(opdt.otype),
+            opdt,
+            opm,
+            opn,
+            span: None,
         }
     }
 
@@ -184,6 +216,7 @@ impl Instruction {
             opdt: Operand::new_null(),
             opm: Operand::new_null(),
             opn: Operand::new_null(),
+            span: None,
         }
     }
 
@@ -194,6 +227,18 @@ impl Instruction {
             opdt: Operand::new_null(),
             opm: Operand::new_null(),
             opn: Operand::new_null(),
+            span: None,
+        }
+    }
+
+    pub fn jump_table(table: Label, targets: Vec<Label>, opm: Operand) -> Self {
+        Self {
+            variant: Variant::JumpTable(table, targets),
+            set: Set::T,
+            opdt: Operand::new_null(),
+            opm,
+            opn: Operand::new_null(),
+            span: None,
         }
     }
 
@@ -204,6 +249,55 @@ impl Instruction {
             opdt,
             opm: Operand::new_null(),
             opn: Operand::new_null(),
+            span: None,
+        }
+    }
+
+    // Call the function whose address is in opm.
+    pub fn call_indirect(opm: Operand, args: Vec<Operand>, set: Set, opdt: Operand) -> Self {
+        Self {
+            variant: Variant::CallIndirect(args),
+            set,
+            opdt,
+            opm,
+            opn: Operand::new_null(),
+            span: None,
+        }
+    }
+
+    // Jump to the function in label, passing args in the frame's own
+    // arguments, so that it returns to where this function would have.
+    pub fn tail_call(label: Label, args: Vec<Operand>) -> Self {
+        Self {
+            variant: Variant::TailCall(label, args),
+            set: Set::T,
+            opdt: Operand::new_null(),
+            opm: Operand::new_null(),
+            opn: Operand::new_null(),
+            span: None,
+        }
+    }
+
+    pub fn load_label(opdt: Operand, label: Label) -> Self {
+        Self {
+            variant: Variant::LoadLabel(label),
+            set: Set::T,
+            opdt,
+            opm: Operand::new_null(),
+            opn: Operand::new_null(),
+            span: None,
+        }
+    }
+
+    // Take the operand of the predecessor control came from.
+    pub fn phi(opdt: Operand, args: Vec<(usize, Operand)>) -> Self {
+        Self {
+            variant: Variant::Phi(args),
+            set: Set::from_type(opdt.otype),
+            opdt,
+            opm: Operand::new_null(),
+            opn: Operand::new_null(),
+            span: None,
         }
     }
 
@@ -214,6 +308,7 @@ impl Instruction {
             opdt: Operand::new_null(),
             opm: Operand::new_null(),
             opn: Operand::new_null(),
+            span: None,
         }
     }
 
@@ -224,13 +319,14 @@ impl Instruction {
             opdt: Operand::new_null(),
             opm: Operand::new_null(),
             opn: Operand::new_null(),
+            span: None,
         }
     }
 }
 
-#[derive(Clone, Debug, Eq, PartialEq)]
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
 pub enum Variant {
-    HeaderText(Label),
+    HeaderText(Option<Label>),
     HeaderStrings(String),
 
     AddrAssign,
@@ -243,6 +339,7 @@ pub enum Variant {
     Ret,
     Save,
     Load(String),
+    LoadLabel(Label),
 
     Address,
     Dereference,
@@ -265,22 +362,163 @@ pub enum Variant {
 
     Label(Label),
     Jump(Label),
+    JumpTable(Label, Vec<Label>),
     Call(Label, Vec<Operand>),
+    CallIndirect(Vec<Operand>),
+    // A call in place of returning, which reuses the caller's frame.
+    TailCall(Label, Vec<Operand>),
+    // Operands by predecessor block, in SSA form only.
+    Phi(Vec<(usize, Operand)>),
 
     Alloc(u32),
     SpillRegisters,
 }
 
-#[derive(Debug, Eq, PartialEq)]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
 pub enum Set {
     T,
     F,
 }
 
+impl Set {
+    pub fn from_type(otype: operand::Type) -> Self {
+        match otype {
+            operand::Type::T => Self::T,
+            operand::Type::F => Self::F,
+        }
+    }
+}
+
+impl Instruction {
+    // The operand the instruction assigns, if any.
+    pub fn def_mut(&mut self) -> Option<&mut Operand> {
+        match self.variant {
+            Variant::Assign
+            | Variant::Malloc
+            | Variant::Negate
+            | Variant::Cast
+            | Variant::Get
+            | Variant::Load(_)
+            | Variant::LoadLabel(_)
+            | Variant::Address
+            | Variant::Dereference
+            | Variant::Reference
+            | Variant::Plus
+            | Variant::Minus
+            | Variant::Times
+            | Variant::Divide
+            | Variant::Call(..)
+            | Variant::CallIndirect(_)
+            | Variant::Phi(_) => Some(&mut self.opdt),
+            _ => None,
+        }
+    }
+
+    // The operands whose values the instruction reads. A reference takes the
+    // address of its operand rather than the value, and the operands of a phi
+    // are read on the edges into its block.
+    pub fn uses_mut(&mut self) -> Vec<&mut Operand> {
+        let Self {
+            variant,
+            opdt,
+            opm,
+            opn,
+            ..
+        } = self;
+        match variant {
+            Variant::AddrAssign => vec![opdt, opm],
+            Variant::Assign
+            | Variant::Malloc
+            | Variant::Negate
+            | Variant::Cast
+            | Variant::Address
+            | Variant::Dereference
+            | Variant::JumpTable(..) => vec![opm],
+            Variant::Put | Variant::PutS | Variant::Save | Variant::Free => vec![opdt],
+            Variant::Plus
+            | Variant::Minus
+            | Variant::Times
+            | Variant::Divide
+            | Variant::Equal(_)
+            | Variant::NotEqual(_)
+            | Variant::Less(_)
+            | Variant::LessEqual(_)
+            | Variant::Greater(_)
+            | Variant::GreaterEqual(_) => vec![opm, opn],
+            Variant::Call(_, args) | Variant::TailCall(_, args) => args.iter_mut().collect(),
+            Variant::CallIndirect(args) => std::iter::once(opm).chain(args).collect(),
+            _ => Vec::new(),
+        }
+    }
+
+    // Every operand of the instruction, assigned or read.
+    pub fn operands_mut(&mut self) -> Vec<&mut Operand> {
+        let Self {
+            variant,
+            opdt,
+            opm,
+            opn,
+            ..
+        } = self;
+        let mut operands = vec![opdt, opm, opn];
+        match variant {
+            Variant::Call(_, args) | Variant::CallIndirect(args) | Variant::TailCall(_, args) => {
+                operands.extend(args)
+            }
+            Variant::Phi(args) => operands.extend(args.iter_mut().map(|(_, o)| o)),
+            _ => {}
+        }
+        operands
+    }
+
+    // Every label the instruction defines or refers to.
+    pub fn labels_mut(&mut self) -> Vec<&mut Label> {
+        match &mut self.variant {
+            Variant::Label(l)
+            | Variant::Equal(l)
+            | Variant::NotEqual(l)
+            | Variant::Less(l)
+            | Variant::LessEqual(l)
+            | Variant::Greater(l)
+            | Variant::GreaterEqual(l)
+            | Variant::Jump(l)
+            | Variant::Call(l, _)
+            | Variant::TailCall(l, _)
+            | Variant::LoadLabel(l) => vec![l],
+            Variant::JumpTable(table, targets) => std::iter::once(table).chain(targets).collect(),
+            _ => Vec::new(),
+        }
+    }
+
+    // Make a jump to from go to to instead.
+    pub fn retarget(&mut self, from: &Label, to: &Label) {
+        match &mut self.variant {
+            Variant::Equal(l)
+            | Variant::NotEqual(l)
+            | Variant::Less(l)
+            | Variant::LessEqual(l)
+            | Variant::Greater(l)
+            | Variant::GreaterEqual(l)
+            | Variant::Jump(l)
+                if l == from =>
+            {
+                *l = to.clone();
+            }
+            Variant::JumpTable(_, targets) => {
+                for l in targets.iter_mut().filter(|l| *l == from) {
+                    *l = to.clone();
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
 impl fmt::Display for Instruction {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match &self.variant {
-            Variant::HeaderText(l) => writeln!(f, "HEADER TEXT | JR {l}"),
+            Variant::HeaderText(Some(l)) => writeln!(f, "HEADER TEXT | JR {l}"),
+            Variant::HeaderText(None) => writeln!(f, "HEADER TEXT"),
             Variant::HeaderStrings(s) => write!(f, "HEADER STRINGS\n{s}"),
 
             Variant::AddrAssign => writeln!(f, "{} <= {}", self.opdt, self.opm),
@@ -292,6 +530,7 @@ impl fmt::Display for Instruction {
             Variant::Ret => writeln!(f, "RET"),
             Variant::Save => writeln!(f, "Save {}", self.opdt),
             Variant::Load(v) => writeln!(f, "{} := {v}", self.opdt),
+            Variant::LoadLabel(l) => writeln!(f, "{} := &{l}", self.opdt),
 
             Variant::Address => writeln!(f, "{} = %[{}]", self.opdt, self.opm),
             Variant::Dereference => writeln!(f, "{} = *[{}]", self.opdt, self.opm),
@@ -326,6 +565,10 @@ impl fmt::Display for Instruction {
             Variant::Jump(l) => {
                 writeln!(f, "J {l}")
             }
+            Variant::JumpTable(l, targets) => {
+                let targets: Vec<String> = targets.iter().map(|t| t.to_string()).collect();
+                writeln!(f, "J {l}[{}] ({})", self.opm, targets.join(", "))
+            }
             Variant::Call(l, args) => {
                 let mut arg = String::new();
                 for a in args {
@@ -340,6 +583,21 @@ impl fmt::Display for Instruction {
                     _ => writeln!(f, "{} = {l}({})", self.opdt, arg),
                 }
             }
+            Variant::CallIndirect(args) => {
+                let args: Vec<String> = args.iter().map(|a| a.to_string()).collect();
+                match self.opdt.variant {
+                    operand::Variant::Null => writeln!(f, "*{}({})", self.opm, args.join(", ")),
+                    _ => writeln!(f, "{} = *{}({})", self.opdt, self.opm, args.join(", ")),
+                }
+            }
+            Variant::TailCall(l, args) => {
+                let args: Vec<String> = args.iter().map(|a| a.to_string()).collect();
+                writeln!(f, "TAIL {l}({})", args.join(", "))
+            }
+            Variant::Phi(args) => {
+                let args: Vec<String> = args.iter().map(|(b, o)| format!("{b}: {o}")).collect();
+                writeln!(f, "{} = PHI({})", self.opdt, args.join(", "))
+            }
 
             Variant::Alloc(u) => writeln!(f, "ALLOC {u}"),
             Variant::SpillRegisters => writeln!(f, "SPILL REGISTERS"),
@@ -2,23 +2,35 @@ use std::collections::VecDeque;
 use std::fmt;
 
 use super::instruction::{self, Instruction};
-use super::label::Label;
+use super::label::{Block, Label};
 use super::operand::{self, Operand};
 
 use crate::ast::{self, BinOp, CondOp};
 use crate::error::Error;
-use crate::symtable::{CType, SymTable};
+use crate::listing::Span;
+use crate::symtable::{CType, SymTable, SymbolType};
 
 pub struct Count {
     pub regular: u32,
     pub float: u32,
     pub label: u32,
+    pub slot: u32,
+    // Name of the function being lowered, for its labels.
+    pub function: String,
+    // Exit labels of the enclosing loops and switches, innermost last.
+    pub breaks: Vec<Label>,
 }
 
 impl Count {
     fn reset(&mut self) {
         self.regular = 0;
         self.float = 0;
+        self.label = 0;
+    }
+
+    fn block(&mut self, construct: &'static str) -> Block {
+        self.label += 1;
+        Block::new(&self.function, construct, self.label)
     }
 }
 
@@ -41,11 +53,9 @@ impl Instructions {
             ast::Node::Free { expr } => Self::from_free(*expr, count, symtable),
             ast::Node::Malloc { ctype, expr } => Self::from_malloc(ctype, *expr, count, symtable),
             ast::Node::Read { ctype, var } => Self::from_read(ctype, *var, count, symtable),
-            ast::Node::Return {
-                ctype,
-                expr,
-                function,
-            } => Self::from_return(ctype, *expr, function, count, symtable),
+            ast::Node::Return { ctype, expr, .. } => {
+                Self::from_return(ctype, *expr, count, symtable)
+            }
             ast::Node::StatementList { statements } => {
                 Self::from_statement_list(statements, count, symtable)
             }
@@ -56,18 +66,29 @@ impl Instructions {
             ast::Node::While { cond, statements } => {
                 Self::from_while(*cond, *statements, count, symtable)
             }
+            ast::Node::DoWhile { cond, statements } => {
+                Self::from_do_while(*cond, *statements, count, symtable)
+            }
+            ast::Node::Switch { expr, cases } => Self::from_switch(*expr, cases, count, symtable),
+            ast::Node::Break => Self::from_break(count),
+            ast::Node::Located { span, node } => Ok(Self::from_ast(*node, count, symtable)
+                .map_err(|e| e.at(span))?
+                .locate(span)),
             ast::Node::BinaryOp {
                 ctype,
                 op,
                 lhs,
                 rhs,
             } => Self::from_binary_op(ctype, op, *lhs, *rhs, count, symtable),
-            ast::Node::ConditionalOp {
+            ast::Node::ConditionalOp { .. } => Err(Error::ThreeAC(String::from(
+                "from_ast: conditional outside of a branch",
+            ))),
+            ast::Node::Ternary {
                 ctype,
-                op,
+                cond,
                 lhs,
                 rhs,
-            } => Self::from_conditional_op(ctype, op, *lhs, *rhs, count, symtable),
+            } => Self::from_ternary(ctype, *cond, *lhs, *rhs, count, symtable),
             ast::Node::UnaryOp { ctype, expr } => {
                 Self::from_unary_op(ctype, *expr, count, symtable)
             }
@@ -90,6 +111,16 @@ impl Instructions {
                 scope,
                 arguments,
             } => Self::from_call(ctype, ident, scope, arguments, count, symtable),
+            ast::Node::IndirectCall {
+                ctype,
+                expr,
+                arguments,
+            } => Self::from_indirect_call(ctype, *expr, arguments, count, symtable),
+            ast::Node::FunctionAddress {
+                ctype,
+                ident,
+                scope,
+            } => Self::from_function_address(ctype, ident, scope, count, symtable),
             ast::Node::FloatLit { ctype, val } => Self::from_float_lit(ctype, val, count),
             ast::Node::IntLit { ctype, val } => Self::from_int_lit(ctype, val, count),
             ast::Node::Var {
@@ -114,7 +145,9 @@ impl Instructions {
     ) -> Result<Self, Error> {
         let is_addr_assign = matches!(lhs, ast::Node::Address { .. });
         let (mut instructions, lhs) = match lhs {
-            ast::Node::Address { expr, .. } => Self::from_ast(*expr, count, symtable)?.split(),
+            ast::Node::Address { expr, .. } => Self::from_ast(*expr, count, symtable)?
+                .park(rhs.ends_block(), count, symtable)?
+                .split(),
             _ => Self::from_ast(lhs, count, symtable)?.split(),
         };
         let (rhs_instrs, rhs) = Self::from_ast(rhs, count, symtable)?.split();
@@ -216,7 +249,6 @@ impl Instructions {
     fn from_return(
         ctype: CType,
         expr: ast::Node,
-        function: i32,
         count: &mut Count,
         symtable: &mut SymTable,
     ) -> Result<Self, Error> {
@@ -233,7 +265,9 @@ impl Instructions {
                 })?,
             ));
         }
-        instructions.push_back(Instruction::jump(Label::FunctionTail(function)));
+        instructions.push_back(Instruction::jump(Label::FunctionTail(
+            count.function.clone(),
+        )));
 
         Ok(Self {
             instructions,
@@ -294,16 +328,11 @@ impl Instructions {
         count: &mut Count,
         symtable: &mut SymTable,
     ) -> Result<Self, Error> {
-        count.label += 1;
-        let label = count.label;
-        let end = Label::BlockJump(label);
+        let block = count.block("if");
+        let end = block.label("end");
 
-        let (mut instructions, tmp) = Self::from_ast(cond, count, symtable)?.split();
-        if tmp.is_some() {
-            return Err(Error::ThreeAC(String::from(
-                "from_if_else: conditional statements should not have operands",
-            )));
-        }
+        let mut instructions =
+            Self::from_branch(cond, block.label("else"), count, symtable)?.instructions;
         let (linstrs, tmp) = Self::from_ast(lhs, count, symtable)?.split();
         if tmp.is_some() {
             return Err(Error::ThreeAC(String::from(
@@ -317,8 +346,8 @@ impl Instructions {
             )));
         }
         instructions.extend(linstrs);
-        instructions.push_back(Instruction::jump(end));
-        instructions.push_back(Instruction::label(Label::BlockBranch(label)));
+        instructions.push_back(Instruction::jump(end.clone()));
+        instructions.push_back(Instruction::label(block.label("else")));
         instructions.extend(rinstrs);
         instructions.push_back(Instruction::label(end));
 
@@ -334,27 +363,54 @@ impl Instructions {
         count: &mut Count,
         symtable: &mut SymTable,
     ) -> Result<Self, Error> {
-        count.label += 1;
-        let label = count.label;
-        let head = Label::BlockJump(label);
+        let block = count.block("while");
+        let head = block.label("head");
+        let end = block.label("end");
 
-        let (mut instructions, tmp) = Self::from_ast(cond, count, symtable)?.split();
+        let mut instructions = Self::from_branch(cond, end.clone(), count, symtable)?.instructions;
+        count.breaks.push(end.clone());
+        let (statements, tmp) = Self::from_ast(statements, count, symtable)?.split();
+        count.breaks.pop();
         if tmp.is_some() {
             return Err(Error::ThreeAC(String::from(
-                "from_while: conditional statements should not have operands",
+                "from_while: statements should not have operands",
             )));
         }
-        let (statements, tmp) = Self::from_ast(statements, count, symtable)?.split();
+
+        instructions.push_front(Instruction::label(head.clone()));
+        instructions.extend(statements);
+        instructions.push_back(Instruction::jump(head));
+        instructions.push_back(Instruction::label(end));
+
+        Ok(Self {
+            instructions,
+            tmp: None,
+        })
+    }
+
+    fn from_do_while(
+        cond: ast::Node,
+        statements: ast::Node,
+        count: &mut Count,
+        symtable: &mut SymTable,
+    ) -> Result<Self, Error> {
+        let block = count.block("do");
+        let head = block.label("head");
+        let end = block.label("end");
+
+        count.breaks.push(end.clone());
+        let (mut instructions, tmp) = Self::from_ast(statements, count, symtable)?.split();
+        count.breaks.pop();
         if tmp.is_some() {
             return Err(Error::ThreeAC(String::from(
-                "from_while: statements should not have operands",
+                "from_do_while: statements should not have operands",
             )));
         }
 
-        instructions.push_front(Instruction::label(head));
-        instructions.extend(statements);
+        instructions.push_front(Instruction::label(head.clone()));
+        instructions.extend(Self::from_branch(cond, end.clone(), count, symtable)?.instructions);
         instructions.push_back(Instruction::jump(head));
-        instructions.push_back(Instruction::label(Label::BlockBranch(label)));
+        instructions.push_back(Instruction::label(end));
 
         Ok(Self {
             instructions,
@@ -362,6 +418,134 @@ impl Instructions {
         })
     }
 
+    // Dense cases dispatch through a jump table, sparse ones through a chain of
+    // compares. Case bodies follow in source order so control falls through.
+    fn from_switch(
+        expr: ast::Node,
+        cases: Vec<(Option<i32>, ast::Node)>,
+        count: &mut Count,
+        symtable: &mut SymTable,
+    ) -> Result<Self, Error> {
+        let block = count.block("switch");
+        let end = block.label("end");
+
+        let (mut instructions, expr) = Self::from_ast(expr, count, symtable)?.split();
+        let expr = expr.ok_or_else(|| {
+            Error::ThreeAC(String::from("from_switch: expr does not have an operand"))
+        })?;
+        // The value is tested across several blocks, so it needs a frame slot.
+        let slot = Operand::new_slot(&CType::Int, count, symtable)?;
+
+        let targets: Vec<Label> = cases
+            .iter()
+            .enumerate()
+            .map(|(k, (val, _))| match val {
+                Some(_) => block.label(&format!("case{k}")),
+                None => block.label("default"),
+            })
+            .collect();
+        let default = cases
+            .iter()
+            .position(|(val, _)| val.is_none())
+            .map_or(end.clone(), |k| targets[k].clone());
+        let mut values: Vec<(i32, Label)> = cases
+            .iter()
+            .zip(&targets)
+            .filter_map(|((val, _), l)| val.map(|v| (v, l.clone())))
+            .collect();
+        values.sort_by_key(|(v, _)| *v);
+
+        match (values.first(), values.last()) {
+            (Some(&(min, _)), Some(&(max, _))) if is_dense(values.len(), min, max) => {
+                let (lo_instrs, lo) = Self::from_int_lit(CType::Int, min, count)?.split();
+                instructions.extend(lo_instrs);
+                instructions.push_back(Instruction {
+                    variant: instruction::Variant::Minus,
+                    set: instruction::Set::T,
+                    opdt: slot,
+                    opm: expr,
+                    opn: lo.ok_or(Error::Type)?,
+                    span: None,
+                });
+                let (zero_instrs, zero) = Self::from_int_lit(CType::Int, 0, count)?.split();
+                instructions.extend(zero_instrs);
+                instructions.push_back(Instruction {
+                    variant: instruction::Variant::GreaterEqual(default.clone()),
+                    set: instruction::Set::T,
+                    opdt: Operand::new_null(),
+                    opm: slot,
+                    opn: zero.ok_or(Error::Type)?,
+                    span: None,
+                });
+                let (hi_instrs, hi) = Self::from_int_lit(CType::Int, max - min, count)?.split();
+                instructions.extend(hi_instrs);
+                instructions.push_back(Instruction {
+                    variant: instruction::Variant::LessEqual(default.clone()),
+                    set: instruction::Set::T,
+                    opdt: Operand::new_null(),
+                    opm: slot,
+                    opn: hi.ok_or(Error::Type)?,
+                    span: None,
+                });
+                let table = (min..=max)
+                    .map(|v| match values.binary_search_by_key(&v, |(v, _)| *v) {
+                        Ok(i) => values[i].1.clone(),
+                        Err(_) => default.clone(),
+                    })
+                    .collect();
+                instructions.push_back(Instruction::jump_table(block.label("table"), table, slot));
+            }
+            _ => {
+                instructions.push_back(Instruction::assign(instruction::Set::T, slot, expr));
+                for (val, target) in values {
+                    let (val_instrs, val) = Self::from_int_lit(CType::Int, val, count)?.split();
+                    instructions.extend(val_instrs);
+                    instructions.push_back(Instruction {
+                        variant: instruction::Variant::NotEqual(target),
+                        set: instruction::Set::T,
+                        opdt: Operand::new_null(),
+                        opm: slot,
+                        opn: val.ok_or(Error::Type)?,
+                        span: None,
+                    });
+                }
+                instructions.push_back(Instruction::jump(default));
+            }
+        }
+
+        count.breaks.push(end.clone());
+        for ((_, statements), target) in cases.into_iter().zip(targets) {
+            let (statements, tmp) = Self::from_ast(statements, count, symtable)?.split();
+            if tmp.is_some() {
+                return Err(Error::ThreeAC(String::from(
+                    "from_switch: statements should not have operands",
+                )));
+            }
+            instructions.push_back(Instruction::label(target));
+            instructions.extend(statements);
+        }
+        count.breaks.pop();
+        instructions.push_back(Instruction::label(end));
+
+        Ok(Self {
+            instructions,
+            tmp: None,
+        })
+    }
+
+    fn from_break(count: &mut Count) -> Result<Self, Error> {
+        let end = count.breaks.last().ok_or_else(|| {
+            Error::ThreeAC(String::from(
+                "from_break: break outside of a loop or switch",
+            ))
+        })?;
+
+        Ok(Self {
+            instructions: VecDeque::from([Instruction::jump(end.clone())]),
+            tmp: None,
+        })
+    }
+
     fn from_binary_op(
         ctype: CType,
         op: BinOp,
@@ -370,7 +554,9 @@ impl Instructions {
         count: &mut Count,
         symtable: &mut SymTable,
     ) -> Result<Self, Error> {
-        let (mut instructions, lhs) = Self::from_ast(lhs, count, symtable)?.split();
+        let (mut instructions, lhs) = Self::from_ast(lhs, count, symtable)?
+            .park(rhs.ends_block(), count, symtable)?
+            .split();
         let (rhs_instrs, rhs) = Self::from_ast(rhs, count, symtable)?.split();
         instructions.extend(rhs_instrs);
 
@@ -390,6 +576,7 @@ impl Instructions {
             opn: rhs.ok_or_else(|| {
                 Error::ThreeAC(String::from("from_binary_op: rhs does not have an operand"))
             })?,
+            span: None,
         });
 
         Ok(Self {
@@ -398,29 +585,48 @@ impl Instructions {
         })
     }
 
+    // Falls through when cond holds, otherwise jumps to target.
+    fn from_branch(
+        cond: ast::Node,
+        target: Label,
+        count: &mut Count,
+        symtable: &mut SymTable,
+    ) -> Result<Self, Error> {
+        match cond {
+            ast::Node::ConditionalOp {
+                ctype,
+                op,
+                lhs,
+                rhs,
+            } => Self::from_conditional_op(ctype, op, *lhs, *rhs, target, count, symtable),
+            _ => Err(Error::ThreeAC(String::from(
+                "from_branch: expected a conditional",
+            ))),
+        }
+    }
+
     fn from_conditional_op(
         ctype: CType,
         op: CondOp,
         lhs: ast::Node,
         rhs: ast::Node,
+        target: Label,
         count: &mut Count,
         symtable: &mut SymTable,
     ) -> Result<Self, Error> {
-        let (mut instructions, lhs) = Self::from_ast(lhs, count, symtable)?.split();
+        let (mut instructions, lhs) = Self::from_ast(lhs, count, symtable)?
+            .park(rhs.ends_block(), count, symtable)?
+            .split();
         let (rhs_instrs, rhs) = Self::from_ast(rhs, count, symtable)?.split();
         instructions.extend(rhs_instrs);
         instructions.push_back(Instruction {
             variant: match op {
-                CondOp::Equal => instruction::Variant::Equal(Label::BlockBranch(count.label)),
-                CondOp::NotEqual => instruction::Variant::NotEqual(Label::BlockBranch(count.label)),
-                CondOp::Less => instruction::Variant::Less(Label::BlockBranch(count.label)),
-                CondOp::LessEqual => {
-                    instruction::Variant::LessEqual(Label::BlockBranch(count.label))
-                }
-                CondOp::Greater => instruction::Variant::Greater(Label::BlockBranch(count.label)),
-                CondOp::GreaterEqual => {
-                    instruction::Variant::GreaterEqual(Label::BlockBranch(count.label))
-                }
+                CondOp::Equal => instruction::Variant::Equal(target),
+                CondOp::NotEqual => instruction::Variant::NotEqual(target),
+                CondOp::Less => instruction::Variant::Less(target),
+                CondOp::LessEqual => instruction::Variant::LessEqual(target),
+                CondOp::Greater => instruction::Variant::Greater(target),
+                CondOp::GreaterEqual => instruction::Variant::GreaterEqual(target),
             },
             set: ctype.to_instruction_set(),
             opdt: match ctype {
@@ -437,6 +643,7 @@ impl Instructions {
                     "from_conditional_op: rhs does not have an operand",
                 ))
             })?,
+            span: None,
         });
 
         Ok(Self {
@@ -445,6 +652,50 @@ impl Instructions {
         })
     }
 
+    // Both arms write the same slot, since a temporary would not survive the
+    // blocks they end up in.
+    fn from_ternary(
+        ctype: CType,
+        cond: ast::Node,
+        lhs: ast::Node,
+        rhs: ast::Node,
+        count: &mut Count,
+        symtable: &mut SymTable,
+    ) -> Result<Self, Error> {
+        let block = count.block("cond");
+        let end = block.label("end");
+        let slot = Operand::new_slot(&ctype, count, symtable)?;
+
+        let mut instructions =
+            Self::from_branch(cond, block.label("else"), count, symtable)?.instructions;
+        let (linstrs, lhs) = Self::from_ast(lhs, count, symtable)?.split();
+        instructions.extend(linstrs);
+        instructions.push_back(Instruction::assign(
+            ctype.to_instruction_set(),
+            slot,
+            lhs.ok_or_else(|| {
+                Error::ThreeAC(String::from("from_ternary: lhs does not have an operand"))
+            })?,
+        ));
+        instructions.push_back(Instruction::jump(end.clone()));
+        instructions.push_back(Instruction::label(block.label("else")));
+        let (rinstrs, rhs) = Self::from_ast(rhs, count, symtable)?.split();
+        instructions.extend(rinstrs);
+        instructions.push_back(Instruction::assign(
+            ctype.to_instruction_set(),
+            slot,
+            rhs.ok_or_else(|| {
+                Error::ThreeAC(String::from("from_ternary: rhs does not have an operand"))
+            })?,
+        ));
+        instructions.push_back(Instruction::label(end));
+
+        Ok(Self {
+            instructions,
+            tmp: Some(slot),
+        })
+    }
+
     fn from_unary_op(
         ctype: CType,
         expr: ast::Node,
@@ -473,6 +724,10 @@ impl Instructions {
         count: &mut Count,
         symtable: &mut SymTable,
     ) -> Result<Self, Error> {
+        // Int and pointer casts share a register class and need no conversion.
+        if expr.ctype().to_instruction_set() == ctype.to_instruction_set() {
+            return Self::from_ast(expr, count, symtable);
+        }
         let (mut instructions, expr) = Self::from_ast(expr, count, symtable)?.split();
         let tmp = Operand::new_tmp(&ctype, count).ok_or(Error::Type)?;
         instructions.push_back(Instruction::cast(
@@ -565,10 +820,10 @@ impl Instructions {
         symtable: &mut SymTable,
     ) -> Result<Self, Error> {
         count.reset();
+        count.function.clone_from(&ident);
 
         let symbol = symtable.get_symbol_in_scope(&ident, scope)?;
         symtable.switch_scope(symbol.scope())?;
-        let (_, local_offset) = symtable.get_addr_offset_val();
 
         let (mut instructions, tmp) = Self::from_ast(statements, count, symtable)?.split();
         if tmp.is_some() {
@@ -576,12 +831,14 @@ impl Instructions {
                 "from_statement_list: statements should not have operands",
             )));
         }
+        // Read after lowering, which may add hidden locals.
+        let (_, local_offset) = symtable.get_addr_offset_val();
 
         if local_offset < 0 {
             instructions.push_front(Instruction::alloc(local_offset));
         }
-        instructions.push_front(Instruction::label(Label::FunctionHead(symbol.address())));
-        instructions.push_back(Instruction::label(Label::FunctionTail(symbol.address())));
+        instructions.push_front(Instruction::label(Label::FunctionHead(ident.clone())));
+        instructions.push_back(Instruction::label(Label::FunctionTail(ident)));
         instructions.push_back(Instruction::ret());
 
         symtable.pop_scope()?;
@@ -602,17 +859,72 @@ impl Instructions {
         let mut args = Vec::new();
         let mut instructions = VecDeque::new();
 
+        let mut ending = arguments.iter().filter(|a| a.ends_block()).count();
         for a in arguments {
-            let (instrs, a) = Self::from_ast(a, count, symtable)?.split();
+            if a.ends_block() {
+                ending -= 1;
+            }
+            let (instrs, a) = Self::from_ast(a, count, symtable)?
+                .park(ending > 0, count, symtable)?
+                .split();
             instructions.extend(instrs);
             args.push(a.ok_or_else(|| {
                 Error::ThreeAC(String::from("from_call: expression does not have operand"))
             })?);
         }
 
+        // The callee must be declared where it is called.
+        symtable.get_symbol_in_scope(&ident, scope)?;
         let tmp = Operand::new_tmp(&ctype, count);
         instructions.push_back(Instruction::call(
-            Label::FunctionHead(symtable.get_symbol_in_scope(&ident, scope)?.address()),
+            Label::FunctionHead(ident),
+            args,
+            ctype.to_instruction_set(),
+            match &tmp {
+                Some(o) => *o,
+                None => Operand::new_null(),
+            },
+        ));
+
+        Ok(Self { instructions, tmp })
+    }
+
+    fn from_indirect_call(
+        ctype: CType,
+        expr: ast::Node,
+        arguments: Vec<ast::Node>,
+        count: &mut Count,
+        symtable: &mut SymTable,
+    ) -> Result<Self, Error> {
+        let mut ending = arguments.iter().filter(|a| a.ends_block()).count();
+        let (mut instructions, target) = Self::from_ast(expr, count, symtable)?
+            .park(ending > 0, count, symtable)?
+            .split();
+        let target = target.ok_or_else(|| {
+            Error::ThreeAC(String::from(
+                "from_indirect_call: function pointer does not have operand",
+            ))
+        })?;
+
+        let mut args = Vec::new();
+        for a in arguments {
+            if a.ends_block() {
+                ending -= 1;
+            }
+            let (instrs, a) = Self::from_ast(a, count, symtable)?
+                .park(ending > 0, count, symtable)?
+                .split();
+            instructions.extend(instrs);
+            args.push(a.ok_or_else(|| {
+                Error::ThreeAC(String::from(
+                    "from_indirect_call: expression does not have operand",
+                ))
+            })?);
+        }
+
+        let tmp = Operand::new_tmp(&ctype, count);
+        instructions.push_back(Instruction::call_indirect(
+            target,
             args,
             ctype.to_instruction_set(),
             match &tmp {
@@ -624,6 +936,24 @@ impl Instructions {
         Ok(Self { instructions, tmp })
     }
 
+    fn from_function_address(
+        ctype: CType,
+        ident: String,
+        scope: usize,
+        count: &mut Count,
+        symtable: &SymTable,
+    ) -> Result<Self, Error> {
+        symtable.get_symbol_in_scope(&ident, scope)?;
+        let tmp = Operand::new_tmp(&ctype, count).ok_or(Error::Type)?;
+        Ok(Self {
+            instructions: VecDeque::from([Instruction::load_label(
+                tmp,
+                Label::FunctionHead(ident),
+            )]),
+            tmp: Some(tmp),
+        })
+    }
+
     fn from_float_lit(ctype: CType, val: f32, count: &mut Count) -> Result<Self, Error> {
         let tmp = Operand::new_tmp(&ctype, count).ok_or(Error::Type)?;
         Ok(Self {
@@ -662,13 +992,45 @@ impl Instructions {
         })
     }
 
+    // Temporaries do not survive block boundaries, so a value that is still
+    // needed after a later operand ends a block is moved to a slot.
+    fn park(
+        mut self,
+        needed: bool,
+        count: &mut Count,
+        symtable: &mut SymTable,
+    ) -> Result<Self, Error> {
+        if let Some(tmp) = self.tmp.filter(|_| needed) {
+            let ctype = match tmp.variant {
+                operand::Variant::Temp(_) => CType::Int,
+                operand::Variant::TempFloat(_) => CType::Float,
+                _ => return Ok(self),
+            };
+            let slot = Operand::new_slot(&ctype, count, symtable)?;
+            self.instructions
+                .push_back(Instruction::assign(ctype.to_instruction_set(), slot, tmp));
+            self.tmp = Some(slot);
+        }
+        Ok(self)
+    }
+
+    // Instructions of nested statements keep their own, closer span.
+    fn locate(mut self, span: Span) -> Self {
+        for i in self.instructions.iter_mut() {
+            i.span.get_or_insert(span);
+        }
+        self
+    }
+
     fn split(self) -> (VecDeque<Instruction>, Option<Operand>) {
         (self.instructions, self.tmp)
     }
 
-    pub fn add_headers(mut self, func: i32, strs: String) -> Self {
-        self.instructions
-            .push_front(Instruction::header_text(Label::FunctionHead(func)));
+    // A program without main (e.g. a library file) gets no entry point.
+    pub fn add_headers(mut self, func: Option<&str>, strs: String) -> Self {
+        self.instructions.push_front(Instruction::header_text(
+            func.map(|f| Label::FunctionHead(f.to_owned())),
+        ));
         self.instructions
             .push_back(Instruction::header_strings(strs));
 
@@ -696,7 +1058,21 @@ impl CType {
     }
 }
 
+// Jump tables are used when at least half of their entries are real cases.
+fn is_dense(cases: usize, min: i32, max: i32) -> bool {
+    cases >= 3 && i64::from(max) - i64::from(min) < 2 * cases as i64
+}
+
 impl Operand {
+    // A hidden local for values that must outlive a block. The name cannot
+    // clash with identifiers from the source.
+    fn new_slot(ctype: &CType, count: &mut Count, symtable: &mut SymTable) -> Result<Self, Error> {
+        count.slot += 1;
+        let name = format!("$slot{}", count.slot);
+        symtable.add_symbol(ctype.clone(), name.clone(), SymbolType::Local)?;
+        Operand::from_symbol(ctype, symtable.get_symbol(&name)?)
+    }
+
     fn new_tmp(ctype: &CType, count: &mut Count) -> Option<Self> {
         match ctype {
             CType::Void => None,
@@ -0,0 +1,151 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::error::Error;
+
+use super::cfg::{Function, Loop};
+use super::instruction::{Instruction, Variant};
+use super::operand::{self, Operand};
+
+impl Function {
+    // Move computations whose operands do not change while a loop runs to
+    // before it, so they run once. Inner loops go first, so what leaves one
+    // may then leave the loop around it too.
+    pub fn hoist_invariants(&mut self) -> Result<(), Error> {
+        let aliased = self.address_taken();
+        let mut done = HashSet::new();
+        loop {
+            let Some((l, label)) = self.next_loop(&done) else {
+                return Ok(());
+            };
+            done.insert(label);
+
+            let Some((p, reused)) = self.loop_entry(&l) else {
+                continue;
+            };
+            let hoisted = self.take_invariants(&l, &aliased);
+            if hoisted.is_empty() {
+                continue;
+            }
+            let p = if reused {
+                p
+            } else {
+                self.insert_preheader(&l, p)?
+            };
+
+            let block = &mut self.blocks[p].instructions;
+            let at = block.len() - usize::from(block.last().is_some_and(|i| i.is_terminator()));
+            block.splice(at..at, hoisted);
+        }
+    }
+
+    // Remove the loop's invariant computations and return them in an order
+    // that defines each temp before it is read. Constants are only worth
+    // moving for a computation that is moved.
+    fn take_invariants(
+        &mut self,
+        l: &Loop,
+        aliased: &HashSet<operand::Variant>,
+    ) -> Vec<Instruction> {
+        let mut defined = HashSet::new();
+        let mut writes = false;
+        for &b in &l.blocks {
+            for i in &mut self.blocks[b].instructions {
+                writes |= matches!(
+                    i.variant,
+                    Variant::AddrAssign | Variant::Call(..) | Variant::CallIndirect(_)
+                );
+                if let Some(d) = i.def_mut() {
+                    match d.variant {
+                        operand::Variant::Temp(_) | operand::Variant::TempFloat(_) => {
+                            defined.insert(*d);
+                        }
+                        operand::Variant::Global(_) => writes = true,
+                        operand::Variant::Local(_) => writes |= aliased.contains(&d.variant),
+                        _ => {}
+                    }
+                }
+            }
+        }
+        // Memory only stays the same in a loop that writes none of it.
+        let invariant = |o: &Operand, hoisted: &HashSet<Operand>| match o.variant {
+            operand::Variant::Temp(_) | operand::Variant::TempFloat(_) => {
+                !defined.contains(o) || hoisted.contains(o)
+            }
+            operand::Variant::Global(_) => !writes,
+            operand::Variant::Local(_) => !writes || !aliased.contains(&o.variant),
+            _ => true,
+        };
+
+        let mut hoisted = HashSet::new();
+        let mut order = Vec::new();
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for &b in &l.blocks {
+                for (k, i) in self.blocks[b].instructions.iter_mut().enumerate() {
+                    if !i.is_movable() || hoisted.contains(&i.opdt) {
+                        continue;
+                    }
+                    if i.uses_mut().into_iter().all(|o| invariant(o, &hoisted)) {
+                        hoisted.insert(i.opdt);
+                        order.push((b, k));
+                        changed = true;
+                    }
+                }
+            }
+        }
+
+        let mut read = HashSet::new();
+        for &(b, k) in &order {
+            let i = &mut self.blocks[b].instructions[k];
+            if !i.is_constant() {
+                read.extend(i.uses_mut().into_iter().map(|o| *o));
+            }
+        }
+        order.retain(|&(b, k)| {
+            let i = &self.blocks[b].instructions[k];
+            !i.is_constant() || read.contains(&i.opdt)
+        });
+
+        let mut taken = HashMap::new();
+        let positions: HashSet<(usize, usize)> = order.iter().copied().collect();
+        for &b in &l.blocks {
+            let instructions = std::mem::take(&mut self.blocks[b].instructions);
+            for (k, i) in instructions.into_iter().enumerate() {
+                if positions.contains(&(b, k)) {
+                    taken.insert((b, k), i);
+                } else {
+                    self.blocks[b].instructions.push(i);
+                }
+            }
+        }
+        order.iter().filter_map(|p| taken.remove(p)).collect()
+    }
+}
+
+impl Instruction {
+    // Whether the instruction only computes a temp and cannot fail, so it
+    // may run before the loop even when the loop would not have run it.
+    // Division may be by zero, and a pointer loaded through may only be
+    // valid once the loop has checked it.
+    fn is_movable(&self) -> bool {
+        matches!(
+            self.variant,
+            Variant::Load(_)
+                | Variant::LoadLabel(_)
+                | Variant::Assign
+                | Variant::Negate
+                | Variant::Cast
+                | Variant::Plus
+                | Variant::Minus
+                | Variant::Times
+        ) && matches!(
+            self.opdt.variant,
+            operand::Variant::Temp(_) | operand::Variant::TempFloat(_)
+        )
+    }
+
+    fn is_constant(&self) -> bool {
+        matches!(self.variant, Variant::Load(_) | Variant::LoadLabel(_))
+    }
+}
@@ -1,22 +1,53 @@
 use std::fmt;
 
-#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
 pub enum Label {
-    FunctionHead(i32),
-    FunctionTail(i32),
+    FunctionHead(String),
+    FunctionTail(String),
 
-    BlockBranch(u32),
-    BlockJump(u32),
+    // A named part of a block, e.g. the head of a loop.
+    Block(Block, String),
+}
+
+// An if, loop, switch or conditional expression, numbered within its function.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub struct Block {
+    function: String,
+    construct: &'static str,
+    n: u32,
+}
+
+impl Block {
+    pub fn new(function: &str, construct: &'static str, n: u32) -> Self {
+        Self {
+            function: function.to_owned(),
+            construct,
+            n,
+        }
+    }
+
+    pub fn label(&self, part: &str) -> Label {
+        Label::Block(self.clone(), part.to_owned())
+    }
 }
 
 impl fmt::Display for Label {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
-            Self::FunctionHead(n) => write!(f, "func_{n}"),
-            Self::FunctionTail(n) => write!(f, "func_tail_{n}"),
+            // Block labels always contain a dot and identifiers never do, so
+            // only names that read as a register need mangling.
+            Self::FunctionHead(name) if is_register(name) => write!(f, "{name}.func"),
+            Self::FunctionHead(name) => write!(f, "{name}"),
+            Self::FunctionTail(name) => write!(f, "{name}.tail"),
 
-            Self::BlockBranch(n) => write!(f, "branch_{n}"),
-            Self::BlockJump(n) => write!(f, "jump_{n}"),
+            Self::Block(b, part) => write!(f, "{}.{}{}.{part}", b.function, b.construct, b.n),
         }
     }
 }
+
+fn is_register(name: &str) -> bool {
+    match name.strip_prefix('x').or_else(|| name.strip_prefix('f')) {
+        Some(n) if !n.is_empty() && n.bytes().all(|b| b.is_ascii_digit()) => true,
+        _ => matches!(name, "sp" | "fp" | "ra"),
+    }
+}
@@ -1,10 +1,20 @@
+pub use self::cfg::{Cfg, Function};
 pub use self::instruction::{Instruction, Set, Variant};
 pub use self::instructions::{Count, Instructions};
 pub use self::label::Label;
 pub use self::operand::Operand;
 
+mod cfg;
+mod constants;
+mod dead_code;
+mod induction;
+mod inline;
 mod instruction;
 mod instructions;
+mod invariants;
 mod label;
 pub mod operand;
 mod optimize;
+mod ssa;
+mod tail_calls;
+mod value_numbering;
@@ -23,7 +23,7 @@ impl Operand {
                 symtable::Entry::Symbol {
                     address, symtype, ..
                 } => match symtype {
-                    SymbolType::Global => Variant::Global(address),
+                    SymbolType::Global | SymbolType::Extern => Variant::Global(address),
                     SymbolType::Str(_) => Variant::Str(address),
                     SymbolType::Local | SymbolType::Argument => Variant::Local(address),
                 },
@@ -85,7 +85,9 @@ impl Type {
             CType::Int | CType::Str => Type::T,
             CType::Float => Type::F,
             CType::Ptr(_) => Type::T,
-            CType::Void => unreachable!("from_ctype: encountered a void operand"),
+            CType::Func(..) | CType::Void => {
+                unreachable!("from_ctype: encountered a void or function operand")
+            }
         }
     }
 }
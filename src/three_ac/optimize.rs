@@ -1,5 +1,8 @@
 use std::collections::VecDeque;
 
+use crate::error::Error;
+
+use super::cfg::Cfg;
 use super::instruction::Variant;
 use super::instructions::Instructions;
 use super::operand;
@@ -24,23 +27,20 @@ impl Instructions {
         };
 
         while let Some(next) = list.pop_front() {
-            match next.variant {
-                Variant::Assign => {
+            match &next.variant {
+                Variant::Assign
                     if next.opm == curr.opdt
                         && matches!(
                             next.opm.variant,
                             operand::Variant::Temp(_) | operand::Variant::TempFloat(_)
-                        )
-                    {
-                        curr.opdt = next.opdt;
-                        continue;
-                    }
+                        ) =>
+                {
+                    curr.opdt = next.opdt;
+                    continue;
                 }
-                Variant::Label(l) => {
-                    if Variant::Jump(l) == curr.variant {
-                        curr = next;
-                        continue;
-                    }
+                Variant::Label(l) if curr.variant == Variant::Jump(l.clone()) => {
+                    curr = next;
+                    continue;
                 }
                 _ => {}
             }
@@ -55,3 +55,19 @@ impl Instructions {
         }
     }
 }
+
+impl Cfg {
+    // The passes over SSA form that -O runs. Folding again after strength
+    // reduction settles the products it moves before loops.
+    pub fn optimize(&mut self) -> Result<(), Error> {
+        for f in &mut self.functions {
+            f.fold_constants()?;
+            f.number_values();
+            f.hoist_invariants()?;
+            f.reduce_strength()?;
+            f.fold_constants()?;
+            f.remove_dead_code()?;
+        }
+        Ok(())
+    }
+}
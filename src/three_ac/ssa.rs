@@ -0,0 +1,551 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::error::Error;
+
+use super::cfg::{BasicBlock, Cfg, Function, Visit};
+use super::instruction::{Instruction, Set, Variant};
+use super::label::{Block, Label};
+use super::operand::{self, Operand};
+
+// The registers of a type the allocator has for temps and the variables it
+// does not pin, in the smallest table.
+const ROOM: usize = 4;
+
+// Numbers for new temps, above those the function already uses.
+pub struct Fresh {
+    regular: u32,
+    float: u32,
+}
+
+impl Fresh {
+    pub fn new(function: &mut Function) -> Self {
+        let mut fresh = Self {
+            regular: 0,
+            float: 0,
+        };
+        for b in &mut function.blocks {
+            for i in &mut b.instructions {
+                for o in i.operands_mut() {
+                    match o.variant {
+                        operand::Variant::Temp(n) => fresh.regular = fresh.regular.max(n),
+                        operand::Variant::TempFloat(n) => fresh.float = fresh.float.max(n),
+                        _ => {}
+                    }
+                }
+            }
+        }
+        fresh
+    }
+
+    pub fn temp(&mut self, otype: operand::Type) -> Operand {
+        let variant = match otype {
+            operand::Type::T => {
+                self.regular += 1;
+                operand::Variant::Temp(self.regular)
+            }
+            operand::Type::F => {
+                self.float += 1;
+                operand::Variant::TempFloat(self.float)
+            }
+        };
+        Operand { variant, otype }
+    }
+}
+
+impl Cfg {
+    pub fn enter_ssa(&mut self) {
+        for f in &mut self.functions {
+            f.enter_ssa();
+        }
+    }
+
+    pub fn leave_ssa(&mut self) -> Result<(), Error> {
+        for f in &mut self.functions {
+            f.leave_ssa()?;
+        }
+        Ok(())
+    }
+}
+
+impl Function {
+    // Put the function in SSA form. Every assignment to a temp or to a local
+    // whose address is never taken gets a new temp, and phis merge them where
+    // control flow joins. A use no assignment reaches reads the local itself,
+    // as arguments do. Globals stay in memory, since calls and stores through
+    // pointers may change them.
+    pub fn enter_ssa(&mut self) {
+        let aliased = self.address_taken();
+        let renamed = |o: &Operand| match o.variant {
+            operand::Variant::Temp(_) | operand::Variant::TempFloat(_) => true,
+            operand::Variant::Local(_) => !aliased.contains(&o.variant),
+            _ => false,
+        };
+
+        // Only variables used in some block before being assigned there can
+        // need a phi. Also find the blocks assigning each variable.
+        let mut live = Vec::new();
+        let mut assigned: HashMap<Operand, Vec<usize>> = HashMap::new();
+        for (k, b) in self.blocks.iter_mut().enumerate() {
+            let mut killed = HashSet::new();
+            for i in &mut b.instructions {
+                for u in i.uses_mut() {
+                    if renamed(u) && !killed.contains(u) && !live.contains(u) {
+                        live.push(*u);
+                    }
+                }
+                if let Some(d) = i.def_mut().filter(|d| renamed(d)) {
+                    killed.insert(*d);
+                    let blocks = assigned.entry(*d).or_default();
+                    if blocks.last() != Some(&k) {
+                        blocks.push(k);
+                    }
+                }
+            }
+        }
+
+        // The entry block has no predecessors, as only calls reach it, so the
+        // frontiers hold every join that needs a phi.
+        let dominators = self.dominators();
+        let frontiers = self.frontiers(&dominators);
+        for v in live {
+            let mut work = assigned.get(&v).cloned().unwrap_or_default();
+            let mut placed = HashSet::new();
+            while let Some(b) = work.pop() {
+                for &f in &frontiers[b] {
+                    if !placed.insert(f) {
+                        continue;
+                    }
+                    let block = &mut self.blocks[f];
+                    let args = block.predecessors.iter().map(|&p| (p, v)).collect();
+                    let at = block
+                        .instructions
+                        .iter()
+                        .take_while(|i| i.is_label())
+                        .count();
+                    block.instructions.insert(at, Instruction::phi(v, args));
+                    work.push(f);
+                }
+            }
+        }
+
+        // Rename along the dominator tree, so the newest temp of a variable
+        // on the stack is the one that reaches.
+        let mut fresh = Fresh::new(self);
+        let mut stacks: HashMap<Operand, Vec<Operand>> = HashMap::new();
+        let mut work = vec![Visit::Enter(0)];
+        while let Some(visit) = work.pop() {
+            let b = match visit {
+                Visit::Enter(b) => b,
+                Visit::Leave(defined) => {
+                    for v in defined {
+                        if let Some(stack) = stacks.get_mut(&v) {
+                            stack.pop();
+                        }
+                    }
+                    continue;
+                }
+            };
+
+            let mut defined = Vec::new();
+            for i in &mut self.blocks[b].instructions {
+                for u in i.uses_mut() {
+                    if let Some(&top) = stacks.get(u).and_then(|s| s.last()) {
+                        *u = top;
+                    }
+                }
+                if let Some(d) = i.def_mut().filter(|d| renamed(d)) {
+                    let new = fresh.temp(d.otype);
+                    stacks.entry(*d).or_default().push(new);
+                    defined.push(*d);
+                    *d = new;
+                }
+            }
+            for s in self.blocks[b].successors.clone() {
+                for i in &mut self.blocks[s].instructions {
+                    if let Variant::Phi(args) = &mut i.variant {
+                        for (_, o) in args.iter_mut().filter(|(p, _)| *p == b) {
+                            if let Some(&top) = stacks.get(o).and_then(|s| s.last()) {
+                                *o = top;
+                            }
+                        }
+                    }
+                }
+            }
+
+            work.push(Visit::Leave(defined));
+            for c in dominators.children(b).into_iter().rev() {
+                work.push(Visit::Enter(c));
+            }
+        }
+    }
+
+    // Leave SSA form. A phi becomes copies at the end of each predecessor, in
+    // a block of their own on an edge out of a branch so that they only run
+    // on that edge. Temps that then live across blocks or calls are moved to
+    // frame slots, since registers are only allocated within them.
+    pub fn leave_ssa(&mut self) -> Result<(), Error> {
+        let mut fresh = Fresh::new(self);
+        let mut copies: HashMap<(usize, usize), Vec<(Operand, Operand)>> = HashMap::new();
+        for (b, block) in self.blocks.iter_mut().enumerate() {
+            block.instructions.retain(|i| match &i.variant {
+                Variant::Phi(args) => {
+                    for &(p, o) in args {
+                        copies.entry((p, b)).or_default().push((i.opdt, o));
+                    }
+                    false
+                }
+                _ => true,
+            });
+        }
+
+        let mut reads: HashMap<Operand, usize> = HashMap::new();
+        for b in &mut self.blocks {
+            for i in &mut b.instructions {
+                for o in i.uses_mut() {
+                    *reads.entry(*o).or_default() += 1;
+                }
+            }
+        }
+        for (_, s) in copies.values().flatten() {
+            *reads.entry(*s).or_default() += 1;
+        }
+
+        let labels: Vec<Vec<Label>> = self
+            .blocks
+            .iter()
+            .map(|b| b.labels().into_iter().cloned().collect())
+            .collect();
+        let mut edges = 0;
+        let mut blocks = Vec::new();
+        let mut moved = Vec::new();
+        for (p, mut block) in std::mem::take(&mut self.blocks).into_iter().enumerate() {
+            let branches = matches!(
+                block.last_variant(),
+                Some(
+                    Variant::Equal(_)
+                        | Variant::NotEqual(_)
+                        | Variant::Less(_)
+                        | Variant::LessEqual(_)
+                        | Variant::Greater(_)
+                        | Variant::GreaterEqual(_)
+                        | Variant::JumpTable(..)
+                )
+            );
+            let mut split = None;
+            for b in block.successors.clone() {
+                let Some(pairs) = copies.remove(&(p, b)) else {
+                    continue;
+                };
+                let pairs = if branches {
+                    pairs
+                } else {
+                    coalesce(&mut block.instructions, pairs, &reads)
+                };
+                let sequence = sequentialize(pairs, &mut fresh);
+                if !branches {
+                    let jumps = matches!(block.last_variant(), Some(Variant::Jump(_)));
+                    let at = block.instructions.len() - usize::from(jumps);
+                    block.instructions.splice(at..at, sequence);
+                    continue;
+                }
+
+                edges += 1;
+                let label = Block::new(&self.name, "edge", edges).label("copy");
+                // The branch may go to any of the target's labels.
+                if let Some(i) = block.instructions.last_mut() {
+                    for target in &labels[b] {
+                        i.retarget(target, &label);
+                    }
+                }
+                let mut instructions = vec![Instruction::label(label)];
+                instructions.extend(sequence);
+                // A branch falls through when its condition holds.
+                let falls =
+                    b == p + 1 && !matches!(block.last_variant(), Some(Variant::JumpTable(..)));
+                if falls {
+                    split = Some(BasicBlock::new(instructions));
+                } else {
+                    let target = labels[b].first().cloned().ok_or_else(|| {
+                        Error::ThreeAC(format!(
+                            "leave_ssa: jump to a block without a label in {}",
+                            self.name
+                        ))
+                    })?;
+                    instructions.push(Instruction::jump(target));
+                    moved.push(BasicBlock::new(instructions));
+                }
+            }
+            blocks.push(block);
+            blocks.extend(split);
+        }
+        // Blocks that end in a jump can go anywhere.
+        blocks.extend(moved);
+        self.blocks = blocks;
+        self.connect()?;
+
+        self.assign_slots();
+        Ok(())
+    }
+
+    // Give each temp used in more than one of the stretches registers are
+    // allocated over a frame slot of its own. The result of a call is only
+    // read after the registers have been spilled for it, so it belongs to the
+    // stretch after the call.
+    fn assign_slots(&mut self) {
+        let mut first = HashMap::new();
+        let mut spread = Vec::new();
+        let mut stretch = 0;
+        for b in &mut self.blocks {
+            stretch += 1;
+            for i in &mut b.instructions {
+                if matches!(i.variant, Variant::Call(..) | Variant::CallIndirect(_)) {
+                    for u in i.uses_mut() {
+                        note(*u, stretch, &mut first, &mut spread);
+                    }
+                    stretch += 1;
+                    note(i.opdt, stretch, &mut first, &mut spread);
+                    continue;
+                }
+                for o in i.operands_mut() {
+                    note(*o, stretch, &mut first, &mut spread);
+                }
+            }
+        }
+        self.relieve(&mut spread);
+        if spread.is_empty() {
+            return;
+        }
+        let interfering = self.interference(&spread);
+
+        let entry = &mut self.blocks[0].instructions;
+        let at = entry.iter().take_while(|i| i.is_label()).count();
+        if !matches!(entry.get(at).map(|i| &i.variant), Some(Variant::Alloc(_))) {
+            entry.insert(at, Instruction::alloc(0));
+        }
+        let Variant::Alloc(size) = &mut entry[at].variant else {
+            unreachable!("assign_slots: no frame allocation");
+        };
+        // Temps of a type that are never live at once share a slot.
+        let mut slots = HashMap::new();
+        let mut shared: Vec<(Operand, Vec<Operand>)> = Vec::new();
+        for t in spread {
+            let free = shared.iter_mut().find(|(slot, temps)| {
+                slot.otype == t.otype && temps.iter().all(|u| !interfering.contains(&(t, *u)))
+            });
+            let slot = match free {
+                Some((slot, temps)) => {
+                    temps.push(t);
+                    *slot
+                }
+                None => {
+                    *size += 4;
+                    let slot = Operand {
+                        variant: operand::Variant::Local(-(*size as i32)),
+                        otype: t.otype,
+                    };
+                    shared.push((slot, vec![t]));
+                    slot
+                }
+            };
+            slots.insert(t, slot);
+        }
+
+        for b in &mut self.blocks {
+            for i in &mut b.instructions {
+                for o in i.operands_mut() {
+                    if let Some(slot) = slots.get(o) {
+                        *o = *slot;
+                    }
+                }
+            }
+        }
+    }
+
+    // The pairs of temps, both ways round, where one is written while the
+    // other is read later.
+    fn interference(&mut self, temps: &[Operand]) -> HashSet<(Operand, Operand)> {
+        let live_out = self.live_out(|o| temps.contains(o));
+        let mut pairs = HashSet::new();
+        for (b, block) in self.blocks.iter_mut().enumerate() {
+            let mut live = live_out[b].clone();
+            for i in block.instructions.iter_mut().rev() {
+                if let Some(&mut d) = i.def_mut().filter(|d| temps.contains(d)) {
+                    live.remove(&d);
+                    for &l in &live {
+                        pairs.insert((d, l));
+                        pairs.insert((l, d));
+                    }
+                }
+                live.extend(
+                    i.uses_mut()
+                        .into_iter()
+                        .filter(|u| temps.contains(u))
+                        .map(|u| *u),
+                );
+            }
+        }
+        pairs
+    }
+
+    // Temps stay in their registers until last read, so where an instruction
+    // would need more registers than there are room for, the temps held
+    // across it that are read again last go to frame slots as well.
+    fn relieve(&mut self, spread: &mut Vec<Operand>) {
+        for b in &mut self.blocks {
+            let mut first = HashMap::new();
+            let mut reads: HashMap<Operand, Vec<usize>> = HashMap::new();
+            let mut steps = Vec::new();
+            for (k, i) in b.instructions.iter_mut().enumerate() {
+                let mut used = Vec::new();
+                for o in i.uses_mut() {
+                    if is_temp(o) {
+                        reads.entry(*o).or_default().push(k);
+                    }
+                    if (is_temp(o) || o.is_variable()) && !used.contains(o) {
+                        used.push(*o);
+                    }
+                }
+                let def = i.def_mut().copied();
+                if let Some(d) = def.filter(is_temp) {
+                    first.entry(d).or_insert(k);
+                }
+                let calls = matches!(i.variant, Variant::Call(..) | Variant::CallIndirect(_));
+                steps.push((used, def, calls));
+            }
+
+            for (k, (used, def, calls)) in steps.iter().enumerate() {
+                if *calls {
+                    continue;
+                }
+                for otype in [operand::Type::T, operand::Type::F] {
+                    loop {
+                        let held: Vec<Operand> = reads
+                            .iter()
+                            .filter(|(t, r)| {
+                                t.otype == otype
+                                    && !spread.contains(t)
+                                    && first.get(*t).is_some_and(|&d| d < k)
+                                    && r.last().is_some_and(|&l| l > k)
+                            })
+                            .map(|(t, _)| *t)
+                            .collect();
+                        let read = used.iter().filter(|o| o.otype == otype);
+                        let before = held.len() + read.filter(|o| !held.contains(o)).count();
+                        let after = held.len()
+                            + usize::from(def.is_some_and(|d| {
+                                d.otype == otype && (is_temp(&d) || d.is_variable())
+                            }));
+                        if before <= ROOM && after <= ROOM {
+                            break;
+                        }
+                        let next = |t: &Operand| reads[t].iter().find(|&&r| r > k).copied();
+                        let Some(t) = held
+                            .into_iter()
+                            .filter(|t| !used.contains(t))
+                            .max_by_key(|t| next(t))
+                        else {
+                            break;
+                        };
+                        spread.push(t);
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn is_temp(o: &Operand) -> bool {
+    matches!(
+        o.variant,
+        operand::Variant::Temp(_) | operand::Variant::TempFloat(_)
+    )
+}
+
+// Record that a temp is used in a stretch, and whether it already was in
+// another one.
+fn note(
+    o: Operand,
+    stretch: usize,
+    first: &mut HashMap<Operand, usize>,
+    spread: &mut Vec<Operand>,
+) {
+    if !matches!(
+        o.variant,
+        operand::Variant::Temp(_) | operand::Variant::TempFloat(_)
+    ) {
+        return;
+    }
+    match first.get(&o) {
+        Some(&s) if s != stretch && !spread.contains(&o) => spread.push(o),
+        Some(_) => {}
+        None => {
+            first.insert(o, stretch);
+        }
+    }
+}
+
+// Let the instruction that computes the value a copy at the end of the block
+// moves write the destination instead, saving the copy, when the value is
+// read nowhere else and the destination's old value is not read after it.
+fn coalesce(
+    instructions: &mut [Instruction],
+    pairs: Vec<(Operand, Operand)>,
+    reads: &HashMap<Operand, usize>,
+) -> Vec<(Operand, Operand)> {
+    let sources: Vec<Operand> = pairs.iter().map(|(_, s)| *s).collect();
+    let mut kept = Vec::new();
+    for (d, s) in pairs {
+        let found = instructions
+            .iter_mut()
+            .position(|i| i.def_mut().is_some_and(|o| *o == s));
+        let Some(k) = found.filter(|_| is_temp(&s) && d != s && !sources.contains(&d)) else {
+            kept.push((d, s));
+            continue;
+        };
+        let mut later = 1;
+        let mut clobbers = false;
+        for i in &mut instructions[k + 1..] {
+            later += i.uses_mut().into_iter().filter(|o| **o == s).count();
+            clobbers |= i.operands_mut().into_iter().any(|o| *o == d);
+        }
+        if clobbers || reads.get(&s) != Some(&later) {
+            kept.push((d, s));
+            continue;
+        }
+        for i in &mut instructions[k..] {
+            for o in i.operands_mut() {
+                if *o == s {
+                    *o = d;
+                }
+            }
+        }
+    }
+    kept
+}
+
+// Order copies that happen at once so that none overwrites a value another
+// still reads, breaking cycles with a new temp.
+fn sequentialize(mut pending: Vec<(Operand, Operand)>, fresh: &mut Fresh) -> Vec<Instruction> {
+    pending.retain(|(d, s)| d != s);
+    let mut sequence = Vec::new();
+    while !pending.is_empty() {
+        match pending
+            .iter()
+            .position(|(d, _)| !pending.iter().any(|(_, s)| s == d))
+        {
+            Some(k) => {
+                let (d, s) = pending.remove(k);
+                sequence.push(Instruction::assign(Set::from_type(d.otype), d, s));
+            }
+            None => {
+                let d = pending[0].0;
+                let t = fresh.temp(d.otype);
+                sequence.push(Instruction::assign(Set::from_type(d.otype), t, d));
+                for (_, s) in pending.iter_mut().filter(|(_, s)| *s == d) {
+                    *s = t;
+                }
+            }
+        }
+    }
+    sequence
+}
@@ -0,0 +1,159 @@
+use std::collections::HashMap;
+
+use crate::error::Error;
+use crate::symtable::ARG_OFFSET;
+
+use super::cfg::{Cfg, Function};
+use super::instruction::{Instruction, Set, Variant};
+use super::label::{Block, Label};
+use super::operand::{self, Operand};
+use super::ssa::Fresh;
+
+// Where the first argument of a call is in the frame of the function called.
+const ARGUMENTS: i32 = ARG_OFFSET + 4;
+
+impl Cfg {
+    // Calls whose result is returned as it is need not come back. A function
+    // calling itself so starts over with the new arguments, which makes the
+    // recursion a loop, and a call to another one of the program's functions
+    // hands it the frame, when its arguments fit where the caller's were.
+    // Functions that take the address of a local keep their calls, since a
+    // pointer into the frame may be among the arguments.
+    pub fn eliminate_tail_calls(&mut self) -> Result<(), Error> {
+        let arguments: HashMap<String, usize> = self
+            .functions
+            .iter()
+            .map(|f| (f.name.clone(), f.arguments()))
+            .collect();
+        for f in &mut self.functions {
+            let frame_escapes = f
+                .address_taken()
+                .iter()
+                .any(|v| matches!(v, operand::Variant::Local(_)));
+            if !frame_escapes {
+                f.eliminate_tail_calls(&arguments)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Function {
+    // How many arguments the function reads, as far as it reads them.
+    fn arguments(&self) -> usize {
+        self.blocks
+            .iter()
+            .flat_map(|b| &b.instructions)
+            .flat_map(|i| [i.opdt, i.opm, i.opn])
+            .filter_map(|o| match o.variant {
+                operand::Variant::Local(i @ ARGUMENTS..) => Some((i - ARGUMENTS) as usize / 4 + 1),
+                _ => None,
+            })
+            .max()
+            .unwrap_or(0)
+    }
+
+    fn eliminate_tail_calls(&mut self, arguments: &HashMap<String, usize>) -> Result<(), Error> {
+        let mut instructions: Vec<Instruction> = self
+            .blocks
+            .iter()
+            .flat_map(|b| b.instructions.clone())
+            .collect();
+        let tail = Label::FunctionTail(self.name.clone());
+        let start = Block::new(&self.name, "recursion", 1).label("start");
+        let own = arguments.get(&self.name).copied().unwrap_or(0);
+
+        let mut fresh = Fresh::new(self);
+        let mut recursive = false;
+        let mut changed = false;
+        let mut k = 0;
+        while k < instructions.len() {
+            let Some(length) = returned(&instructions[k..], &tail) else {
+                k += 1;
+                continue;
+            };
+            let call = &instructions[k];
+            let Variant::Call(Label::FunctionHead(g), args) = &call.variant else {
+                unreachable!("eliminate_tail_calls: not a call");
+            };
+            let span = call.span;
+            let mut replacement = if *g == self.name {
+                recursive = true;
+                restart(args, &start, &mut fresh)
+            } else if arguments.contains_key(g) && args.len() <= own {
+                let label = Label::FunctionHead(g.clone());
+                vec![Instruction::tail_call(label, args.clone())]
+            } else {
+                k += 1;
+                continue;
+            };
+            for i in &mut replacement {
+                i.span = span;
+            }
+            changed = true;
+            let count = replacement.len();
+            instructions.splice(k..k + length, replacement);
+            k += count;
+        }
+        if !changed {
+            return Ok(());
+        }
+
+        // The loop starts after the frame is allocated, in a block of its own
+        // so that the entry block has no predecessors.
+        if recursive {
+            let at = instructions.iter().take_while(|i| i.is_label()).count();
+            if !matches!(
+                instructions.get(at).map(|i| &i.variant),
+                Some(Variant::Alloc(_))
+            ) {
+                instructions.insert(at, Instruction::alloc(0));
+            }
+            instructions.insert(at + 1, Instruction::label(start));
+        }
+        *self = Function::new(self.name.clone(), instructions)?;
+        Ok(())
+    }
+}
+
+// How many instructions from a call at the start of instructions on make up
+// returning its result, if that is all they do: the call, saving its result
+// unless there is none, and a jump to the end of the function unless it
+// comes right after.
+fn returned(instructions: &[Instruction], tail: &Label) -> Option<usize> {
+    let call = instructions.first()?;
+    if !matches!(call.variant, Variant::Call(Label::FunctionHead(_), _)) {
+        return None;
+    }
+    let mut length = 1;
+    if call.opdt.variant != operand::Variant::Null {
+        match instructions.get(length) {
+            Some(i) if i.variant == Variant::Save && i.opdt == call.opdt => length += 1,
+            _ => return None,
+        }
+    }
+    match instructions.get(length).map(|i| &i.variant) {
+        Some(Variant::Jump(l)) if l == tail => Some(length + 1),
+        Some(Variant::Label(l)) if l == tail => Some(length),
+        _ => None,
+    }
+}
+
+// Set the arguments to those of the call, all read before any is written,
+// and go back to the start.
+fn restart(args: &[Operand], start: &Label, fresh: &mut Fresh) -> Vec<Instruction> {
+    let mut read = Vec::new();
+    let mut write = Vec::new();
+    for (k, a) in args.iter().enumerate() {
+        let t = fresh.temp(a.otype);
+        let slot = Operand {
+            variant: operand::Variant::Local(ARGUMENTS + 4 * k as i32),
+            otype: a.otype,
+        };
+        read.push(Instruction::assign(Set::from_type(a.otype), t, *a));
+        write.push(Instruction::assign(Set::from_type(a.otype), slot, t));
+    }
+    read.extend(write);
+    read.push(Instruction::jump(start.clone()));
+    read
+}
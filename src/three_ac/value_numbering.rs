@@ -0,0 +1,180 @@
+use std::collections::{HashMap, HashSet};
+
+use super::cfg::{Function, Visit};
+use super::instruction::{Instruction, Set, Variant};
+use super::operand::{self, Operand};
+
+// What an operand holds. Globals and variables whose address is taken hold a
+// value only until something may write them, which the stamp tells apart.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+enum Value {
+    Constant(operand::Type, String),
+    Operand(Operand),
+    Memory(Operand, u32),
+}
+
+// A computation by the values it works on. Loads through a pointer also
+// depend on what memory held.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+struct Expression {
+    variant: Variant,
+    otype: operand::Type,
+    m: Value,
+    n: Value,
+    stamp: Option<u32>,
+}
+
+impl Function {
+    // Reuse the result of an earlier computation of the same value instead
+    // of computing it again, arithmetic and loads through pointers alike. In
+    // SSA form a temp holds its value wherever its definition dominates, so
+    // the blocks below one in the dominator tree can reuse what it computed.
+    // Stores through pointers and calls may write memory, so what was read
+    // from it before is not reused after them.
+    pub fn number_values(&mut self) {
+        let aliased = self.address_taken();
+        let dominators = self.dominators();
+        let mut values = HashMap::new();
+        let mut available: HashMap<Expression, Operand> = HashMap::new();
+        let mut replaced = HashMap::new();
+        let mut ends = vec![0; self.blocks.len()];
+        let mut stamps = 0;
+
+        let mut work = vec![Visit::Enter(0)];
+        while let Some(visit) = work.pop() {
+            let b = match visit {
+                Visit::Enter(b) => b,
+                Visit::Leave(added) => {
+                    for e in added {
+                        available.remove(&e);
+                    }
+                    continue;
+                }
+            };
+
+            // Memory is only as the immediate dominator left it when that
+            // is the one way in.
+            let mut stamp = match dominators.idom(b) {
+                Some(d) if self.blocks[b].predecessors == [d] => ends[d],
+                _ => {
+                    stamps += 1;
+                    stamps
+                }
+            };
+            let mut added = Vec::new();
+            for i in &mut self.blocks[b].instructions {
+                let temp = is_temp(&i.opdt);
+                match &i.variant {
+                    Variant::Load(lit) if temp => {
+                        values.insert(i.opdt, Value::Constant(i.opdt.otype, lit.clone()));
+                    }
+                    Variant::Assign if temp => {
+                        values.insert(i.opdt, value(&i.opm, &values, &aliased, stamp));
+                    }
+                    Variant::Plus
+                    | Variant::Minus
+                    | Variant::Times
+                    | Variant::Address
+                    | Variant::Dereference => {
+                        let e = Expression {
+                            variant: i.variant.clone(),
+                            otype: i.opdt.otype,
+                            m: value(&i.opm, &values, &aliased, stamp),
+                            n: value(&i.opn, &values, &aliased, stamp),
+                            stamp: matches!(i.variant, Variant::Address | Variant::Dereference)
+                                .then_some(stamp),
+                        };
+                        let swapped = Expression {
+                            m: e.n.clone(),
+                            n: e.m.clone(),
+                            ..e.clone()
+                        };
+                        let commutes = matches!(i.variant, Variant::Plus | Variant::Times);
+                        let found = available
+                            .get(&e)
+                            .or_else(|| available.get(&swapped).filter(|_| commutes))
+                            .copied();
+                        match found {
+                            Some(r) if temp => {
+                                values.insert(i.opdt, value(&r, &values, &aliased, stamp));
+                                replaced.insert(i.opdt, r);
+                            }
+                            Some(r) => {
+                                let mut assign =
+                                    Instruction::assign(Set::from_type(i.opdt.otype), i.opdt, r);
+                                assign.span = i.span;
+                                *i = assign;
+                            }
+                            None if temp => {
+                                available.insert(e.clone(), i.opdt);
+                                added.push(e);
+                            }
+                            None => {}
+                        }
+                    }
+                    _ => {}
+                }
+
+                let writes = matches!(
+                    i.variant,
+                    Variant::AddrAssign | Variant::Call(..) | Variant::CallIndirect(_)
+                ) || i.def_mut().is_some_and(|d| in_memory(d, &aliased));
+                if writes {
+                    stamps += 1;
+                    stamp = stamps;
+                }
+            }
+            ends[b] = stamp;
+
+            work.push(Visit::Leave(added));
+            for c in dominators.children(b).into_iter().rev() {
+                work.push(Visit::Enter(c));
+            }
+        }
+
+        if replaced.is_empty() {
+            return;
+        }
+        for b in &mut self.blocks {
+            b.instructions
+                .retain_mut(|i| !i.def_mut().is_some_and(|d| replaced.contains_key(d)));
+            for i in &mut b.instructions {
+                for o in i.operands_mut() {
+                    if let Some(r) = replaced.get(o) {
+                        *o = *r;
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn value(
+    o: &Operand,
+    values: &HashMap<Operand, Value>,
+    aliased: &HashSet<operand::Variant>,
+    stamp: u32,
+) -> Value {
+    match values.get(o) {
+        Some(v) => v.clone(),
+        None if in_memory(o, aliased) => Value::Memory(*o, stamp),
+        None => Value::Operand(*o),
+    }
+}
+
+// Whether the operand is in memory that stores and calls may change. Other
+// locals are only assigned through the temps SSA form gives them.
+fn in_memory(o: &Operand, aliased: &HashSet<operand::Variant>) -> bool {
+    match o.variant {
+        operand::Variant::Global(_) => true,
+        operand::Variant::Local(_) => aliased.contains(&o.variant),
+        _ => false,
+    }
+}
+
+fn is_temp(o: &Operand) -> bool {
+    matches!(
+        o.variant,
+        operand::Variant::Temp(_) | operand::Variant::TempFloat(_)
+    )
+}
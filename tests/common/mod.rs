@@ -0,0 +1,19 @@
+use std::fs;
+use std::path::Path;
+use std::process::{Command, Output};
+
+// Write a file for a test to the directory cargo keeps for them, returning
+// its path.
+pub fn source(name: &str, text: &str) -> String {
+    let path = Path::new(env!("CARGO_TARGET_TMPDIR")).join(name);
+    fs::write(&path, text).unwrap();
+    path.to_str().unwrap().to_owned()
+}
+
+// Run the compiler with the arguments.
+pub fn project(args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_project"))
+        .args(args)
+        .output()
+        .unwrap()
+}
@@ -0,0 +1,137 @@
+mod common;
+
+use common::{project, source};
+
+fn error(path: &str) -> (Option<i32>, String) {
+    let output = project(&[path, "8"]);
+    let stderr = String::from_utf8_lossy(&output.stderr).into_owned();
+    (output.status.code(), stderr)
+}
+
+#[test]
+fn errors_in_included_files_point_at_them() {
+    source("diag_header.h", "int f(int a) {\n  return b;\n}\n");
+    let path = source(
+        "diag_include.c",
+        "#include \"diag_header.h\"\nvoid main() {\n  print(f(1));\n}\n",
+    );
+    let (_, stderr) = error(&path);
+    assert!(
+        stderr.contains("diag_header.h:2:3: get_symbol: b is used before its declaration"),
+        "{stderr}"
+    );
+}
+
+#[test]
+fn errors_point_past_removed_lines() {
+    let path = source(
+        "diag_lines.c",
+        "// removed\n#define N 1\nvoid main() {\n  int x;\n  int x;\n  x = N;\n}\n",
+    );
+    let (_, stderr) = error(&path);
+    assert!(stderr.contains("diag_lines.c:5:3: add_symbol:"), "{stderr}");
+}
+
+#[test]
+fn type_errors_keep_their_exit_code() {
+    let path = source(
+        "diag_type.c",
+        "// removed\nvoid main() {\n  int x;\n  float *p;\n  x = p;\n}\n",
+    );
+    let (code, stderr) = error(&path);
+    assert_eq!(code, Some(7));
+    assert!(stderr.contains("diag_type.c:5:3: TYPE ERROR"), "{stderr}");
+}
+
+#[test]
+fn switches_reject_repeated_and_non_constant_cases() {
+    let head = "int main() {\n  int x;\n  x = 1;\n  switch (x) {\n";
+    let tail = "  }\n  return 0;\n}\n";
+    for (name, cases, message) in [
+        (
+            "diag_case.c",
+            "  case 1: x = 2;\n  case 1: x = 3;\n",
+            "diag_case.c:4:3: from_statement: duplicate case 1 in switch",
+        ),
+        (
+            "diag_default.c",
+            "  default: x = 2;\n  default: x = 3;\n",
+            "diag_default.c:4:3: from_statement: duplicate default in switch",
+        ),
+        (
+            "diag_variable_case.c",
+            "  case x: x = 2;\n",
+            "from_case_label: case x is not an integer constant",
+        ),
+        (
+            "diag_float_case.c",
+            "  case 1.5: x = 2;\n",
+            "from_case_label: case 1.5 is not an integer constant",
+        ),
+    ] {
+        let path = source(name, &format!("{head}{cases}{tail}"));
+        let (code, stderr) = error(&path);
+        assert_eq!(code, Some(1), "{name}");
+        assert!(stderr.contains(message), "{name}: {stderr}");
+    }
+}
+
+#[test]
+fn breaks_must_be_inside_a_loop_or_switch() {
+    // A break in a function called from a loop does not leave that loop.
+    let path = source(
+        "diag_break.c",
+        "void f() {\n  break;\n}\nint main() {\n  int x;\n  x = 0;\n  while (x < 1) {\n    f();\n  }\n  return 0;\n}\n",
+    );
+    let (code, stderr) = error(&path);
+    assert_eq!(code, Some(1));
+    assert!(
+        stderr.contains("diag_break.c:2:3: from_break: break outside of a loop or switch"),
+        "{stderr}"
+    );
+}
+
+#[test]
+fn ternary_arms_must_have_compatible_types() {
+    for (name, arms) in [
+        ("diag_ternary_float.c", "p : 2.5"),
+        ("diag_ternary_pointers.c", "p : q"),
+    ] {
+        let path = source(
+            name,
+            &format!(
+                "int main() {{\n  int x;\n  int *p;\n  float *q;\n  x = 1;\n  p = x > 0 ? {arms};\n  return 0;\n}}\n"
+            ),
+        );
+        let (code, stderr) = error(&path);
+        assert_eq!(code, Some(7), "{name}");
+        assert!(
+            stderr.contains(&format!("{name}:6:3: TYPE ERROR")),
+            "{stderr}"
+        );
+    }
+}
+
+#[test]
+fn function_pointers_must_match_what_they_call() {
+    for (name, statement) in [
+        ("diag_signature.c", "f = scale"),
+        ("diag_arity.c", "x = f(1, 2)"),
+        ("diag_parameter.c", "x = f(p)"),
+        ("diag_not_function.c", "x = x(1)"),
+        ("diag_passed_function.c", "x = apply(scale, 1)"),
+    ] {
+        let path = source(
+            name,
+            &format!(
+                "float scale(float x, int k) {{\n  return x * k;\n}}\nint apply(int (*f)(int), int x) {{\n  return f(x);\n}}\nint main() {{\n  int (*f)(int);\n  int *p;\n  int x;\n  x = 1;\n  {statement};\n  return 0;\n}}\n"
+            ),
+        );
+        let (code, stderr) = error(&path);
+        assert_eq!(code, Some(7), "{name}");
+        assert!(
+            stderr.contains(&format!("{name}:12:3: TYPE ERROR")),
+            "{stderr}"
+        );
+    }
+}
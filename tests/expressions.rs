@@ -0,0 +1,137 @@
+mod common;
+mod machine;
+
+use machine::check;
+
+// Ternaries nested in either arm, with int and float arms made float, pointer
+// arms and NULL, only the chosen arm evaluated, and used as conditions and
+// arguments.
+const TERNARIES: &str = "
+int calls;
+int count(int x) {
+    calls = calls + 1;
+    return x;
+}
+int sign(int x) {
+    return x < 0 ? -1 : x == 0 ? 0 : 1;
+}
+int clamp(int x, int lo, int hi) {
+    return x < lo ? lo : (x > hi ? hi : x);
+}
+float half(int x) {
+    return x > 0 ? x / 2 : 0.5;
+}
+int main() {
+    int x;
+    float f;
+    int a;
+    int b;
+    int *p;
+    int *q;
+    read(x);
+    read(f);
+    a = 10;
+    b = 20;
+    print(sign(x));
+    print(clamp(x, -3, 3));
+    print(half(x));
+    print(f > 1.5 ? f : x);
+    print(x > 0 ? count(1) : count(2));
+    print(calls);
+    p = x > 0 ? &a : &b;
+    print(*p);
+    q = x > 0 ? NULL : p;
+    print(q == NULL ? 1 : 0);
+    print(*(x < 0 ? &a : p));
+    if ((x > 0 ? a : b) > 15) {
+        print(100);
+    }
+    print(clamp(x > 0 ? x * 10 : x, x < 0 ? x : -50, 50));
+    return 0;
+}
+";
+
+#[test]
+fn ternaries_run_as_in_c() {
+    check(
+        "ternaries",
+        TERNARIES,
+        &[
+            (&["-5", "2.5"], "-1 -3 0.5 2.5 2 1 20 0 10 100 -5"),
+            (&["0", "1"], "0 0 0.5 0 2 1 20 0 20 100 0"),
+            (&["7", "-0.25"], "1 3 3 7 1 1 10 1 10 50"),
+        ],
+    );
+}
+
+// Functions taken with and without &, called through variables, parameters,
+// tables and ternaries, with float and void signatures, and compared.
+const POINTERS: &str = "
+int twice(int x) {
+    return 2 * x;
+}
+int square(int x) {
+    return x * x;
+}
+int negate(int x) {
+    return -x;
+}
+float scale(float x, int k) {
+    return x * k;
+}
+int total;
+void add(int x) {
+    total = total + x;
+}
+int apply(int (*f)(int), int x) {
+    return f(f(x));
+}
+int main() {
+    int (*f)(int);
+    int (**table)(int);
+    float (*g)(float, int);
+    void (*h)(int);
+    int x;
+    int i;
+    read(x);
+    f = twice;
+    print(f(x));
+    f = &square;
+    print(f(x));
+    print(apply(f, x));
+    print(apply(negate, x));
+    table = malloc(3 * sizeof(int (*)(int)));
+    table[0] = twice;
+    table[1] = square;
+    table[2] = negate;
+    i = 0;
+    while (i < 3) {
+        print(table[i](x + i));
+        i = i + 1;
+    }
+    g = scale;
+    print(g(1.5, x));
+    h = add;
+    h(x);
+    h(x + 1);
+    print(total);
+    print(f == square ? 1 : 0);
+    f = NULL;
+    print(f == NULL ? 1 : 0);
+    print((x > 0 ? twice : negate)(5));
+    return 0;
+}
+";
+
+#[test]
+fn calls_through_function_pointers_run_as_in_c() {
+    check(
+        "function_pointers",
+        POINTERS,
+        &[
+            (&["-3"], "-6 9 81 -3 -6 4 1 -4.5 -5 1 1 -5"),
+            (&["0"], "0 0 0 0 0 1 -2 0 1 1 1 -5"),
+            (&["4"], "8 16 256 4 8 25 -6 6 9 1 1 10"),
+        ],
+    );
+}
@@ -0,0 +1,189 @@
+mod common;
+mod machine;
+
+use common::{project, source};
+
+fn module(name: &str, text: &str, flags: &[&str]) -> String {
+    let path = source(name, text);
+    let mut args = vec!["-c"];
+    args.extend(flags);
+    args.extend([path.as_str(), "8"]);
+    let output = project(&args);
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    source(
+        &format!("{name}.o"),
+        &String::from_utf8_lossy(&output.stdout),
+    )
+}
+
+#[test]
+fn functions_may_be_named_like_instructions() {
+    let a = module(
+        "link_opcodes_a.c",
+        "int MV(int a) { return a + 1; }\nint RET(int a) { return MV(a) * 2; }\n",
+        &[],
+    );
+    let b = module(
+        "link_opcodes_b.c",
+        "int RET(int a);\nint J(int a) { return RET(a); }\nint main() { print(J(3)); return 0; }\n",
+        &[],
+    );
+    let output = project(&["-l", &a, &b]);
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    let program = String::from_utf8_lossy(&output.stdout);
+
+    // Only labels and targets are renamed, never the instruction itself.
+    for line in program.lines() {
+        let first = line.split_whitespace().next().unwrap_or("");
+        assert!(
+            !first.starts_with("m0_") && !first.starts_with("m1_") || first.ends_with(':'),
+            "renamed instruction: {line}"
+        );
+    }
+    for line in [
+        "m0_MV:",
+        "m0_RET:",
+        "m1_J:",
+        "JR m0_MV",
+        "JR m0_RET",
+        "JR m1_J",
+        "MV fp, sp",
+        "RET",
+    ] {
+        assert!(program.lines().any(|l| l == line), "missing {line}");
+    }
+}
+
+// Globals, strings and functions defined in one module and used through
+// extern declarations and prototypes in the others.
+const DEFINES: &str = "
+int counter;
+float scale;
+string hello = \"hello\";
+int bump(int by) {
+    counter = counter + by;
+    return counter;
+}
+void greet() {
+    print(hello);
+}
+";
+const USES: &str = "
+extern int counter;
+extern float scale;
+int bump(int by);
+string bye = \"bye\";
+int twice(int x) {
+    return bump(x) + bump(x);
+}
+float scaled(int x) {
+    return scale * x;
+}
+void farewell() {
+    print(bye);
+}
+";
+const MAIN: &str = "
+extern int counter;
+extern float scale;
+int bump(int by);
+int twice(int x);
+float scaled(int x);
+void greet();
+void farewell();
+int main() {
+    int x;
+    int (*f)(int);
+    read(x);
+    scale = 1.5;
+    greet();
+    print(twice(x));
+    f = bump;
+    print(f(1));
+    print(counter);
+    print(scaled(x));
+    farewell();
+    return 0;
+}
+";
+
+const RUNS: [(&[&str], &str); 2] = [
+    (&["3"], "hello 9 7 7 4.5 bye"),
+    (&["-2"], "hello -6 -3 -3 -3 bye"),
+];
+
+#[test]
+fn linked_modules_share_globals_strings_and_functions() {
+    for flags in [&[][..], &["-O"]] {
+        let modules = [
+            module("link_defines.c", DEFINES, flags),
+            module("link_uses.c", USES, flags),
+            module("link_main.c", MAIN, flags),
+        ];
+        let mut args = vec!["-l"];
+        args.extend(modules.iter().map(String::as_str));
+        let output = project(&args);
+        assert!(
+            output.status.success(),
+            "{}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+        let program = String::from_utf8_lossy(&output.stdout);
+        for (input, expected) in RUNS {
+            assert_eq!(
+                machine::run(&program, input),
+                expected,
+                "{flags:?} {input:?}"
+            );
+        }
+    }
+}
+
+#[test]
+fn linked_modules_run_like_the_same_code_in_one_file() {
+    machine::check("link_whole", &format!("{DEFINES}{USES}{MAIN}"), &RUNS);
+}
+
+#[test]
+fn symbols_must_be_defined_once_with_one_type() {
+    let main = module(
+        "link_bad_main.c",
+        "extern int counter;\nint bump(int by);\nint main() { print(bump(counter)); return 0; }\n",
+        &[],
+    );
+    let float_counter = module(
+        "link_float_counter.c",
+        "float counter;\nint bump(int by) { return by; }\n",
+        &[],
+    );
+    let no_bump = module("link_no_bump.c", "int counter;\n", &[]);
+    let no_main = module("link_no_main.c", "int counter;\n", &[]);
+    let defines = module("link_good_defines.c", DEFINES, &[]);
+    for (modules, message) in [
+        (vec![&main, &float_counter], "link: counter is int in"),
+        (
+            vec![&main, &no_bump],
+            "link: undefined reference to bump in",
+        ),
+        (
+            vec![&main, &defines, &defines],
+            "link: multiple definitions of",
+        ),
+        (vec![&no_main], "link: main is not defined"),
+    ] {
+        let mut args = vec!["-l"];
+        args.extend(modules.into_iter().map(String::as_str));
+        let output = project(&args);
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert!(!output.status.success(), "{message}");
+        assert!(stderr.contains(message), "{message}: {stderr}");
+    }
+}
@@ -0,0 +1,87 @@
+mod common;
+mod machine;
+
+use common::{project, source};
+use machine::check;
+
+fn compiles(name: &str, literal: &str) -> Result<(), String> {
+    let path = source(name, &format!("void main() {{ int x; x = {literal}; }}\n"));
+    let output = project(&[&path, "8"]);
+    match output.status.success() {
+        true => Ok(()),
+        false => Err(String::from_utf8_lossy(&output.stderr).into_owned()),
+    }
+}
+
+#[test]
+fn negated_literals_must_fit_an_int() {
+    for literal in [
+        "-0xFFFFFFFF",
+        "-0x80000001",
+        "-0b10000000000000000000000000000001",
+    ] {
+        let error = compiles("negated_out_of_range.c", literal).unwrap_err();
+        assert!(
+            error.contains("literal out of range for int"),
+            "{literal}: {error}"
+        );
+    }
+    for literal in ["-0x80000000", "-2147483648", "0xFFFFFFFF"] {
+        assert_eq!(compiles("negated_in_range.c", literal), Ok(()), "{literal}");
+    }
+}
+
+#[test]
+fn literals_have_the_values_they_spell() {
+    check(
+        "literal_values",
+        "
+int main() {
+    int x;
+    float f;
+    print(0x1F);
+    print(0XfF);
+    print(017);
+    print(0b1011);
+    print(0B11);
+    print(0);
+    print(-2147483648);
+    print(0xFFFFFFFF);
+    print(-0x10);
+    print(2147483647);
+    print(1e3);
+    print(1.5e-3);
+    print(.25);
+    print(5.);
+    print(-.5);
+    print(2E+2);
+    x = 010 - 8;
+    print(x);
+    x = 5-3;
+    print(x);
+    f = 1.0 - .5;
+    print(f);
+    return 0;
+}
+",
+        &[(
+            &[],
+            "31 255 15 11 3 0 -2147483648 -1 -16 2147483647 1000 0.0015 0.25 5 -0.5 200 0 2 0.5",
+        )],
+    );
+}
+
+#[test]
+fn literals_that_do_not_fit_are_rejected() {
+    for (literal, message) in [
+        ("3000000000", "literal out of range for int"),
+        ("2147483648", "literal out of range for int"),
+        ("0x100000000", "literal out of range for int"),
+        ("1e39", "literal out of range for float"),
+        ("08", "expected expr"),
+        ("0b2", "expected expr"),
+    ] {
+        let error = compiles("out_of_range.c", literal).unwrap_err();
+        assert!(error.contains(message), "{literal}: {error}");
+    }
+}
@@ -0,0 +1,271 @@
+use std::collections::HashMap;
+
+use crate::common::{project, source};
+
+// Where the stack starts and where memory is handed out from.
+const STACK: u32 = 0x7fff_fff0;
+const HEAP: u32 = 0x3000_0000;
+
+// A program can run this many instructions before it is taken to loop.
+const STEPS: usize = 10_000_000;
+
+// Compile a program with and without -O, with few and many registers, and
+// check that each run of it prints what it should.
+pub fn check(name: &str, text: &str, runs: &[(&[&str], &str)]) {
+    let path = source(&format!("{name}.c"), text);
+    for flags in [&[][..], &["-O"]] {
+        for regs in ["8", "12", "32"] {
+            let mut args = flags.to_vec();
+            args.extend([path.as_str(), regs]);
+            let output = project(&args);
+            assert!(
+                output.status.success(),
+                "{name} {flags:?} {regs}: {}",
+                String::from_utf8_lossy(&output.stderr)
+            );
+            let assembly = String::from_utf8_lossy(&output.stdout);
+            for (input, expected) in runs {
+                assert_eq!(
+                    run(&assembly, input),
+                    *expected,
+                    "{name} {flags:?} {regs} with {input:?}"
+                );
+            }
+        }
+    }
+}
+
+// Run a whole program, reading the input in order, and return what it
+// prints with a space between each value. Registers and memory hold words,
+// which floats are kept in as their bits, and an instruction's address is
+// four times its index.
+pub fn run(assembly: &str, input: &[&str]) -> String {
+    let mut lines = Vec::new();
+    let mut labels = HashMap::new();
+    let mut strings = HashMap::new();
+    let mut in_strings = false;
+    for line in assembly.lines() {
+        if let Some(section) = line.strip_prefix(".section ") {
+            in_strings = section == ".strings";
+            continue;
+        }
+        if in_strings {
+            let (address, text) = line.split_once(' ').unwrap();
+            strings.insert(number(address) as u32, text.trim_matches('"').to_owned());
+            continue;
+        }
+        let line = line.split('#').next().unwrap_or("").trim();
+        if line.is_empty() {
+            continue;
+        }
+        if let Some(label) = line.strip_suffix(':') {
+            labels.insert(label.to_owned(), lines.len());
+        } else {
+            let (op, rest) = line.split_once(' ').unwrap_or((line, ""));
+            lines.push((op, rest.split(',').map(str::trim).collect::<Vec<_>>()));
+        }
+    }
+
+    let mut machine = Machine {
+        registers: HashMap::from([("sp", STACK)]),
+        memory: HashMap::new(),
+    };
+    let mut input = input.iter();
+    let mut heap = HEAP;
+    let mut output = Vec::new();
+    let target = |label: &str| -> usize {
+        *labels
+            .get(label)
+            .unwrap_or_else(|| panic!("run: no label {label}"))
+    };
+    let mut pc = 0;
+    for _ in 0..STEPS {
+        let (op, a) = lines
+            .get(pc)
+            .unwrap_or_else(|| panic!("run: fell off the end"));
+        let (op, a) = (*op, a.as_slice());
+        pc += 1;
+        let m = &mut machine;
+        match op {
+            "HALT" => return output.join(" "),
+            "MV" | "FMV.S" => m.set(a[0], m.get(a[1])),
+            "LI" => m.set(a[0], number(a[1]) as u32),
+            "LA" => match labels.get(a[1]) {
+                Some(&k) => m.set(a[0], 4 * k as u32),
+                None => m.set(a[0], number(a[1]) as u32),
+            },
+            "FIMM.S" => m.set(a[0], a[1].parse::<f32>().unwrap().to_bits()),
+            "LW" | "FLW" => {
+                let address = m.address(a[1]);
+                let value = m.memory.get(&address).copied().unwrap_or(0);
+                m.set(a[0], value);
+            }
+            "SW" | "FSW" => {
+                let address = m.address(a[1]);
+                m.memory.insert(address, m.get(a[0]));
+            }
+            "ADDI" => m.set(a[0], m.get(a[1]).wrapping_add(number(a[2]) as u32)),
+            "ADD" => m.int(a, i32::wrapping_add),
+            "SUB" => m.int(a, i32::wrapping_sub),
+            "MUL" => m.int(a, i32::wrapping_mul),
+            "DIV" => m.int(a, i32::wrapping_div),
+            "NEG" => m.set(a[0], (m.get(a[1]) as i32).wrapping_neg() as u32),
+            "FADD.S" => m.float(a, |x, y| x + y),
+            "FSUB.S" => m.float(a, |x, y| x - y),
+            "FMUL.S" => m.float(a, |x, y| x * y),
+            "FDIV.S" => m.float(a, |x, y| x / y),
+            "FNEG.S" => m.set(a[0], (-f32::from_bits(m.get(a[1]))).to_bits()),
+            "FMOVI.S" => m.set(a[0], f32::from_bits(m.get(a[1])) as i32 as u32),
+            "IMOVF.S" => m.set(a[0], (m.get(a[1]) as i32 as f32).to_bits()),
+            "FEQ.S" | "FLT.S" | "FLE.S" => {
+                let x = f32::from_bits(m.get(a[1]));
+                let y = f32::from_bits(m.get(a[2]));
+                let holds = match op {
+                    "FEQ.S" => x == y,
+                    "FLT.S" => x < y,
+                    _ => x <= y,
+                };
+                m.set(a[0], u32::from(holds));
+            }
+            "BEQ" | "BNE" | "BLT" | "BLE" | "BGT" | "BGE" => {
+                let x = m.get(a[0]) as i32;
+                let y = m.get(a[1]) as i32;
+                let holds = match op {
+                    "BEQ" => x == y,
+                    "BNE" => x != y,
+                    "BLT" => x < y,
+                    "BLE" => x <= y,
+                    "BGT" => x > y,
+                    _ => x >= y,
+                };
+                if holds {
+                    pc = target(a[2]);
+                }
+            }
+            "J" => pc = target(a[0]),
+            "JR" => {
+                m.set("ra", 4 * pc as u32);
+                pc = target(a[0]);
+            }
+            "JALR" => {
+                let address = m.address(a[1]);
+                m.set(a[0], 4 * pc as u32);
+                pc = address as usize / 4;
+            }
+            "RET" => pc = m.get("ra") as usize / 4,
+            "GETI" => {
+                let value: i32 = input.next().unwrap().parse().unwrap();
+                m.set(a[0], value as u32);
+            }
+            "GETF" => {
+                let value: f32 = input.next().unwrap().parse().unwrap();
+                m.set(a[0], value.to_bits());
+            }
+            "PUTI" => output.push((m.get(a[0]) as i32).to_string()),
+            "PUTF" => output.push(general(f32::from_bits(m.get(a[0])))),
+            "PUTS" => output.push(strings[&m.get(a[0])].clone()),
+            "MALLOC" => {
+                m.set(a[0], heap);
+                heap += (m.get(a[1]) as i32).max(0) as u32 + 16;
+            }
+            "FREE" => {}
+            _ => panic!("run: unknown instruction {op}"),
+        }
+    }
+    panic!("run: more than {STEPS} steps");
+}
+
+struct Machine<'a> {
+    registers: HashMap<&'a str, u32>,
+    memory: HashMap<u32, u32>,
+}
+
+impl<'a> Machine<'a> {
+    fn get(&self, register: &str) -> u32 {
+        exists(register);
+        self.registers.get(register).copied().unwrap_or(0)
+    }
+
+    fn set(&mut self, register: &'a str, value: u32) {
+        exists(register);
+        if register != "x0" {
+            self.registers.insert(register, value);
+        }
+    }
+
+    // An address written as offset(register).
+    fn address(&self, operand: &str) -> u32 {
+        let (offset, register) = operand.trim_end_matches(')').split_once('(').unwrap();
+        self.get(register).wrapping_add(number(offset) as u32)
+    }
+
+    fn int(&mut self, a: &[&'a str], op: fn(i32, i32) -> i32) {
+        let value = op(self.get(a[1]) as i32, self.get(a[2]) as i32);
+        self.set(a[0], value as u32);
+    }
+
+    fn float(&mut self, a: &[&'a str], op: fn(f32, f32) -> f32) {
+        let x = f32::from_bits(self.get(a[1]));
+        let y = f32::from_bits(self.get(a[2]));
+        self.set(a[0], op(x, y).to_bits());
+    }
+}
+
+// Registers are named by their use or numbered from 0 to 31.
+fn exists(register: &str) {
+    let number = register
+        .strip_prefix('x')
+        .or_else(|| register.strip_prefix('f'))
+        .and_then(|n| n.parse::<u32>().ok());
+    let named = ["sp", "fp", "ra"].contains(&register);
+    assert!(
+        named || number.is_some_and(|n| n < 32),
+        "run: there is no register {register}"
+    );
+}
+
+// A decimal or hexadecimal integer.
+fn number(text: &str) -> i64 {
+    let (negative, digits) = match text.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, text),
+    };
+    let value = match digits.strip_prefix("0x") {
+        Some(hex) => i64::from_str_radix(hex, 16),
+        None => digits.parse(),
+    }
+    .unwrap_or_else(|_| panic!("run: {text} is not a number"));
+    if negative {
+        -value
+    } else {
+        value
+    }
+}
+
+// A float with six significant digits and no trailing zeros, as printf's %g
+// writes it.
+fn general(value: f32) -> String {
+    let value = f64::from(value);
+    if value == 0.0 || !value.is_finite() {
+        return format!("{value}");
+    }
+    let exponent = value.abs().log10().floor() as i32;
+    if (-4..6).contains(&exponent) {
+        let decimals = (5 - exponent).max(0) as usize;
+        let text = format!("{value:.decimals$}");
+        match text.contains('.') {
+            true => text.trim_end_matches('0').trim_end_matches('.').to_owned(),
+            false => text,
+        }
+    } else {
+        let text = format!("{value:.5e}");
+        let (mantissa, exponent) = text.split_once('e').unwrap();
+        let mantissa = mantissa.trim_end_matches('0').trim_end_matches('.');
+        let exponent: i32 = exponent.parse().unwrap();
+        format!(
+            "{mantissa}e{}{:02}",
+            if exponent < 0 { '-' } else { '+' },
+            exponent.abs()
+        )
+    }
+}
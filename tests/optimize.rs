@@ -0,0 +1,1182 @@
+mod common;
+mod machine;
+
+use common::{project, source};
+use machine::check;
+
+// The 3AC of main's nth while loop in a listing.
+fn loop_body(listing: &str, n: u32) -> &str {
+    let head = listing.find(&format!("main.while{n}.head:")).unwrap();
+    let end = listing.find(&format!("main.while{n}.end:")).unwrap();
+    &listing[head..end]
+}
+
+// Nested loops left by break, cases falling through and functions returning
+// from several places, all allocated block by block over the graph.
+#[test]
+fn control_flow_runs_the_same() {
+    check(
+        "optimize_control_flow",
+        "int calls;
+int step(int x) {
+    calls = calls + 1;
+    return x + 1;
+}
+int sign(int x) {
+    if (x < 0) {
+        return -1;
+    }
+    if (x == 0) {
+        return 0;
+    }
+    return 1;
+}
+int main() {
+    int i;
+    int j;
+    int s;
+    int n;
+    read(n);
+    s = 0;
+    i = 0;
+    while (i < n) {
+        j = i;
+        do {
+            if (j == 3) {
+                break;
+            }
+            s = s + j * sign(j - 2);
+            j = j - 1;
+        } while (j > 0);
+        switch (i) {
+        case 0:
+            s = s + 100;
+        case 1:
+            s = s + 10;
+            break;
+        case 4:
+            s = step(s);
+        default:
+            s = s - 1;
+        }
+        i = step(i);
+    }
+    print(s);
+    print(calls);
+    print(i);
+    return 0;
+}
+",
+        &[(&["0"], "0 0 0"), (&["1"], "110 1 1"), (&["6"], "128 7 6")],
+    );
+}
+
+// More values live from block to block than there are registers for.
+#[test]
+fn values_live_across_blocks_survive_few_registers() {
+    check(
+        "optimize_live_across_blocks",
+        "int main() {
+    int a;
+    int b;
+    int c;
+    int d;
+    int e;
+    float f;
+    float g;
+    int k;
+    read(a);
+    b = a + 1;
+    c = b * 2;
+    d = c - a;
+    e = d * b;
+    f = a * 0.5;
+    g = f + 1.25;
+    k = 0;
+    while (k < a) {
+        if (k > 2) {
+            a = a - 1;
+            f = f + g;
+        } else {
+            e = e + d;
+            g = g * 2.0;
+        }
+        b = b + c;
+        k = k + 1;
+    }
+    print(a);
+    print(b);
+    print(c);
+    print(d);
+    print(e);
+    print(f);
+    print(g);
+    print(k);
+    return 0;
+}
+",
+        &[
+            (&["0"], "0 1 2 2 2 0 1.25 0"),
+            (&["2"], "2 15 6 4 20 1 9 2"),
+            (&["7"], "5 88 16 9 99 79.5 38 5"),
+        ],
+    );
+}
+
+// A case falls into an if whose else label starts the same block as the
+// end of the switch, which the branch around the if goes to.
+#[test]
+fn edges_into_merged_labels_keep_their_copies() {
+    check(
+        "optimize_merged_labels",
+        "int main() {
+    int c;
+    int x;
+    read(c);
+    read(x);
+    switch (c) {
+    case 0:
+    case 1:
+    case 2:
+        if (x > 5) {
+            c = x;
+        }
+    }
+    print(c);
+    return 0;
+}
+",
+        &[(&["7", "9"], "7"), (&["1", "9"], "9"), (&["1", "3"], "1")],
+    );
+}
+
+// Phis whose copies would clobber each other's sources if made in order,
+// a phi read after the loop that redefines it, a variable only reached
+// through a pointer and one read on a path that never set it.
+#[test]
+fn phis_become_copies_that_keep_their_values() {
+    check(
+        "optimize_phis",
+        "int fib(int n) {
+    int a;
+    int b;
+    int t;
+    a = 0;
+    b = 1;
+    while (n > 0) {
+        t = a;
+        a = b;
+        b = t + b;
+        n = n - 1;
+    }
+    return a;
+}
+int swaps(int n) {
+    int x;
+    int y;
+    int t;
+    x = 1;
+    y = 2;
+    while (n > 0) {
+        t = x;
+        x = y;
+        y = t;
+        n = n - 1;
+    }
+    return x * 10 + y;
+}
+int lost(int n) {
+    int i;
+    int prev;
+    i = 0;
+    prev = 0;
+    while (i < n) {
+        prev = i;
+        i = i + 1;
+    }
+    return prev * 100 + i;
+}
+int alias(int n) {
+    int x;
+    int *p;
+    x = n;
+    p = &x;
+    while (n > 0) {
+        *p = *p + n;
+        n = n - 1;
+    }
+    return x;
+}
+int maybe(int c) {
+    int u;
+    if (c != 0) {
+        u = 5;
+    }
+    if (c != 0) {
+        return u;
+    }
+    return 3;
+}
+float halves(int n) {
+    float f;
+    f = 0.5;
+    do {
+        f = f + n;
+        n = n - 1;
+    } while (n > 0);
+    return f;
+}
+int main() {
+    int n;
+    read(n);
+    print(fib(n));
+    print(swaps(n));
+    print(lost(n));
+    print(alias(n));
+    print(maybe(n));
+    print(halves(n));
+    return 0;
+}
+",
+        &[
+            (&["0"], "0 12 0 0 3 0.5"),
+            (&["3"], "2 21 203 9 5 6.5"),
+            (&["10"], "55 12 910 65 5 55.5"),
+        ],
+    );
+}
+
+// Jump table entries and the branches around the table go to blocks that
+// phis join at the end of the switch.
+#[test]
+fn switches_in_loops_keep_their_values() {
+    check(
+        "optimize_switch_in_loop",
+        "int main() {
+    int x;
+    int y;
+    int k;
+    k = 0;
+    read(x);
+    while (k < 3) {
+        y = x - 1;
+        switch (x) {
+        case 1:
+            print(10);
+            break;
+        case 2:
+            print(20);
+        case 3:
+            print(30);
+            break;
+        }
+        print(y);
+        x = x + 1;
+        k = k + 1;
+    }
+    return 0;
+}
+",
+        &[(&["0"], "-1 10 0 20 30 1"), (&["2"], "20 30 1 30 2 3")],
+    );
+}
+
+#[test]
+fn variables_get_phis_where_definitions_join() {
+    let path = source(
+        "optimize_ssa_form.c",
+        "int main() {
+    int i;
+    i = 0;
+    while (i < 5) {
+        i = i + 1;
+    }
+    print(i);
+    return 0;
+}
+",
+    );
+    let output = project(&["--ssa", &path, "8"]);
+    let listing = String::from_utf8_lossy(&output.stdout);
+    let head = listing.find("main.while1.head:").unwrap();
+    assert!(listing[head..].contains("= PHI(0: "), "{listing}");
+
+    let output = project(&["-O", "--3ac", &path, "8"]);
+    assert!(!String::from_utf8_lossy(&output.stdout).contains("PHI"));
+}
+
+// Renaming makes the same temp every argument of these calls, and it is
+// not read after them. The functions making them call themselves so that
+// they are not inlined.
+#[test]
+fn a_value_may_be_passed_more_than_once() {
+    check(
+        "optimize_repeated_arguments",
+        "int f(int a, int b) {
+    if (a > 100) {
+        return f(a - 1, b) + 1;
+    }
+    return a * 10 + b;
+}
+int h(int a, int b, int c) {
+    if (c > 50) {
+        return h(a, b, c - 1);
+    }
+    return a + b + c;
+}
+float g(float a, float b) {
+    return a * b + a;
+}
+void printed(int x) {
+    if (x < 0) {
+        printed(-x);
+    }
+    x = x * 3;
+    print(f(x, x));
+}
+int returned(int x) {
+    if (x < 0) {
+        return returned(-x);
+    }
+    x = x * 3;
+    return h(x, x, x);
+}
+int main() {
+    int x;
+    float y;
+    read(x);
+    read(y);
+    printed(x);
+    print(returned(x));
+    y = y * 2.0;
+    print(g(y, y));
+    return 0;
+}
+",
+        &[(&["4", "1.5"], "132 36 12"), (&["30", "0.5"], "990 230 2")],
+    );
+}
+
+// Arithmetic that wraps or truncates as the target's does, branches on
+// known conditions, variables constant only on some paths, and calls whose
+// arguments propagation may make the same.
+const CONSTANTS: &str = "int pair(int a, int b) {
+    if (a < 0) {
+        return pair(-a, b);
+    }
+    return a * 100 + b;
+}
+int main() {
+    int a;
+    int b;
+    int c;
+    int i;
+    int n;
+    float f;
+    float g;
+    read(n);
+    a = 6;
+    b = a * 7;
+    print(b);
+    if (b == 42) {
+        print(1);
+    } else {
+        print(2);
+    }
+    a = 2147483647;
+    print(a + 1);
+    a = -7;
+    print(a / 2);
+    f = 0.1;
+    g = f + 0.2;
+    print(g * 10.0);
+    f = 1.0 / 3.0;
+    print(f * 3.0);
+    c = n;
+    print(pair(c, n));
+    b = 7;
+    print(pair(b, 7));
+    b = 5;
+    if (n > 3) {
+        b = 6;
+    }
+    print(b * 2);
+    i = 0;
+    c = 1;
+    while (i < n) {
+        c = 1;
+        i = i + 1;
+    }
+    print(c + i);
+    while (0 == 1) {
+        print(99);
+    }
+    b = 3;
+    b = b - 3;
+    if (n / (b + 1) > 2) {
+        print(n);
+    }
+    return 0;
+}
+";
+
+#[test]
+fn folded_constants_match_run_time_arithmetic() {
+    check(
+        "optimize_constants",
+        CONSTANTS,
+        &[
+            (&["0"], "42 1 -2147483648 -3 3 1 0 707 10 1"),
+            (&["3"], "42 1 -2147483648 -3 3 1 303 707 10 4 3"),
+            (&["5"], "42 1 -2147483648 -3 3 1 505 707 12 6 5"),
+        ],
+    );
+}
+
+#[test]
+fn known_branches_are_resolved() {
+    let path = source("optimize_constants_listing.c", CONSTANTS);
+    let output = project(&["-O", "--3ac", &path, "8"]);
+    let listing = String::from_utf8_lossy(&output.stdout);
+    // The branch on b == 42 and the loop that never runs are gone.
+    assert!(!listing.contains("main.if1."), "{listing}");
+    assert!(!listing.contains(":= 99"), "{listing}");
+}
+
+// Code after returns, a loop only left by returning, a value computed in a
+// loop and never read, and results thrown away of calls, stores and frees
+// that must still happen.
+const DEAD: &str = "int calls;
+int *cell;
+int bump() {
+    calls = calls + 1;
+    return calls;
+}
+int early(int x) {
+    int y;
+    if (x > 0) {
+        return 1;
+        print(99);
+        y = 3;
+    }
+    return 2;
+    print(98);
+}
+void spin(int n) {
+    int i;
+    i = 0;
+    while (0 == 0) {
+        if (i == n) {
+            print(i);
+            return;
+        }
+        i = i + 1;
+    }
+}
+int unused(int n) {
+    int i;
+    int waste;
+    i = 0;
+    waste = 0;
+    while (i < n) {
+        waste = waste * 2 + i;
+        i = i + 1;
+    }
+    return i;
+}
+void store(int v) {
+    int x;
+    *cell = v;
+    x = v * 5;
+}
+int main() {
+    int a;
+    int *p;
+    int (*f)();
+    read(a);
+    cell = malloc(4);
+    a = bump() * 0 + a;
+    p = malloc(4);
+    free(p);
+    f = bump;
+    print(early(a));
+    print(early(0));
+    spin(a);
+    print(unused(a));
+    store(a + 1);
+    print(*cell);
+    print(calls);
+    print(f());
+    return 0;
+}
+";
+
+#[test]
+fn removing_dead_code_keeps_what_it_does() {
+    check(
+        "optimize_dead_code",
+        DEAD,
+        &[(&["0"], "2 2 0 0 1 1 2"), (&["4"], "1 2 4 4 5 1 2")],
+    );
+}
+
+#[test]
+fn values_never_read_are_not_computed() {
+    let path = source("optimize_dead_code_listing.c", DEAD);
+    let output = project(&["-O", "--3ac", &path, "8"]);
+    let listing = String::from_utf8_lossy(&output.stdout);
+    for function in ["unused:", "store:"] {
+        let start = listing.find(function).unwrap();
+        let body = &listing[start..start + listing[start..].find("RET").unwrap()];
+        assert!(!body.contains(" * "), "{body}");
+    }
+    assert!(!listing.contains(":= 99"), "{listing}");
+    assert!(!listing.contains(":= 98"), "{listing}");
+}
+
+// Repeated arithmetic, loads that stores through pointers or calls change
+// in between, and calls that numbering makes pass the same value twice.
+const NUMBERING: &str = "int g;
+int *q;
+int pair(int a, int b) {
+    if (a < 0) {
+        return pair(-a, b);
+    }
+    return a * 100 + b;
+}
+float mix(float a, float b) {
+    if (a < 0.0) {
+        return mix(-a, b);
+    }
+    return a * 2.0 + b;
+}
+int add(int x) {
+    g = g + x;
+    return g;
+}
+void bump() {
+    g = g + 1;
+    *q = *q + 10;
+}
+int main() {
+    int x;
+    int i;
+    int s;
+    int k;
+    int *a;
+    int *b;
+    int *p;
+    float y;
+    read(x);
+    read(y);
+    print(pair(x + 1, x + 1));
+    print(mix(y * 3.0, y * 3.0));
+    a = malloc(40);
+    b = malloc(20);
+    i = x;
+    a[i] = 4;
+    a[i] = a[i] + 1;
+    a[i] = a[i] + 1;
+    b[i] = a[i] * 3;
+    s = b[i] + b[i];
+    print(s);
+    p = &b[i];
+    *p = 7;
+    s = b[i] + a[i];
+    print(s);
+    g = i * 5;
+    s = add(1);
+    s = i * 5 + g;
+    print(s);
+    if (i > 1) {
+        s = i * 5;
+    } else {
+        s = i * 5 + 1;
+    }
+    s = s + i * 5;
+    print(s);
+    q = malloc(4);
+    *q = 0;
+    p = &x;
+    s = x + 2;
+    *p = 5;
+    s = s + x + 2;
+    print(s);
+    g = 3;
+    s = g * 2;
+    bump();
+    s = s + g * 2;
+    print(s);
+    k = 0;
+    s = 0;
+    while (k < 3) {
+        s = s + *q + g * 2;
+        *q = *q + 1;
+        g = g + 1;
+        k = k + 1;
+    }
+    s = s + *q + g * 2;
+    print(s);
+    return 0;
+}
+";
+
+#[test]
+fn reused_values_are_still_current() {
+    check(
+        "optimize_value_numbering",
+        NUMBERING,
+        &[
+            (&["0", "1.5"], "101 13.5 36 13 1 1 9 14 90"),
+            (&["3", "-0.25"], "404 0.75 36 13 31 30 12 14 90"),
+        ],
+    );
+}
+
+#[test]
+fn repeated_arguments_are_computed_once() {
+    let path = source("optimize_value_numbering_listing.c", NUMBERING);
+    let output = project(&["-O", "--3ac", &path, "32"]);
+    let listing = String::from_utf8_lossy(&output.stdout);
+    for call in ["= pair(", "= mix("] {
+        let line = listing.lines().find(|l| l.contains(call)).unwrap();
+        let args = line.split_once('(').unwrap().1.trim_end_matches(')');
+        let (a, b) = args.split_once(", ").unwrap();
+        assert_eq!(a, b, "{line}");
+    }
+}
+
+// Loop variables kept in registers next to one changed through a pointer,
+// calls that change globals, and recursion that must get back the caller's
+// values.
+#[test]
+fn variables_kept_in_registers_survive_calls() {
+    check(
+        "optimize_pinned",
+        "int g;
+int sq(int x) {
+    g = g + 1;
+    return x * x;
+}
+int depth(int n) {
+    int a;
+    int b;
+    if (n < 1) {
+        return 0;
+    }
+    a = n * 2;
+    b = a + 1;
+    a = a + depth(n - 1);
+    return a + b;
+}
+int main() {
+    int i;
+    int s;
+    int t;
+    int k;
+    int *p;
+    float f;
+    read(k);
+    i = 0;
+    s = 0;
+    t = 1;
+    f = 0.5;
+    p = &t;
+    while (i < 20) {
+        s = s + i;
+        f = f * 1.5;
+        if (i == k) {
+            s = s + sq(i);
+            *p = *p + s;
+        }
+        t = t + 2;
+        i = i + 1;
+    }
+    print(s);
+    print(t);
+    print(f);
+    print(g);
+    s = 0;
+    i = 0;
+    while (i < k) {
+        s = s + depth(i) + k;
+        i = i + 1;
+    }
+    print(s);
+    return 0;
+}
+",
+        &[
+            (&["0"], "190 41 1662.63 1 0"),
+            (&["5"], "215 81 1662.63 1 115"),
+            (&["12"], "334 263 1662.63 1 1354"),
+        ],
+    );
+}
+
+// Invariant products of locals, of globals that calls or stores through
+// pointers may change, a division by a divisor that is zero when the loop
+// never runs, and loops behind an if or entered at the bottom.
+const INVARIANTS: &str = "int *a;
+int n;
+int f(int x) {
+    n = n + 1;
+    return x;
+}
+int main() {
+    int i;
+    int j;
+    int s;
+    int m;
+    int d;
+    int *p;
+    read(m);
+    read(d);
+    n = 5;
+    a = malloc(40);
+    p = malloc(4);
+    *p = 2;
+    i = 0;
+    while (i < 10) {
+        a[i] = n * 4 + m * i;
+        i = i + 1;
+    }
+    s = 0;
+    i = 0;
+    while (i < 10) {
+        s = s + a[i] + n * m + *p;
+        *p = 1;
+        i = i + 1;
+    }
+    print(s);
+    i = 0;
+    s = 0;
+    while (i < 3) {
+        j = 0;
+        while (j < 4) {
+            s = s + m * 7 + i * m + j;
+            j = j + 1;
+        }
+        i = i + 1;
+    }
+    print(s);
+    i = 0;
+    s = 0;
+    while (i < 3) {
+        s = s + n * 2;
+        s = s + f(i);
+        i = i + 1;
+    }
+    print(s);
+    print(n);
+    i = 0;
+    s = 0;
+    while (i < m) {
+        s = s + 100 / d;
+        i = i + 1;
+    }
+    print(s);
+    i = 0;
+    s = 0;
+    if (m > 1) {
+        while (i < 4) {
+            s = s + m * m;
+            i = i + 1;
+        }
+    }
+    print(s);
+    i = 10;
+    do {
+        s = s + m * 2;
+        i = i - 1;
+    } while (i > 0);
+    print(s);
+    return 0;
+}
+";
+
+#[test]
+fn hoisted_computations_give_the_same_results() {
+    check(
+        "optimize_invariants",
+        INVARIANTS,
+        &[
+            (&["0", "0"], "211 18 39 8 0 0 0"),
+            (&["3", "7"], "496 306 39 8 42 36 96"),
+        ],
+    );
+}
+
+// What a loop computes the same way each time is done before it, except
+// for the division, which could fail when the loop does not run.
+#[test]
+fn invariants_leave_loops_that_are_safe_to_leave() {
+    let path = source("optimize_invariants_listing.c", INVARIANTS);
+    let output = project(&["-O", "--3ac", &path, "8"]);
+    let listing = String::from_utf8_lossy(&output.stdout);
+    assert!(!loop_body(&listing, 8).contains(" * "), "{listing}");
+    assert!(loop_body(&listing, 6).contains(" / "), "{listing}");
+}
+
+// Two cases of a switch go to a loop's header by labels of their own, and
+// both must go through the code hoisted out of the loop.
+#[test]
+fn every_way_into_a_loop_goes_through_its_preheader() {
+    check(
+        "optimize_preheader_labels",
+        "int main() {
+    int c;
+    int m;
+    int i;
+    int s;
+    read(c);
+    read(m);
+    i = 0;
+    s = 0;
+    switch (c) {
+    case 0:
+    case 1:
+        while (i < 3) {
+            s = s + m * m;
+            i = i + 1;
+        }
+    case 2:
+        if (c > 0) {
+            s = s + 1;
+        }
+        while (i < 5) {
+            s = s + m * 3;
+            i = i + 1;
+        }
+    }
+    print(s);
+    print(i);
+    return 0;
+}
+",
+        &[
+            (&["0", "2"], "24 5"),
+            (&["1", "3"], "46 5"),
+            (&["2", "4"], "61 5"),
+            (&["5", "1"], "0 0"),
+        ],
+    );
+}
+
+// Induction variables counting up and down by constants and by a step read
+// at run time, multiplied by constants, by negative factors and by
+// variables, read after the loop and in products that overflow.
+const INDUCTION: &str = "int main() {
+    int *a;
+    int i;
+    int j;
+    int s;
+    int k;
+    int n;
+    int step;
+    read(n);
+    read(step);
+    a = malloc(400);
+    i = 0;
+    while (i < 100) {
+        a[i] = i * 3;
+        i = i + 1;
+    }
+    s = 0;
+    i = 0;
+    while (i < 10) {
+        j = 0;
+        while (j < 10) {
+            s = s + a[i * 10 + j];
+            j = j + 1;
+        }
+        i = i + 1;
+    }
+    print(s);
+    k = 0;
+    i = 20;
+    while (i > 0) {
+        i = i - 2;
+        k = k + i * n;
+    }
+    print(k);
+    print(i);
+    i = 0;
+    s = 0;
+    while (i < 5) {
+        s = s + (i + 1) * 7 - i * n;
+        i = i + 1;
+    }
+    print(s);
+    i = 1;
+    s = 0;
+    while (i < 200) {
+        s = s + i * -3 + i * n;
+        i = i + step;
+    }
+    print(s);
+    print(i);
+    i = 0;
+    k = 0;
+    while (i < 4) {
+        k = k + i * 1000000000;
+        i = i + 1;
+    }
+    print(k);
+    return 0;
+}
+";
+
+#[test]
+fn reduced_products_give_the_same_results() {
+    check(
+        "optimize_induction",
+        INDUCTION,
+        &[
+            (&["3", "7"], "14850 270 0 75 0 204 1705032704"),
+            (&["-2", "1"], "14850 -180 0 125 -99500 200 1705032704"),
+            (&["0", "50"], "14850 0 0 105 -912 201 1705032704"),
+        ],
+    );
+}
+
+#[test]
+fn loops_step_products_instead_of_multiplying() {
+    let path = source("optimize_induction_listing.c", INDUCTION);
+    let output = project(&["-O", "--3ac", &path, "8"]);
+    let listing = String::from_utf8_lossy(&output.stdout);
+    for n in [1, 4, 5, 6, 7] {
+        assert!(!loop_body(&listing, n).contains(" * "), "{listing}");
+    }
+}
+
+// Callees that return from several places, change their arguments, take
+// their address, loop, convert an argument, call each other or themselves,
+// and are called in a loop or through a pointer.
+const INLINING: &str = "int g;
+int absv(int x) {
+    if (x < 0) {
+        return -x;
+    }
+    return x;
+}
+int sum(int n) {
+    int s;
+    s = 0;
+    while (n > 0) {
+        s = s + n;
+        n = n - 1;
+    }
+    return s;
+}
+int pick(int x) {
+    switch (x) {
+    case 0:
+        return 5;
+    case 1:
+        return 7;
+    }
+    return 9;
+}
+void bump(int *p) {
+    *p = *p + 1;
+    g = g + 1;
+}
+int viaaddr(int x) {
+    int *p;
+    p = &x;
+    *p = *p * 2;
+    return x;
+}
+float scale(float f, int k) {
+    return f * k;
+}
+int twice(int x) {
+    return absv(x) + absv(x - 10);
+}
+int add3(int a, int b, int c) {
+    int t;
+    t = a + b;
+    return t + c;
+}
+int fact(int n) {
+    if (n < 2) {
+        return 1;
+    }
+    return n * fact(n - 1);
+}
+int apply(int (*f)(int), int v) {
+    return f(v);
+}
+int main() {
+    int a;
+    int n;
+    int i;
+    int s;
+    read(n);
+    g = 0;
+    a = 3;
+    print(absv(n));
+    print(sum(n));
+    print(n);
+    print(pick(n));
+    bump(&a);
+    bump(&a);
+    print(a);
+    print(g);
+    print(viaaddr(n));
+    print(scale(1.5, n));
+    print(twice(n));
+    print(fact(6));
+    print(apply(absv, -9));
+    i = 0;
+    s = 0;
+    while (i < 4) {
+        s = s + add3(i, i, n) + absv(n - i);
+        i = i + 1;
+    }
+    print(s);
+    return 0;
+}
+";
+
+#[test]
+fn inlined_calls_give_the_same_results() {
+    check(
+        "optimize_inlining",
+        INLINING,
+        &[
+            (&["-4"], "4 0 -4 9 5 2 -8 -6 18 720 9 18"),
+            (&["0"], "0 0 0 5 5 2 0 0 10 720 9 18"),
+            (&["1"], "1 1 1 7 5 2 2 1.5 10 720 9 20"),
+            (&["6"], "6 21 6 9 5 2 12 9 10 720 9 54"),
+        ],
+    );
+}
+
+#[test]
+fn small_functions_are_inlined_and_recursive_ones_are_not() {
+    let path = source("optimize_inlining_listing.c", INLINING);
+    let output = project(&["-O", "--3ac", &path, "8"]);
+    let listing = String::from_utf8_lossy(&output.stdout);
+    let main = &listing[listing.find("main:").unwrap()..];
+    for callee in ["absv(", "sum(", "bump(", "viaaddr(", "add3("] {
+        assert!(!main.contains(callee), "{main}");
+    }
+    assert!(main.contains("= fact("), "{main}");
+}
+
+// Recursion that becomes a loop, with arguments that swap or repeat, calls
+// that hand over the frame, and calls that must not: one with more
+// arguments than its caller, one passing a pointer into the frame and
+// one whose result is used.
+const TAIL_CALLS: &str = "int sumto(int n, int acc) {
+    if (n == 0) {
+        return acc;
+    }
+    return sumto(n - 1, acc + n);
+}
+int even(int n);
+int odd(int n) {
+    if (n == 0) {
+        return 0;
+    }
+    return even(n - 1);
+}
+int even(int n) {
+    if (n == 0) {
+        return 1;
+    }
+    return odd(n - 1);
+}
+float fsum(float x, int n) {
+    if (n == 0) {
+        return x;
+    }
+    return fsum(x + 0.5, n - 1);
+}
+int swap(int a, int b, int k) {
+    if (k == 0) {
+        return a * 10 + b;
+    }
+    return swap(b, a, k - 1);
+}
+int same(int a, int b, int k) {
+    if (k == 0) {
+        return a * 10 + b;
+    }
+    return same(b + 1, b + 1, k - 1);
+}
+int wide(int a, int b, int c) {
+    return a * 100 + b * 10 + c;
+}
+int narrow(int a) {
+    return wide(a, a + 1, a + 2);
+}
+int deref(int *p) {
+    return *p;
+}
+int local(int n) {
+    int x;
+    x = n * 2;
+    return deref(&x);
+}
+void count(int n) {
+    if (n > 0) {
+        print(n);
+        count(n - 1);
+    }
+}
+int fact(int n) {
+    if (n < 2) {
+        return 1;
+    }
+    return n * fact(n - 1);
+}
+int main() {
+    int n;
+    read(n);
+    print(sumto(n, 0));
+    print(even(n));
+    print(fsum(0.0, n));
+    print(swap(1, 2, n));
+    print(same(1, 2, n));
+    print(narrow(n));
+    print(local(n));
+    count(3);
+    print(fact(n));
+    return 0;
+}
+";
+
+#[test]
+fn tail_calls_give_the_same_results() {
+    check(
+        "optimize_tail_calls",
+        TAIL_CALLS,
+        &[
+            (&["0"], "0 1 0 12 12 12 0 3 2 1 1"),
+            (&["1"], "1 0 0.5 21 33 123 2 3 2 1 1"),
+            (&["7"], "28 0 3.5 21 99 789 14 3 2 1 5040"),
+            (&["1000"], "500500 1 500 12 11022 111012 2000 3 2 1 0"),
+        ],
+    );
+}
+
+#[test]
+fn returned_calls_do_not_come_back() {
+    let path = source("optimize_tail_calls_listing.c", TAIL_CALLS);
+    let output = project(&["-O", "--3ac", &path, "8"]);
+    let listing = String::from_utf8_lossy(&output.stdout);
+    let body = |name: &str| {
+        let start = listing.find(&format!("\n{name}:\n")).unwrap() + 1;
+        let end = start + listing[start..].find("RET").unwrap();
+        &listing[start..end]
+    };
+    for name in ["sumto", "fsum", "swap", "same"] {
+        assert!(!body(name).contains(&format!("{name}(")), "{listing}");
+    }
+    assert!(body("odd").contains("TAIL even("), "{listing}");
+    assert!(body("even").contains("TAIL odd("), "{listing}");
+    assert!(body("fact").contains("= fact("), "{listing}");
+}
@@ -0,0 +1,129 @@
+mod common;
+mod machine;
+
+use common::{project, source};
+use machine::check;
+
+fn error(name: &str, text: &str) -> String {
+    let output = project(&[&source(name, text), "8"]);
+    assert!(!output.status.success(), "{name} compiled");
+    String::from_utf8_lossy(&output.stderr).into_owned()
+}
+
+#[test]
+fn includes_macros_conditionals_and_comments_run_as_in_c() {
+    source(
+        "pp_defs.h",
+        "
+#ifndef DEFS_H
+#define DEFS_H
+#define SIZE 4
+#define SQUARE(x) ((x) * (x))
+#define MAX(a, b) (a > b ? a : b)
+#endif
+",
+    );
+    // Macro calls go on over lines, and expansions are rescanned with the
+    // rest of the line for the arguments of a macro they end with.
+    check(
+        "preprocess",
+        "
+#include \"pp_defs.h\"
+#include \"pp_defs.h\"
+// A comment on its own line.
+#define TWICE(x) (2 * (x)) /* doubled */
+#define APPLY TWICE
+#define FOUR TWICE(2)
+#define LIMIT \\
+    100
+#ifdef SIZE
+int size() {
+    return SIZE;
+}
+#else
+int size() {
+    return 0;
+}
+#endif
+#ifdef MISSING
+#error not here
+#endif
+int main() {
+    int x;
+    /* A comment
+       over lines */
+    read(x);
+    print(size());
+    print(SQUARE(x + 1));
+    print(MAX(x, SIZE));
+    print(MAX(SQUARE(x),
+              LIMIT));
+    print(TWICE
+          (x));
+    print(APPLY(x + 3));
+    print(FOUR);
+#undef SIZE
+#ifndef SIZE
+    print(-1); // gone
+#endif
+    return 0;
+}
+",
+        &[
+            (&["-4"], "4 9 4 100 -8 -2 4 -1"),
+            (&["3"], "4 16 4 100 6 12 4 -1"),
+            (&["11"], "4 144 11 121 22 28 4 -1"),
+        ],
+    );
+}
+
+#[test]
+fn lines_after_a_call_over_lines_keep_their_numbers() {
+    let stderr = error(
+        "pp_joined.c",
+        "#define MAX(a, b) (a > b ? a : b)\nint main() {\n  int x;\n  x = MAX(1,\n    2);\n  x = y;\n  return 0;\n}\n",
+    );
+    assert!(
+        stderr.contains("pp_joined.c:6:3: get_symbol: y is used before its declaration"),
+        "{stderr}"
+    );
+}
+
+#[test]
+fn malformed_directives_and_calls_are_rejected() {
+    for (name, text, message) in [
+        (
+            "pp_unterminated.c",
+            "#define MAX(a, b) (a > b ? a : b)\nint main() {\n  int x;\n  x = MAX(1,\n#define Y 2\n    Y);\n  return 0;\n}\n",
+            "pp_unterminated.c:4: unterminated arguments to MAX",
+        ),
+        (
+            "pp_arity.c",
+            "#define MAX(a, b) (a > b ? a : b)\nint main() {\n  return MAX(1);\n}\n",
+            "pp_arity.c:3: MAX expects 2 arguments, found 1",
+        ),
+        (
+            "pp_unknown.c",
+            "#pragma once\nint main() {\n  return 0;\n}\n",
+            "pp_unknown.c:1: unknown directive #pragma",
+        ),
+        (
+            "pp_ifdef.c",
+            "#ifdef X\nint main() {\n  return 0;\n}\n",
+            "pp_ifdef.c:4: unterminated #ifdef",
+        ),
+        (
+            "pp_endif.c",
+            "#endif\nint main() {\n  return 0;\n}\n",
+            "pp_endif.c:1: #endif without #ifdef",
+        ),
+        (
+            "pp_cycle.c",
+            "#include \"pp_cycle.c\"\n",
+            "#include nested too deeply",
+        ),
+    ] {
+        let stderr = error(name, text);
+        assert!(stderr.contains(message), "{name}: {stderr}");
+    }
+}
@@ -0,0 +1,80 @@
+mod common;
+
+use common::{project, source};
+
+// A loop that calls a function that is not inlined, since it is recursive,
+// and one that calls nothing.
+const LOOPS: &str = "int depth(int x) {
+    if (x < 1) {
+        return 0;
+    }
+    return depth(x - 1) + 1;
+}
+int main() {
+    int i;
+    int s;
+    int n;
+    int k;
+    k = 3;
+    n = 10;
+    s = 0;
+    i = 0;
+    while (i < n) {
+        s = s + depth(k);
+        i = i + 1;
+    }
+    print(s);
+    i = 0;
+    while (i < n) {
+        s = s + i * k;
+        i = i + 1;
+    }
+    print(s);
+    return 0;
+}
+";
+
+// The loads and stores in the nth loop of main.
+fn memory_accesses(flags: &[&str], n: usize) -> usize {
+    let path = source(&format!("registers_loops_{}_{n}.c", flags.len()), LOOPS);
+    let mut args = flags.to_vec();
+    args.extend([path.as_str(), "32"]);
+    let output = project(&args);
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    let program = String::from_utf8_lossy(&output.stdout).into_owned();
+
+    let (head, end) = (
+        format!("main.while{n}.head:"),
+        format!("main.while{n}.end:"),
+    );
+    program
+        .lines()
+        .skip_while(|l| *l != head)
+        .take_while(|l| *l != end)
+        .filter(|l| {
+            ["LW ", "SW ", "FLW ", "FSW "]
+                .iter()
+                .any(|op| l.starts_with(op))
+        })
+        .count()
+}
+
+#[test]
+fn loops_without_calls_keep_variables_in_registers() {
+    assert!(memory_accesses(&[], 2) > 0);
+    assert_eq!(memory_accesses(&["-O"], 2), 0);
+}
+
+#[test]
+fn calls_store_and_load_no_more_than_memory_would() {
+    let plain = memory_accesses(&[], 1);
+    let optimized = memory_accesses(&["-O"], 1);
+    assert!(
+        optimized <= plain,
+        "{optimized} loads and stores with -O, {plain} without"
+    );
+}
@@ -0,0 +1,145 @@
+mod common;
+mod machine;
+
+use machine::check;
+
+// Cases falling through into the next, a default in the middle, a dense
+// switch that jumps through a table and a sparse one that compares, nested
+// switches, breaks that leave a switch but not the loop around it, and
+// do-while loops running their body before the first test.
+const SWITCHES: &str = "
+int calls;
+int next() {
+    calls = calls + 1;
+    return calls;
+}
+int table(int x) {
+    int r;
+    r = 0;
+    switch (x) {
+    case -1:
+        r = r + 1;
+    case 0:
+        r = r + 10;
+    default:
+        r = r + 100;
+    case 1:
+        r = r + 1000;
+        break;
+    case 3:
+        r = r + 5;
+    }
+    return r;
+}
+int sparse(int x) {
+    switch (x) {
+    case 1:
+        return 1;
+    case 100:
+        return 2;
+    case -5000:
+        return 3;
+    }
+    return 0;
+}
+int nested(int x, int y) {
+    switch (x) {
+    case 0:
+        switch (y) {
+        case 0:
+            return 1;
+        default:
+            break;
+        }
+        return 2;
+    case 1:
+        return 3;
+    }
+    return 4;
+}
+int loop(int n) {
+    int i;
+    int s;
+    i = 0;
+    s = 0;
+    while (i < n) {
+        switch (i) {
+        case 2:
+            s = s + 100;
+            break;
+        case 4:
+            break;
+        default:
+            s = s + 1;
+        }
+        if (i == 5) {
+            break;
+        }
+        i = i + 1;
+    }
+    return s * 10 + i;
+}
+int once(int n) {
+    int k;
+    k = 0;
+    do {
+        k = k + 1;
+        if (k > 5) {
+            break;
+        }
+    } while (k < n);
+    return k;
+}
+int main() {
+    int x;
+    int y;
+    read(x);
+    read(y);
+    print(table(x));
+    print(table(y));
+    print(sparse(x));
+    print(sparse(y));
+    print(nested(x, y));
+    print(loop(y));
+    print(once(x));
+    switch (next() + x) {
+    case 1:
+        print(10);
+    case 2:
+        print(20);
+        break;
+    case 3:
+        print(30);
+    }
+    print(calls);
+    return 0;
+}
+";
+
+#[test]
+fn switches_and_do_while_loops_run_as_in_c() {
+    check(
+        "statements",
+        SWITCHES,
+        &[
+            (&["-1", "3"], "1111 5 0 0 4 1023 1 1"),
+            (&["0", "2"], "1110 1100 0 0 2 22 1 10 20 1"),
+            (&["1", "100"], "1000 1100 1 2 3 1045 1 20 1"),
+            (&["2", "-5000"], "1100 1100 0 3 4 0 2 30 1"),
+            (&["0", "0"], "1110 1110 0 0 1 0 1 10 20 1"),
+            (&["3", "9"], "5 1100 0 0 4 1045 3 1"),
+        ],
+    );
+}
+
+#[test]
+fn dense_switches_jump_through_a_table() {
+    let path = common::source("statements_table.c", SWITCHES);
+    let output = common::project(&["--3ac", &path, "8"]);
+    assert!(output.status.success());
+    let listing = String::from_utf8_lossy(&output.stdout);
+    // sparse's cases are too far apart to fill a table, so it compares.
+    assert!(listing.contains("J table.switch1.table["), "{listing}");
+    assert!(!listing.contains("J sparse.switch1.table["), "{listing}");
+    assert!(listing.contains("J main.switch1.table["), "{listing}");
+}